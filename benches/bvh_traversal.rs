@@ -0,0 +1,145 @@
+//! Compares bvh traversal variants on generated scenes.
+//! Run with `cargo bench --bench bvh_traversal`,
+//! paths to `.rts` scenes can be passed after `--` to measure them too.
+use std::time::Instant;
+use nalgebra::Vector3;
+use rtracer::{bvh::{Bvh, BvhBuilder, Bvh4, Bvh8, RayPacket, PACKET_TILE_SIZE}, entity::{mesh::Mesh, geometry::Geometry, primitive::Primitives, Bounds}, material::Material, math::{ray::Ray, pcg}, loaders::scene_loader::load_scene};
+
+const RAYS: usize = 250_000;
+
+/// Random small triangles scattered in a box
+fn scattered_triangles(count: usize) -> Geometry {
+    let mut seed = 1;
+    let mut positions = vec![];
+    for _ in 0..count {
+        let center = pcg::random_vector3(&mut seed) * 20.0 - Vector3::repeat(10.0);
+        for _ in 0..3 {
+            positions.push(center + (pcg::random_vector3(&mut seed) - Vector3::repeat(0.5)) * 0.5);
+        }
+    }
+    let indices = (0..count as u32).map(|i| [i * 3, i * 3 + 1, i * 3 + 2]).collect();
+    Geometry::new(vec![Mesh::new(positions, vec![], vec![], indices, Material::default().into())])
+}
+
+/// Finely tessellated sphere with shared vertices, closed surface like most models
+fn sphere(segments: u32) -> Geometry {
+    let mut positions = vec![];
+    for i in 0..=segments {
+        let theta = std::f32::consts::PI * i as f32 / segments as f32;
+        for j in 0..=segments * 2 {
+            let phi = std::f32::consts::PI * j as f32 / segments as f32;
+            positions.push(Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()) * 10.0);
+        }
+    }
+    let row = segments * 2 + 1;
+    let mut indices = vec![];
+    for i in 0..segments {
+        for j in 0..segments * 2 {
+            let a = i * row + j;
+            indices.push([a, a + row, a + 1]);
+            indices.push([a + 1, a + row, a + row + 1]);
+        }
+    }
+    Geometry::new(vec![Mesh::new(positions, vec![], vec![], indices, Material::default().into())])
+}
+
+/// Center and size of the scene bounding box
+fn scene_box(geometry: &Geometry) -> (Vector3<f32>, f32) {
+    let mut min = Vector3::repeat(f32::INFINITY);
+    let mut max = Vector3::repeat(f32::NEG_INFINITY);
+    for mesh in &geometry.meshes {
+        for position in mesh.positions() {
+            min = min.inf(position);
+            max = max.sup(position);
+        }
+    }
+    ((min + max) * 0.5, (max - min).max())
+}
+
+/// Rays from a pinhole camera looking at the scene center, neighbour rays are coherent.
+/// Rays are ordered by screen tiles, so every packet of tile size is one tile.
+fn camera_rays(geometry: &Geometry) -> Vec<Ray> {
+    let (center, size) = scene_box(geometry);
+    let tile = PACKET_TILE_SIZE;
+    let tiles = (RAYS as f32).sqrt() as usize / tile;
+    let resolution = tiles * tile;
+    let origin = center + Vector3::new(0.0, 0.25, -1.5) * size;
+    (0..resolution * resolution).map(|i| {
+        let (tile_index, pixel) = (i / (tile * tile), i % (tile * tile));
+        let x = ((tile_index % tiles) * tile + pixel % tile) as f32 / resolution as f32 - 0.5;
+        let y = ((tile_index / tiles) * tile + pixel / tile) as f32 / resolution as f32 - 0.5;
+        let target = center + Vector3::new(x, y, 0.0) * size * 1.5;
+        Ray::new(origin, (target - origin).normalize())
+    }).collect()
+}
+
+/// Rays with random origins inside the scene and random directions, like diffuse bounces
+fn random_rays(geometry: &Geometry) -> Vec<Ray> {
+    let (center, size) = scene_box(geometry);
+    let mut seed = 3;
+    (0..RAYS).map(|_| {
+        let origin = center + (pcg::random_vector3(&mut seed) - Vector3::repeat(0.5)) * size;
+        Ray::new(origin, pcg::random_direction(&mut seed))
+    }).collect()
+}
+
+fn build(geometry: &Geometry) -> Bvh {
+    let bounds: Vec<Bounds> = geometry.triangles.iter().map(|x| geometry.triangle_bounds(x)).collect();
+    let centroids = bounds.iter().map(|x| x.centroid).collect();
+    let mut bvh = Bvh::new(BvhBuilder::default());
+    bvh.calculate_bvh(bounds, centroids);
+    bvh
+}
+
+/// Prints million rays per second and returns sum of hit distances, so results can be compared
+fn measure(name: &str, rays: &[Ray], cast: impl Fn(&Ray) -> Option<f32>) -> f64 {
+    let timer = Instant::now();
+    let sum: f64 = rays.iter().filter_map(&cast).map(|x| x as f64).sum();
+    let seconds = timer.elapsed().as_secs_f64();
+    println!("    {:<12} {:>8.2} MRays/s", name, rays.len() as f64 / seconds / 1_000_000.0);
+    sum
+}
+
+/// Same as `measure`, but rays are traced in packets of a tile size
+fn measure_packets(name: &str, rays: &[Ray], cast: impl Fn(&RayPacket) -> Vec<Option<f32>>) -> f64 {
+    let packets: Vec<RayPacket> = rays.chunks(PACKET_TILE_SIZE * PACKET_TILE_SIZE).map(|x| RayPacket::new(x.to_vec())).collect();
+    let timer = Instant::now();
+    let sum: f64 = packets.iter().flat_map(&cast).flatten().map(|x| x as f64).sum();
+    let seconds = timer.elapsed().as_secs_f64();
+    println!("    {:<12} {:>8.2} MRays/s", name, rays.len() as f64 / seconds / 1_000_000.0);
+    sum
+}
+
+fn main() {
+    let mut scenes = vec![
+        ("scattered 200k".to_string(), scattered_triangles(200_000)),
+        ("sphere 320k".to_string(), sphere(400)),
+    ];
+    for path in std::env::args().skip(1).filter(|x| x.ends_with(".rts")) {
+        let meshes = load_scene(&path).meshes;
+        scenes.push((path, Geometry::new(meshes)));
+    }
+    for (scene_name, geometry) in scenes {
+        // Objects are the same for both sets, so one tree is used for both
+        let primitives = Primitives::new(geometry);
+        let geometry = &primitives.geometry;
+        let bvh = build(geometry);
+        let bvh4 = Bvh4::new(&bvh);
+        let bvh8 = Bvh8::new(&bvh);
+        for (rays_name, rays) in [("camera rays", camera_rays(geometry)), ("random rays", random_rays(geometry))] {
+            println!("{}, {}:", scene_name, rays_name);
+            let expected = measure("recursive", &rays, |ray| bvh.intersect_recursive(ray, geometry).map(|x| x.t));
+            let iterative = measure("iterative", &rays, |ray| bvh.intersect(ray, geometry).map(|x| x.t));
+            let wide4 = measure("wide 4", &rays, |ray| bvh4.intersect(ray, geometry).map(|x| x.t));
+            let wide8 = measure("wide 8", &rays, |ray| bvh8.intersect(ray, geometry).map(|x| x.t));
+            let packet = measure_packets("packet", &rays,
+                |packet| bvh.intersect_packet(packet, geometry).iter().map(|x| x.as_ref().map(|x| x.t)).collect());
+            let dispatched = measure("enum", &rays, |ray| bvh.intersect(ray, &primitives).map(|x| x.t));
+            assert_eq!(expected, iterative);
+            assert_eq!(expected, dispatched);
+            assert_eq!(expected, packet);
+            assert_eq!(expected, wide4);
+            assert_eq!(expected, wide8);
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use nalgebra::Vector3;
+use rayon::prelude::*;
+use crate::entity::Bounds;
+use super::{BvhNode, Aabb, bvh_lbvh};
+
+/// Strategy used to find split planes while building bvh
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BvhBuilder {
+    /// Evaluates SAH for `candidates` uniformly spaced planes per axis.
+    /// Every candidate rescans all objects of the node.
+    Planes { candidates: usize },
+    /// Distributes objects to `bins` bins per axis in one pass,
+    /// then evaluates SAH for planes between bins. Less than two bins are raised to two.
+    Binned { bins: usize },
+    /// Binned object splits combined with spatial splits (SBVH).
+    /// Objects crossing a spatial split plane are clipped and referenced from both children,
+    /// number of references grows by no more than `max_duplication` of objects count.
+    /// Less than two bins are raised to two.
+    Spatial { bins: usize, max_duplication: f32 },
+    /// Linear bvh from objects sorted by Morton codes.
+    /// Builds much faster than SAH builders, but the tree is worse.
+    Lbvh,
+}
+
+impl Default for BvhBuilder {
+    #[inline]
+    fn default() -> Self {
+        BvhBuilder::Binned { bins: 16 }
+    }
+}
+
+/// Binned builders need at least one plane between bins
+pub(super) const MIN_BINS: usize = 2;
+
+/// Nodes with more objects build their subtrees and bins in parallel
+pub(super) const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+/// Deeper subtrees are built on one thread, recursion depth of parallel build is bounded by it
+pub(super) const MAX_PARALLEL_DEPTH: u32 = 16;
+
+/// Node with its range of descendant slots and objects, which are not built yet
+struct BuildTask<'a> {
+    node: &'a mut BvhNode,
+    descendants: &'a mut [BvhNode],
+    /// Index of the first descendant slot in the whole tree
+    descendants_index: usize,
+    objects: BuildObjects<'a>,
+    first_object: usize,
+}
+
+/// Objects of one subtree.
+/// Subtrees own not overlapping parts of object arrays, so they can be built in parallel.
+pub(super) struct BuildObjects<'a> {
+    pub bounds: &'a mut [Bounds],
+    pub centroids: &'a mut [Vector3<f32>],
+    pub indexes: &'a mut [usize],
+}
+
+impl<'a> BuildObjects<'a> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.bounds.len()
+    }
+
+    #[inline(always)]
+    fn swap(&mut self, a: usize, b: usize) {
+        self.bounds.swap(a, b);
+        self.centroids.swap(a, b);
+        self.indexes.swap(a, b);
+    }
+
+    #[inline]
+    fn split_at(self, mid: usize) -> (BuildObjects<'a>, BuildObjects<'a>) {
+        let (bounds_left, bounds_right) = self.bounds.split_at_mut(mid);
+        let (centroids_left, centroids_right) = self.centroids.split_at_mut(mid);
+        let (indexes_left, indexes_right) = self.indexes.split_at_mut(mid);
+        (
+            BuildObjects { bounds: bounds_left, centroids: centroids_left, indexes: indexes_left },
+            BuildObjects { bounds: bounds_right, centroids: centroids_right, indexes: indexes_right },
+        )
+    }
+
+    /// Moves objects with centroid before `split_pos` to the start, returns their count
+    #[inline]
+    fn partition(&mut self, axis: usize, split_pos: f32) -> usize {
+        let mut i = 0;
+        let mut j = self.len();
+        while i < j {
+            if self.centroids[i][axis] < split_pos {
+                i += 1;
+            } else {
+                j -= 1;
+                self.swap(i, j);
+            }
+        }
+        i
+    }
+}
+
+impl BvhBuilder {
+    /// Builds tree for `objects`, root is the first node.
+    /// Output doesn't depend on the number of threads.
+    pub(super) fn build(self, objects: BuildObjects) -> Vec<BvhNode> {
+        let count = objects.len();
+        if count == 0 {
+            return vec![];
+        }
+        if self == BvhBuilder::Lbvh {
+            return bvh_lbvh::build(objects);
+        }
+        // Subtree with n objects never has more than 2n - 2 descendants.
+        // Every subtree gets such range of nodes, so parallel subtrees never write to the same nodes
+        // and node indices don't depend on the order in which subtrees are finished.
+        let mut nodes = vec![BvhNode::new(0, 0); count * 2 - 1];
+        let (root, descendants) = nodes.split_at_mut(1);
+        self.build_node(BuildTask { node: &mut root[0], descendants, descendants_index: 1, objects, first_object: 0 }, 0);
+        compact(nodes)
+    }
+
+    /// Builds subtrees of big nodes in parallel up to `MAX_PARALLEL_DEPTH`, the rest without recursion
+    fn build_node(self, task: BuildTask, depth: u32) {
+        let parallel = task.objects.len() > PARALLEL_BUILD_THRESHOLD && depth < MAX_PARALLEL_DEPTH;
+        let Some((left, right)) = self.split_node(task) else {
+            return;
+        };
+        if parallel {
+            rayon::join(|| self.build_node(left, depth + 1), || self.build_node(right, depth + 1));
+        } else {
+            self.build_subtree(left);
+            self.build_subtree(right);
+        }
+    }
+
+    /// Builds subtree on the current thread with explicit stack, so deep trees can't overflow the thread stack
+    fn build_subtree(self, task: BuildTask) {
+        let mut stack = vec![task];
+        while let Some(task) = stack.pop() {
+            if let Some((left, right)) = self.split_node(task) {
+                stack.push(right);
+                stack.push(left);
+            }
+        }
+    }
+
+    /// Fills node bounds and splits its objects, returns tasks of children or `None` for leaf
+    fn split_node<'a>(self, task: BuildTask<'a>) -> Option<(BuildTask<'a>, BuildTask<'a>)> {
+        let BuildTask { node, descendants, descendants_index, mut objects, first_object } = task;
+        let count = objects.len();
+        node.first_object = first_object;
+        node.object_count = count;
+        let aabb = objects_aabb(objects.bounds);
+        node.aabb_min = aabb.bmin;
+        node.aabb_max = aabb.bmax;
+
+        let node_cost: f32 = count as f32 * aabb.area();
+        let (split_pos, divide_axis, best_cost) = self.division_plane(objects.bounds, objects.centroids);
+        if best_cost >= node_cost {
+            return None;
+        }
+        // Divide
+        let left_count = objects.partition(divide_axis as usize, split_pos);
+        if left_count == 0 || left_count == count {
+            return None;
+        }
+        node.first_object = descendants_index;
+        node.object_count = 0;
+
+        let (children, rest) = descendants.split_at_mut(2);
+        let (left, right) = children.split_at_mut(1);
+        let (left_descendants, right_descendants) = rest.split_at_mut(left_count * 2 - 2);
+        let (left_objects, right_objects) = objects.split_at(left_count);
+        let left_descendants_index = descendants_index + 2;
+        let right_descendants_index = left_descendants_index + left_descendants.len();
+        Some((
+            BuildTask { node: &mut left[0], descendants: left_descendants, descendants_index: left_descendants_index,
+                objects: left_objects, first_object },
+            BuildTask { node: &mut right[0], descendants: right_descendants, descendants_index: right_descendants_index,
+                objects: right_objects, first_object: first_object + left_count },
+        ))
+    }
+
+    #[inline]
+    /// Returns (split_pos, divide_axis, best_cost)
+    pub(super) fn division_plane(self, bounds: &[Bounds], centroids: &[Vector3<f32>]) -> (f32, i32, f32) {
+        match self {
+            BvhBuilder::Planes { candidates } => division_plane_planes(bounds, centroids, candidates),
+            BvhBuilder::Binned { bins } | BvhBuilder::Spatial { bins, .. } => division_plane_binned(bounds, centroids, bins),
+            // Linear bvh doesn't use planes, give the plane binned builder would choose
+            BvhBuilder::Lbvh => division_plane_binned(bounds, centroids, 16),
+        }
+    }
+}
+
+/// Removes node slots left unused by subtree ranges, keeps sibling nodes next to each other
+pub(super) fn compact(nodes: Vec<BvhNode>) -> Vec<BvhNode> {
+    // Children are always placed after the parent, so one pass finds all used nodes
+    let mut used = vec![false; nodes.len()];
+    let mut new_indexes = vec![0; nodes.len()];
+    used[0] = true;
+    let mut used_count = 0;
+    for (i, node) in nodes.iter().enumerate() {
+        if !used[i] {
+            continue;
+        }
+        new_indexes[i] = used_count;
+        used_count += 1;
+        if !node.is_leaf() {
+            used[node.first_object] = true;
+            used[node.first_object + 1] = true;
+        }
+    }
+    nodes.into_iter().zip(used).filter(|x| x.1).map(|(mut node, _)| {
+        if !node.is_leaf() {
+            node.first_object = new_indexes[node.first_object];
+        }
+        node
+    }).collect()
+}
+
+#[inline]
+pub(super) fn objects_aabb(bounds: &[Bounds]) -> Aabb {
+    let grow = |mut aabb: Aabb, x: &Bounds| {
+        aabb.grow(x.aabb_min);
+        aabb.grow(x.aabb_max);
+        aabb
+    };
+    if bounds.len() > PARALLEL_BUILD_THRESHOLD {
+        bounds.par_iter().fold(Aabb::default, grow).reduce(Aabb::default, Aabb::union)
+    } else {
+        bounds.iter().fold(Aabb::default(), grow)
+    }
+}
+
+#[inline]
+pub(super) fn centroids_aabb(centroids: &[Vector3<f32>]) -> Aabb {
+    let grow = |mut aabb: Aabb, x: &Vector3<f32>| {
+        aabb.grow(*x);
+        aabb
+    };
+    if centroids.len() > PARALLEL_BUILD_THRESHOLD {
+        centroids.par_iter().fold(Aabb::default, grow).reduce(Aabb::default, Aabb::union)
+    } else {
+        centroids.iter().fold(Aabb::default(), grow)
+    }
+}
+
+#[inline]
+fn division_plane_planes(bounds: &[Bounds], centroids: &[Vector3<f32>], candidates: usize) -> (f32, i32, f32) {
+    // determine split axis using SAH
+    let mut best_axis: usize = 0;
+    let mut best_pos: f32 = 0.0;
+    let mut best_cost: f32 = 1e30;
+    // Calculate bounds by centroids of objects in this node
+    let centroids_bounds = centroids_aabb(centroids);
+    for axis in 0..3 {
+        let bounds_min: f32 = centroids_bounds.bmin[axis];
+        let bounds_max: f32 = centroids_bounds.bmax[axis];
+        if bounds_min == bounds_max {
+            continue;
+        }
+        let scale: f32 = (bounds_max - bounds_min) / candidates as f32;
+        for i in 0..candidates {
+            let candidate_pos: f32 = bounds_min + i as f32 * scale;
+            let cost: f32 = evaluate_sah(bounds, centroids, axis, candidate_pos);
+            if cost < best_cost {
+                best_axis = axis;
+                best_pos = candidate_pos;
+                best_cost = cost;
+            }
+        }
+    }
+    let axis: i32 = best_axis as i32;
+    let split_pos: f32 = best_pos;
+    (split_pos, axis, best_cost)
+}
+
+#[inline]
+pub(super) fn division_plane_binned(bounds: &[Bounds], centroids: &[Vector3<f32>], bins_count: usize) -> (f32, i32, f32) {
+    let bins_count = bins_count.max(MIN_BINS);
+    let mut best_axis: usize = 0;
+    let mut best_pos: f32 = 0.0;
+    let mut best_cost: f32 = 1e30;
+    // Data for the (bins_count - 1) planes between the bins
+    let mut left_area: Vec<f32> = vec![0.0; bins_count - 1];
+    let mut left_count: Vec<usize> = vec![0; bins_count - 1];
+
+    // Calculate bounds by centroids of objects in this node
+    let centroids_bounds = centroids_aabb(centroids);
+    for axis in 0..3 {
+        let bounds_min: f32 = centroids_bounds.bmin[axis];
+        let bounds_max: f32 = centroids_bounds.bmax[axis];
+        if bounds_min == bounds_max {
+            continue;
+        }
+        // Populate the bins
+        let scale: f32 = bins_count as f32 / (bounds_max - bounds_min);
+        let populate = |mut bins: Vec<Bin>, (centroid, bounds): (&Vector3<f32>, &Bounds)| {
+            let bin_index: usize = usize::min(bins_count - 1, ((centroid[axis] - bounds_min) * scale) as usize);
+            bins[bin_index].object_count += 1;
+            bins[bin_index].aabb.grow(bounds.aabb_min);
+            bins[bin_index].aabb.grow(bounds.aabb_max);
+            bins
+        };
+        let empty_bins = || vec![Bin::default(); bins_count];
+        // Bins merging is order independent, so parallel binning gives the same result
+        let bins: Vec<Bin> = if centroids.len() > PARALLEL_BUILD_THRESHOLD {
+            centroids.par_iter().zip(bounds).fold(empty_bins, populate)
+                .reduce(empty_bins, |a, b| a.iter().zip(&b).map(|(a, b)| a.merge(b)).collect())
+        } else {
+            centroids.iter().zip(bounds).fold(empty_bins(), populate)
+        };
+        // Sweep from the left, then from the right evaluating cost of each plane
+        // Empty bins have inverted bounds, so they must not grow the boxes
+        let mut left_box = Aabb::default();
+        let mut left_sum = 0;
+        for i in 0..bins_count - 1 {
+            if bins[i].object_count > 0 {
+                left_sum += bins[i].object_count;
+                left_box = left_box.union(bins[i].aabb);
+            }
+            left_count[i] = left_sum;
+            left_area[i] = left_box.area();
+        }
+        let mut right_box = Aabb::default();
+        let mut right_sum = 0;
+        let plane_step: f32 = (bounds_max - bounds_min) / bins_count as f32;
+        for i in (0..bins_count - 1).rev() {
+            if bins[i + 1].object_count > 0 {
+                right_sum += bins[i + 1].object_count;
+                right_box = right_box.union(bins[i + 1].aabb);
+            }
+            if left_count[i] == 0 || right_sum == 0 {
+                continue;
+            }
+            let plane_cost: f32 = left_count[i] as f32 * left_area[i] + right_sum as f32 * right_box.area();
+            if plane_cost < best_cost {
+                best_axis = axis;
+                best_pos = bounds_min + plane_step * (i + 1) as f32;
+                best_cost = plane_cost;
+            }
+        }
+    }
+    (best_pos, best_axis as i32, best_cost)
+}
+
+#[inline]
+fn evaluate_sah(bounds: &[Bounds], centroids: &[Vector3<f32>], axis: usize, pos: f32) -> f32 {
+    // determine triangle counts and bounds for this split candidate
+    let mut left_box = Aabb::default();
+    let mut right_box = Aabb::default();
+    let mut left_count = 0;
+    let mut right_count = 0;
+    for (centroid, bounds) in centroids.iter().zip(bounds) {
+        if centroid[axis] < pos {
+            left_count += 1;
+            left_box.grow(bounds.aabb_min);
+            left_box.grow(bounds.aabb_max);
+        } else {
+            right_count += 1;
+            right_box.grow(bounds.aabb_min);
+            right_box.grow(bounds.aabb_max);
+        }
+    }
+    let cost: f32 = left_count as f32 * left_box.area() + right_count as f32 * right_box.area();
+    if cost > 0.0 { cost } else { 1e30 }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Bin {
+    pub aabb: Aabb,
+    pub object_count: usize
+}
+
+impl Bin {
+    #[inline]
+    fn merge(&self, other: &Bin) -> Bin {
+        Bin { aabb: self.aabb.union(other.aabb), object_count: self.object_count + other.object_count }
+    }
+}
@@ -0,0 +1,160 @@
+use std::{fs, io::{self, Write, BufWriter}, path::Path, hash::Hasher};
+use nalgebra::Vector3;
+use crate::entity::Bounds;
+use super::{Bvh, BvhNode};
+
+const MAGIC: &[u8; 4] = b"RTBV";
+/// Increase when layout of the file or the tree changes
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 4 + 4 + 8 + 8 + 8;
+const NODE_SIZE: usize = 6 * 4 + 4 + 4;
+
+/// 64-bit FNV-1a hash, stable between runs and platforms
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    #[inline]
+    fn default() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+impl Bvh {
+    /// Writes nodes and object references to binary file.
+    /// `key` identifies input geometry, usually its hash.
+    pub fn save(&self, path: &Path, key: u64) -> io::Result<()> {
+        // Write to temporary file first, so interrupted save doesn't leave broken cache behind
+        let mut temp_name = path.as_os_str().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = Path::new(&temp_name);
+        let result = self.write(temp_path, key).and_then(|_| fs::rename(temp_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(temp_path);
+        }
+        result
+    }
+
+    fn write(&self, path: &Path, key: u64) -> io::Result<()> {
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&key.to_le_bytes())?;
+        writer.write_all(&(self.bvhs.len() as u64).to_le_bytes())?;
+        writer.write_all(&(self.objects_indexes.len() as u64).to_le_bytes())?;
+        for node in &self.bvhs {
+            for value in node.aabb_min.iter().chain(node.aabb_max.iter()) {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            writer.write_all(&to_u32(node.first_object)?.to_le_bytes())?;
+            writer.write_all(&to_u32(node.object_count)?.to_le_bytes())?;
+        }
+        for index in &self.objects_indexes {
+            writer.write_all(&to_u32(*index)?.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    /// Reads tree written by `save`.
+    /// Returns `Ok(false)` and keeps the tree unchanged if file was saved for other `key` or version.
+    /// `objects_bounds` are the same as for `calculate_bvh`, they are used by refit.
+    pub fn load(&mut self, path: &Path, key: u64, objects_bounds: &[Bounds]) -> io::Result<bool> {
+        let data = fs::read(path)?;
+        if data.len() < HEADER_SIZE || &data[0..4] != MAGIC {
+            return Err(invalid_data("Not a bvh cache file"));
+        }
+        let mut reader = ByteReader { data: &data, position: 4 };
+        if reader.u32() != VERSION || reader.u64() != key {
+            return Ok(false);
+        }
+        let node_count = reader.u64() as usize;
+        let reference_count = reader.u64() as usize;
+        let expected_size = node_count.checked_mul(NODE_SIZE)
+            .and_then(|x| x.checked_add(reference_count.checked_mul(4)?))
+            .and_then(|x| x.checked_add(HEADER_SIZE));
+        if expected_size != Some(data.len()) {
+            return Err(invalid_data("Bvh cache file has wrong size"));
+        }
+        let mut nodes = Vec::with_capacity(node_count);
+        for index in 0..node_count {
+            let aabb_min = Vector3::new(reader.f32(), reader.f32(), reader.f32());
+            let aabb_max = Vector3::new(reader.f32(), reader.f32(), reader.f32());
+            let node = BvhNode { aabb_min, aabb_max, first_object: reader.u32() as usize, object_count: reader.u32() as usize };
+            // Children are stored after their parent, so the tree can't have cycles
+            let valid = if node.is_leaf() {
+                node.first_object + node.object_count <= reference_count
+            } else {
+                index < node.first_object && node.first_object + 1 < node_count
+            };
+            if !valid {
+                return Err(invalid_data("Bvh cache file has invalid node"));
+            }
+            nodes.push(node);
+        }
+        let indexes: Vec<usize> = (0..reference_count).map(|_| reader.u32() as usize).collect();
+        if indexes.iter().any(|x| *x >= objects_bounds.len()) {
+            return Err(invalid_data("Bvh cache file references missing object"));
+        }
+
+        self.objects_bounds = indexes.iter().map(|x| objects_bounds[*x].clone()).collect();
+        self.objects_centroids = self.objects_bounds.iter().map(|x| x.centroid).collect();
+        self.objects_indexes = indexes;
+        self.bvhs = nodes;
+        self.built_sah_cost = self.sah_cost();
+        Ok(true)
+    }
+}
+
+#[inline]
+fn to_u32(value: usize) -> io::Result<u32> {
+    u32::try_from(value).map_err(|_| invalid_data("Bvh is too big for cache file"))
+}
+
+#[inline]
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads little endian values, size of data must be checked before
+struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl ByteReader<'_> {
+    #[inline]
+    fn bytes<const N: usize>(&mut self) -> [u8; N] {
+        let bytes = self.data[self.position..self.position + N].try_into().unwrap();
+        self.position += N;
+        bytes
+    }
+
+    #[inline]
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes())
+    }
+
+    #[inline]
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.bytes())
+    }
+
+    #[inline]
+    fn f32(&mut self) -> f32 {
+        f32::from_le_bytes(self.bytes())
+    }
+}
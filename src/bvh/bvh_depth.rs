@@ -14,7 +14,9 @@ impl BvhDepth<'_> {
 
     #[inline]
     pub fn intersect_hierarchy(&mut self) {
-        self.intersect_bvh(0, 0);
+        if !self.data.is_empty() {
+            self.intersect_bvh(0, 0);
+        }
     }
 
     #[inline]
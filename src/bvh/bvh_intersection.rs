@@ -1,64 +1,102 @@
-use crate::{entity::hit::{Hittable, Intersection}, math::ray::Ray};
+use crate::{entity::hit::{HittableSet, Intersection}, math::ray::Ray};
 use super::{Bvh, BvhNode};
 
-pub(super) struct BvhIntersection<'a, 'b, T> {
+/// Depth of tree the traversal stack holds, deeper parts are traversed with recursion
+const STACK_SIZE: usize = 64;
+
+pub(super) struct BvhIntersection<'a, 'b, T, S: ?Sized> {
     pub closest_hit: Option<Intersection<'b, T>>,
     closest_dist: f32,
     data: &'a Bvh,
-    objects: &'b [T],
+    objects: &'b S,
     ray: &'a Ray
 }
 
-impl<'a, 'b, T>  BvhIntersection<'a, 'b, T>
-where T: Hittable<T> {
+impl<'a, 'b, T, S>  BvhIntersection<'a, 'b, T, S>
+where S: HittableSet<T> + ?Sized {
     #[inline]
-    pub fn new(data: &'a Bvh, ray: &'a Ray, objects: &'b [T]) -> BvhIntersection<'a, 'b, T> {
-        BvhIntersection { data, closest_hit: None, closest_dist: f32::INFINITY, objects, ray }
+    pub fn new(data: &'a Bvh, ray: &'a Ray, objects: &'b S) -> BvhIntersection<'a, 'b, T, S> {
+        BvhIntersection { data, closest_hit: None, closest_dist: ray.t_max, objects, ray }
     }
 
     #[inline]
     pub fn intersect_hierarchy(&mut self) {
-        self.intersect_bvh(&self.data.bvhs[0]);
+        if !self.data.bvhs.is_empty() {
+            self.traverse::<false>(0);
+        }
+    }
 
-        // Non recursive code (much slower for now)
-        /*let mut node: &BvhNode = &self.data.bvhs[0];
-        let binding = BvhNode::default();
-        let mut stack: [&BvhNode; 64] = [&binding; 64];
-        let mut stack_ptr: usize = 0;
+    /// Returns true if any object is hit closer than `t_max`, stops on the first found hit
+    #[inline]
+    pub fn occluded(&mut self, t_max: f32) -> bool {
+        self.closest_dist = t_max.min(self.ray.t_max);
+        !self.data.bvhs.is_empty() && self.traverse::<true>(0)
+    }
+
+    /// Recursive traversal, kept as reference for tests and benchmarks
+    #[inline]
+    pub fn intersect_hierarchy_recursive(&mut self) {
+        let data = self.data;
+        if let Some(root) = data.bvhs.first() {
+            self.intersect_bvh(root);
+        }
+    }
+
+    /// Iterative traversal of subtree with root `root`.
+    /// Nearer child is visited right away, further one is put to the stack.
+    /// With `ANY_HIT` stops on the first hit and returns true.
+    fn traverse<const ANY_HIT: bool>(&mut self, root: usize) -> bool {
+        let bvhs = &self.data.bvhs;
+        let mut stack: [(u32, f32); STACK_SIZE] = [(0, 0.0); STACK_SIZE];
+        let mut stack_size: usize = 0;
+        let mut node: &BvhNode = &bvhs[root];
         loop {
             if node.is_leaf() {
-                for i in 0..node.object_count {
-                    self.intersect_triangles(node, ray, objects);
+                if ANY_HIT {
+                    if self.any_triangle(node) {
+                        return true;
+                    }
+                } else {
+                    self.intersect_triangles(node);
                 }
-                if stack_ptr == 0 {
-                    break;
-                } else { 
-                    stack_ptr -= 1;
-                    node = stack[stack_ptr];
+            } else {
+                let mut near = node.first_object;
+                let mut far = node.first_object + 1;
+                let mut near_dist = bvhs[near].intersect_distance(self.ray);
+                let mut far_dist = bvhs[far].intersect_distance(self.ray);
+                if far_dist < near_dist {
+                    (near, far) = (far, near);
+                    (near_dist, far_dist) = (far_dist, near_dist);
+                }
+                if near_dist < self.closest_dist {
+                    if far_dist < self.closest_dist {
+                        if stack_size < STACK_SIZE {
+                            stack[stack_size] = (far as u32, far_dist);
+                            stack_size += 1;
+                        } else {
+                            // Trees deeper than the stack are traversed with recursion
+                            if self.traverse::<ANY_HIT>(far) {
+                                return true;
+                            }
+                        }
+                    }
+                    node = &bvhs[near];
+                    continue;
                 }
-                continue;
-            }
-            let mut child1: &BvhNode = &self.data.bvhs[node.first_object];
-            let mut child2: &BvhNode = &self.data.bvhs[node.first_object + 1];
-            let mut dist1 = child1.intersect_distance(ray);
-            let mut dist2 = child2.intersect_distance(ray);
-            if dist1.unwrap_or(1e30) > dist2.unwrap_or(1e30) {
-                (dist1, dist2) = (dist2, dist1);
-                (child1, child2) = (child2, child1)
             }
-            if dist1.is_some() {
-                node = child1;
-                if dist2.is_some() {
-                    stack[stack_ptr] = child2;
-                    stack_ptr += 1;
+            // Take the next node which is still closer than the closest hit
+            loop {
+                if stack_size == 0 {
+                    return false;
+                }
+                stack_size -= 1;
+                let (index, distance) = stack[stack_size];
+                if distance < self.closest_dist {
+                    node = &bvhs[index as usize];
+                    break;
                 }
-            } else if stack_ptr == 0 {
-                break;
-            } else {
-                stack_ptr -= 1;
-                node = stack[stack_ptr];
             }
-        }*/
+        }
     }
 
     #[inline]
@@ -93,24 +131,32 @@ where T: Hittable<T> {
     fn intersect_triangles(&mut self, bvh: &BvhNode) {
         let hit: Option<Intersection<'b, T>> = 
         self.data.objects_indexes[(bvh.first_object)..(bvh.first_object + bvh.object_count)]
-        .iter().map(|x| {
-            &self.objects[*x]
-        })
-        .filter_map(|obj| {  // Take valid hits
-            obj.intersect(self.ray)
+        .iter()
+        .filter_map(|x| {  // Take valid hits
+            self.objects.intersect_object(*x, self.ray)
         })
         .min_by(|hit1, hit2| hit1.t.partial_cmp(&hit2.t).unwrap()); // Get min hit by param `t`
 
         if let Some(hit_u) = &hit {
-            if let Some(closest_hit) = &self.closest_hit {
-                if hit_u.t < closest_hit.t {
-                    self.closest_dist = hit_u.t;
-                    self.closest_hit = hit;
-                }
-            } else {
+            if hit_u.t < self.closest_dist {
                 self.closest_dist = hit_u.t;
                 self.closest_hit = hit;
             }
         }
     }
+
+    /// Returns true on the first object hit closer than `closest_dist`
+    #[inline(always)]
+    fn any_triangle(&mut self, bvh: &BvhNode) -> bool {
+        let hit: Option<Intersection<'b, T>> =
+        self.data.objects_indexes[(bvh.first_object)..(bvh.first_object + bvh.object_count)]
+        .iter()
+        .filter_map(|x| self.objects.intersect_object(*x, self.ray))
+        .find(|x| x.t < self.closest_dist);
+        let found = hit.is_some();
+        if found {
+            self.closest_hit = hit;
+        }
+        found
+    }
 }
\ No newline at end of file
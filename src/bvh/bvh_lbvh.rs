@@ -0,0 +1,146 @@
+use nalgebra::Vector3;
+use rayon::prelude::*;
+use crate::entity::Bounds;
+use super::{BvhNode, Aabb, bvh_builder::{BuildObjects, centroids_aabb, compact, PARALLEL_BUILD_THRESHOLD}};
+
+/// Linear bvh build (Karras 2012).
+/// Objects are sorted by Morton codes of their centroids and the tree is made
+/// by splitting sorted ranges at the highest differing bit.
+/// Subtrees with SAH cost higher than cost of a leaf are collapsed after that.
+pub(super) fn build(objects: BuildObjects) -> Vec<BvhNode> {
+    let count = objects.bounds.len();
+    if count == 0 {
+        return vec![];
+    }
+    let codes = sort_by_morton_codes(objects.centroids, objects.indexes);
+    // Same order for the rest of object data
+    let order: Vec<usize> = codes.iter().map(|x| x.1).collect();
+    let sorted_bounds: Vec<_> = order.iter().map(|x| objects.bounds[*x].clone()).collect();
+    let sorted_centroids: Vec<_> = order.iter().map(|x| objects.centroids[*x]).collect();
+    let sorted_indexes: Vec<_> = order.iter().map(|x| objects.indexes[*x]).collect();
+    objects.bounds.iter_mut().zip(sorted_bounds).for_each(|(x, y)| *x = y);
+    objects.centroids.copy_from_slice(&sorted_centroids);
+    objects.indexes.copy_from_slice(&sorted_indexes);
+
+    // Every leaf has one object at first, so the tree has exactly 2n - 1 nodes
+    let codes: Vec<u32> = codes.iter().map(|x| x.0).collect();
+    let mut nodes = vec![BvhNode::new(0, 0); count * 2 - 1];
+    let (root, descendants) = nodes.split_at_mut(1);
+    build_node(&mut root[0], descendants, 1, &codes, 0);
+    collapse(&mut nodes, objects.bounds);
+    compact(nodes)
+}
+
+/// Returns (morton code, object) pairs sorted by code
+fn sort_by_morton_codes(centroids: &[Vector3<f32>], indexes: &[usize]) -> Vec<(u32, usize)> {
+    let centroids_bounds = centroids_aabb(centroids);
+    let extent = centroids_bounds.bmax - centroids_bounds.bmin;
+    let scale = extent.map(|x| if x > 0.0 { 1.0 / x } else { 0.0 });
+    let mut codes: Vec<(u32, usize)> = centroids.par_iter().enumerate().map(|(i, centroid)| {
+        let normalized = (centroid - centroids_bounds.bmin).component_mul(&scale);
+        (morton_code(normalized.x, normalized.y, normalized.z), i)
+    }).collect();
+    // Ties are broken by object index, so the order is always the same
+    codes.par_sort_unstable_by_key(|x| (x.0, indexes[x.1]));
+    codes
+}
+
+/// 30-bit Morton code of a point in the unit cube
+#[inline]
+pub fn morton_code(x: f32, y: f32, z: f32) -> u32 {
+    let quantize = |x: f32| (x * 1024.0).clamp(0.0, 1023.0) as u32;
+    (expand_bits(quantize(x)) << 2) | (expand_bits(quantize(y)) << 1) | expand_bits(quantize(z))
+}
+
+/// Inserts two zero bits after each of 10 low bits
+#[inline(always)]
+fn expand_bits(v: u32) -> u32 {
+    let mut v = v & 0x3ff;
+    v = (v | (v << 16)) & 0x030000ff;
+    v = (v | (v << 8)) & 0x0300f00f;
+    v = (v | (v << 4)) & 0x030c30c3;
+    v = (v | (v << 2)) & 0x09249249;
+    v
+}
+
+fn build_node(node: &mut BvhNode, descendants: &mut [BvhNode], descendants_index: usize, codes: &[u32], first_object: usize) {
+    let count = codes.len();
+    node.first_object = first_object;
+    node.object_count = count;
+    if count == 1 {
+        return;
+    }
+    let left_count = find_split(codes);
+    node.first_object = descendants_index;
+    node.object_count = 0;
+
+    let (children, rest) = descendants.split_at_mut(2);
+    let (left, right) = children.split_at_mut(1);
+    let (left_descendants, right_descendants) = rest.split_at_mut(left_count * 2 - 2);
+    let (left_codes, right_codes) = codes.split_at(left_count);
+    let left_descendants_index = descendants_index + 2;
+    let right_descendants_index = left_descendants_index + left_descendants.len();
+
+    let mut build_left = || build_node(&mut left[0], left_descendants, left_descendants_index, left_codes, first_object);
+    let mut build_right = || build_node(&mut right[0], right_descendants, right_descendants_index,
+        right_codes, first_object + left_count);
+    if count > PARALLEL_BUILD_THRESHOLD {
+        rayon::join(build_left, build_right);
+    } else {
+        build_left();
+        build_right();
+    }
+}
+
+/// Returns count of codes going to the left child.
+/// Range is split where the highest differing bit changes, equal codes are split in the middle.
+#[inline]
+fn find_split(codes: &[u32]) -> usize {
+    let first = codes[0];
+    let last = codes[codes.len() - 1];
+    if first == last {
+        return codes.len() / 2;
+    }
+    let common_prefix = (first ^ last).leading_zeros();
+    // Codes are sorted, so codes sharing more than common prefix with the first one go first
+    codes.partition_point(|x| (first ^ x).leading_zeros() > common_prefix)
+}
+
+/// Calculates node bounds bottom-up and turns subtrees into leaves where it's cheaper by SAH
+fn collapse(nodes: &mut [BvhNode], bounds: &[Bounds]) {
+    // Cost and objects range of every subtree relative to not normalized area
+    let mut costs = vec![0.0; nodes.len()];
+    let mut ranges = vec![(0, 0); nodes.len()];
+    // Children are always placed after the parent
+    for i in (0..nodes.len()).rev() {
+        let node = &nodes[i];
+        if node.is_leaf() {
+            let mut aabb = Aabb::default();
+            for bounds in &bounds[node.first_object..node.first_object + node.object_count] {
+                aabb.grow(bounds.aabb_min);
+                aabb.grow(bounds.aabb_max);
+            }
+            costs[i] = node.object_count as f32 * aabb.area();
+            ranges[i] = (node.first_object, node.object_count);
+            nodes[i].aabb_min = aabb.bmin;
+            nodes[i].aabb_max = aabb.bmax;
+            continue;
+        }
+        let (left, right) = (node.first_object, node.first_object + 1);
+        let aabb = Aabb::from(&nodes[left]).union(Aabb::from(&nodes[right]));
+        let range = (ranges[left].0, ranges[left].1 + ranges[right].1);
+        let leaf_cost = range.1 as f32 * aabb.area();
+        let split_cost = aabb.area() + costs[left] + costs[right];
+        let node = &mut nodes[i];
+        node.aabb_min = aabb.bmin;
+        node.aabb_max = aabb.bmax;
+        ranges[i] = range;
+        if leaf_cost <= split_cost {
+            node.first_object = range.0;
+            node.object_count = range.1;
+            costs[i] = leaf_cost;
+        } else {
+            costs[i] = split_cost;
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use nalgebra::Vector3;
 use crate::{math::ray::Ray, entity::{hit::{Intersection, Hittable}, Bounds}};
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct BvhNode {
     pub aabb_min: Vector3<f32>,
     pub aabb_max: Vector3<f32>,
@@ -37,11 +37,11 @@ impl BvhNode {
         let tz2 = (self.aabb_max.z - ray.origin.z) * dirfrac.z;
         tmin = tmin.max(tz1.min(tz2));
         tmax = tmax.min(tz1.max(tz2));
-        tmax >= tmin && tmax > 0.0
+        tmax >= tmin && tmax > ray.t_min && tmin < ray.t_max
     }
 
     #[inline]
-    pub fn intersect_point(&self, ray: &Ray) -> Option<Intersection<BvhNode>> {
+    pub fn intersect_point(&self, ray: &Ray) -> Option<Intersection<'_, BvhNode>> {
         let dirfrac = ray.get_frac_direction();
 
         let t1 = (self.aabb_min.x - ray.origin.x) * dirfrac.x;
@@ -56,28 +56,18 @@ impl BvhNode {
         let tmax = f32::min(f32::min(f32::max(t1, t2),
             f32::max(t3, t4)), f32::max(t5, t6));
 
-        // if tmax < 0, ray (line) is intersecting AABB, but the whole AABB is behind us
-        if tmax < 0.0 {
-            // t = tmax;
-            return None;
-        }
-
         // if tmin > tmax, ray doesn't intersect AABB
         if tmin > tmax {
-            // t = tmax;
             return None;
         }
-
-        if tmin < 0.0 {
-            return Some(Intersection::<BvhNode>::new(tmax, self));
+        // Enter point, or exit point if ray starts inside or enter point is before ray interval
+        if tmin > ray.t_min && tmin < ray.t_max {
+            return Some(Intersection::<BvhNode>::new(tmin, self));
         }
-
-        if tmin < 0.0 {
+        if tmax > ray.t_min && tmax < ray.t_max {
             return Some(Intersection::<BvhNode>::new(tmax, self));
         }
-
-        // t = tmin;
-        Some(Intersection::<BvhNode>::new(tmin, self))
+        None
     }
 
     #[inline]
@@ -96,7 +86,7 @@ impl BvhNode {
         let tmax = f32::min(f32::min(f32::max(t1, t2),
             f32::max(t3, t4)), f32::max(t5, t6));
         
-        if tmax >= tmin && tmax > 0.0 {
+        if tmax >= tmin && tmax > ray.t_min && tmin < ray.t_max {
             tmin
         } else {
             f32::INFINITY
@@ -129,14 +119,14 @@ impl BvhNode {
     #[inline]
     pub fn distance_to_edge(&self, point: &Vector3<f32>) -> f32 {
         let ma_x = 
-            (if (point.x - self.aabb_min.x).abs() < 0.00001 {std::f32::MAX} else {point.x - self.aabb_min.x}).abs()
-            .min((if (point.x - self.aabb_max.x).abs() < 0.00001 {std::f32::MAX} else {point.x - self.aabb_max.x}).abs());
+            (if (point.x - self.aabb_min.x).abs() < 0.00001 {f32::MAX} else {point.x - self.aabb_min.x}).abs()
+            .min((if (point.x - self.aabb_max.x).abs() < 0.00001 {f32::MAX} else {point.x - self.aabb_max.x}).abs());
         let ma_y = 
-            (if (point.y - self.aabb_min.y).abs() < 0.00001 {std::f32::MAX} else {point.y - self.aabb_min.y}).abs()
-            .min((if (point.y - self.aabb_max.y).abs() < 0.00001 {std::f32::MAX} else {point.y - self.aabb_max.y}).abs());
+            (if (point.y - self.aabb_min.y).abs() < 0.00001 {f32::MAX} else {point.y - self.aabb_min.y}).abs()
+            .min((if (point.y - self.aabb_max.y).abs() < 0.00001 {f32::MAX} else {point.y - self.aabb_max.y}).abs());
         let ma_z = 
-            (if (point.z - self.aabb_min.z).abs() < 0.00001 {std::f32::MAX} else {point.z - self.aabb_min.z}).abs()
-            .min((if (point.z - self.aabb_max.z).abs() < 0.00001 {std::f32::MAX} else {point.z - self.aabb_max.z}).abs());
+            (if (point.z - self.aabb_min.z).abs() < 0.00001 {f32::MAX} else {point.z - self.aabb_min.z}).abs()
+            .min((if (point.z - self.aabb_max.z).abs() < 0.00001 {f32::MAX} else {point.z - self.aabb_max.z}).abs());
         ma_x.min(ma_y).min(ma_z)
     }
 
@@ -170,7 +160,7 @@ impl From<&BvhNode> for Bounds {
 
 impl Hittable<BvhNode> for BvhNode {
     #[inline]
-    fn intersect(&self, ray: &Ray) -> Option<Intersection<BvhNode>> {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_, BvhNode>> {
         self.intersect_point(ray)
     }
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+use nalgebra::Vector3;
+use crate::{entity::hit::{Hit, HittableSet, Intersection}, math::ray::Ray};
+use super::{Bvh, BvhNode};
+
+/// Side of square screen tile traced as one packet
+pub const PACKET_TILE_SIZE: usize = 8;
+/// Relative error allowed for plane distances, so rays on frustum planes are never culled
+const FRUSTUM_TOLERANCE: f32 = 1e-5;
+
+/// Frustum bounding rays with a common origin.
+/// Made of 4 side planes through the origin and a near plane at the origin.
+#[derive(Debug, Clone)]
+pub struct Frustum {
+    pub origin: Vector3<f32>,
+    /// Normals pointing inside, points with negative distance are outside
+    pub planes: [Vector3<f32>; 5],
+}
+
+impl Frustum {
+    /// Returns `None` if rays don't share origin or don't go to the same side along any axis
+    pub fn new(rays: &[Ray]) -> Option<Self> {
+        let first = rays.first()?;
+        let origin = first.origin;
+        if rays.iter().any(|x| x.origin != origin) {
+            return None;
+        }
+        // Axis along which all rays go to the same side, slopes to it are bounded
+        let k = first.get_direction().iamax();
+        let sign = first.get_direction()[k].signum();
+        if rays.iter().any(|x| x.get_direction()[k] * sign <= 0.0) {
+            return None;
+        }
+        let mut planes = [Vector3::zeros(); 5];
+        planes[0][k] = sign;
+        for (n, i) in [(k + 1) % 3, (k + 2) % 3].into_iter().enumerate() {
+            let (min_slope, max_slope) = rays.iter()
+                .map(|x| x.get_direction()[i] / x.get_direction()[k])
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), x| (min.min(x), max.max(x)));
+            // Distance to plane of slope `s` is `(p_i - o_i) - s * (p_k - o_k)` times direction sign
+            planes[n * 2 + 1][i] = sign;
+            planes[n * 2 + 1][k] = -min_slope * sign;
+            planes[n * 2 + 2][i] = -sign;
+            planes[n * 2 + 2][k] = max_slope * sign;
+        }
+        Some(Frustum { origin, planes })
+    }
+
+    /// Returns true if box is surely outside of the frustum
+    #[inline]
+    pub fn culls(&self, node: &BvhNode) -> bool {
+        self.planes.iter().any(|normal| {
+            // Corner of the box which is the furthest along the normal
+            let corner = Vector3::from_fn(|i, _| if normal[i] > 0.0 { node.aabb_max[i] } else { node.aabb_min[i] }) - self.origin;
+            normal.dot(&corner) < -FRUSTUM_TOLERANCE * normal.abs().dot(&corner.abs())
+        })
+    }
+}
+
+/// Coherent rays traced together, usually camera rays of a screen tile.
+/// Rays with a common origin are culled with their frustum.
+#[derive(Debug, Clone)]
+pub struct RayPacket {
+    rays: Vec<Ray>,
+    frustum: Option<Frustum>,
+}
+
+impl RayPacket {
+    #[inline]
+    pub fn new(rays: Vec<Ray>) -> Self {
+        let frustum = Frustum::new(&rays);
+        RayPacket { rays, frustum }
+    }
+
+    #[inline]
+    pub fn rays(&self) -> &[Ray] {
+        &self.rays
+    }
+
+    #[inline]
+    pub fn frustum(&self) -> Option<&Frustum> {
+        self.frustum.as_ref()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.rays.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rays.is_empty()
+    }
+}
+
+impl Bvh {
+    /// Closest hits of all rays of the packet, in order of rays.
+    /// Nodes are visited once for the whole packet, for range of rays from the first to the last one hitting the node.
+    /// Rays out of the range missed the node or one of its ancestors, so they are skipped in the subtree.
+    pub fn intersect_packet<'a, T, S>(&'a self, packet: &RayPacket, objects: &'a S) -> Vec<Option<Hit<'a, T>>>
+    where S: HittableSet<T> + ?Sized {
+        let rays = packet.rays();
+        let mut closest_hits: Vec<Option<Intersection<'a, T>>> = rays.iter().map(|_| None).collect();
+        let mut closest_dists: Vec<f32> = rays.iter().map(|x| x.t_max).collect();
+        // (node index, first active ray, end of active rays)
+        let mut stack: Vec<(usize, usize, usize)> = vec![];
+        if !self.bvhs.is_empty() && !rays.is_empty() {
+            stack.push((0, 0, rays.len()));
+        }
+        while let Some((index, first_active, end_active)) = stack.pop() {
+            let node = &self.bvhs[index];
+            if packet.frustum().is_some_and(|x| x.culls(node)) {
+                continue;
+            }
+            let hits_node = |i: &usize| node.intersect_distance(&rays[*i]) < closest_dists[*i];
+            let Some(first) = (first_active..end_active).find(hits_node) else {
+                continue;
+            };
+            let end = (first + 1..end_active).rfind(hits_node).unwrap_or(first) + 1;
+            if node.is_leaf() {
+                let objects_indexes = &self.objects_indexes[node.first_object..node.first_object + node.object_count];
+                for i in first..end {
+                    if i > first && i + 1 < end && node.intersect_distance(&rays[i]) >= closest_dists[i] {
+                        continue;
+                    }
+                    for object in objects_indexes {
+                        if let Some(hit) = objects.intersect_object(*object, &rays[i]) {
+                            if hit.t < closest_dists[i] {
+                                closest_dists[i] = hit.t;
+                                closest_hits[i] = Some(hit);
+                            }
+                        }
+                    }
+                }
+            } else {
+                // Nearer child for the first active ray is visited first
+                let (left, right) = (node.first_object, node.first_object + 1);
+                let left_dist = self.bvhs[left].intersect_distance(&rays[first]);
+                let right_dist = self.bvhs[right].intersect_distance(&rays[first]);
+                if left_dist <= right_dist {
+                    stack.push((right, first, end));
+                    stack.push((left, first, end));
+                } else {
+                    stack.push((left, first, end));
+                    stack.push((right, first, end));
+                }
+            }
+        }
+        closest_hits.into_iter().zip(rays).map(|(hit, ray)| hit.map(|hit| hit.into_hit(ray))).collect()
+    }
+}
@@ -0,0 +1,44 @@
+use crate::entity::Bounds;
+use super::{Bvh, Aabb};
+
+impl Bvh {
+    /// Recomputes bounds of nodes bottom-up for moved objects, keeps the tree topology.
+    /// `objects_bounds` are indexed by object, like the ones passed to `calculate_bvh`.
+    /// Returns SAH cost of the refitted tree relative to the cost after build,
+    /// tree should be rebuilt when it gets much higher than 1.
+    pub fn refit(&mut self, objects_bounds: &[Bounds]) -> f32 {
+        // Clipped references of spatial splits get bounds of the whole object
+        for ((bounds, centroid), index) in self.objects_bounds.iter_mut().zip(&mut self.objects_centroids).zip(&self.objects_indexes) {
+            *bounds = objects_bounds[*index].clone();
+            *centroid = bounds.centroid;
+        }
+        // Children are always placed after the parent
+        for i in (0..self.bvhs.len()).rev() {
+            let node = &self.bvhs[i];
+            let aabb = if node.is_leaf() {
+                let mut aabb = Aabb::default();
+                for bounds in &self.objects_bounds[node.first_object..node.first_object + node.object_count] {
+                    aabb.grow(bounds.aabb_min);
+                    aabb.grow(bounds.aabb_max);
+                }
+                aabb
+            } else {
+                Aabb::from(&self.bvhs[node.first_object]).union(Aabb::from(&self.bvhs[node.first_object + 1]))
+            };
+            let node = &mut self.bvhs[i];
+            node.aabb_min = aabb.bmin;
+            node.aabb_max = aabb.bmax;
+        }
+        self.degradation()
+    }
+
+    /// SAH cost of the tree relative to the cost right after build
+    #[inline]
+    pub fn degradation(&self) -> f32 {
+        if self.built_sah_cost > 0.0 {
+            self.sah_cost() / self.built_sah_cost
+        } else {
+            1.0
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use nalgebra::Vector3;
+use crate::entity::Bounds;
+use super::{BvhNode, Aabb, bvh_builder::{division_plane_binned, objects_aabb, PARALLEL_BUILD_THRESHOLD, MAX_PARALLEL_DEPTH, MIN_BINS}};
+
+/// Spatial split is tried only when children of object split overlap
+/// by more than this part of the root area
+const OVERLAP_THRESHOLD: f32 = 1e-5;
+
+/// Splits object references by planes for spatial split build
+pub trait ReferenceClipper {
+    /// Returns bounds of the object parts on both sides of plane `pos` on `axis`, limited by `bounds`.
+    /// `None` if object has no part on that side.
+    fn split(&self, object: usize, bounds: &Bounds, axis: usize, pos: f32) -> (Option<Bounds>, Option<Bounds>);
+}
+
+/// Splits only bounding boxes, used when shapes of objects are unknown
+pub struct BoxClipper;
+
+impl ReferenceClipper for BoxClipper {
+    #[inline]
+    fn split(&self, _object: usize, bounds: &Bounds, axis: usize, pos: f32) -> (Option<Bounds>, Option<Bounds>) {
+        let mut left_max = bounds.aabb_max;
+        left_max[axis] = left_max[axis].min(pos);
+        let mut right_min = bounds.aabb_min;
+        right_min[axis] = right_min[axis].max(pos);
+        (
+            clip_bounds(bounds.aabb_min, left_max, bounds),
+            clip_bounds(right_min, bounds.aabb_max, bounds),
+        )
+    }
+}
+
+/// Bounds of box `min`-`max` inside `bounds`, `None` if they don't overlap
+#[inline]
+pub fn clip_bounds(min: Vector3<f32>, max: Vector3<f32>, bounds: &Bounds) -> Option<Bounds> {
+    let min = min.sup(&bounds.aabb_min);
+    let max = max.inf(&bounds.aabb_max);
+    if min.x > max.x || min.y > max.y || min.z > max.z {
+        return None;
+    }
+    Some(Bounds::new((min + max) * 0.5, min, max))
+}
+
+/// Object references of a subtree, one object may be referenced several times
+#[derive(Default)]
+pub(super) struct References {
+    pub bounds: Vec<Bounds>,
+    pub centroids: Vec<Vector3<f32>>,
+    pub indexes: Vec<usize>,
+}
+
+impl References {
+    #[inline]
+    fn with_capacity(capacity: usize) -> Self {
+        References {
+            bounds: Vec::with_capacity(capacity),
+            centroids: Vec::with_capacity(capacity),
+            indexes: Vec::with_capacity(capacity),
+        }
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.indexes.len()
+    }
+
+    #[inline(always)]
+    fn push(&mut self, index: usize, bounds: Bounds) {
+        self.centroids.push(bounds.centroid);
+        self.bounds.push(bounds);
+        self.indexes.push(index);
+    }
+
+    #[inline]
+    fn append(&mut self, other: &mut References) {
+        self.bounds.append(&mut other.bounds);
+        self.centroids.append(&mut other.centroids);
+        self.indexes.append(&mut other.indexes);
+    }
+}
+
+/// Nodes of a subtree with its references, indices are local to the subtree
+struct SubTree {
+    nodes: Vec<BvhNode>,
+    references: References,
+}
+
+/// References of a node which is not built yet, with budget of duplicated references for its subtree
+struct BuildTask {
+    references: References,
+    budget: usize,
+}
+
+/// Result of splitting one node
+enum NodeSplit {
+    Leaf(SubTree),
+    /// Inner node with children still to build
+    Inner(BvhNode, BuildTask, BuildTask),
+}
+
+/// Step of subtree build with explicit stack
+enum BuildStep {
+    Build(BuildTask),
+    /// Merges two last built subtrees under the node
+    Merge(BvhNode),
+}
+
+#[derive(Clone, Copy)]
+enum Split {
+    Object { axis: usize, pos: f32 },
+    Spatial { axis: usize, pos: f32 },
+}
+
+#[derive(Clone, Copy)]
+struct SpatialBin {
+    aabb: Aabb,
+    entries: usize,
+    exits: usize,
+}
+
+impl Default for SpatialBin {
+    #[inline]
+    fn default() -> Self {
+        SpatialBin { aabb: Aabb::default(), entries: 0, exits: 0 }
+    }
+}
+
+/// Spatial split bvh build (SBVH, Stich et al. 2009).
+/// References of objects overlapping a split plane can be clipped and put to both children.
+pub(super) struct SpatialBuild<'a, C: ReferenceClipper + Sync> {
+    pub clipper: &'a C,
+    pub bins: usize,
+    root_area: f32,
+}
+
+impl<'a, C: ReferenceClipper + Sync> SpatialBuild<'a, C> {
+    #[inline]
+    pub fn new(clipper: &'a C, bins: usize) -> Self {
+        SpatialBuild { clipper, bins: bins.max(MIN_BINS), root_area: 0.0 }
+    }
+
+    /// Builds tree, root is the first node.
+    /// Number of references grows by no more than `max_duplication` of objects count.
+    pub fn build(mut self, references: References, max_duplication: f32) -> (Vec<BvhNode>, References) {
+        if references.len() == 0 {
+            return (vec![], references);
+        }
+        self.root_area = objects_aabb(&references.bounds).area();
+        let budget = (references.len() as f32 * max_duplication.max(0.0)) as usize;
+        let tree = self.build_node(BuildTask { references, budget }, 0);
+        (tree.nodes, tree.references)
+    }
+
+    /// Builds subtrees of big nodes in parallel up to `MAX_PARALLEL_DEPTH`, the rest without recursion
+    fn build_node(&self, task: BuildTask, depth: u32) -> SubTree {
+        let parallel = task.references.len() > PARALLEL_BUILD_THRESHOLD && depth < MAX_PARALLEL_DEPTH;
+        let (node, left, right) = match self.split_node(task) {
+            NodeSplit::Leaf(tree) => return tree,
+            NodeSplit::Inner(node, left, right) => (node, left, right),
+        };
+        let (left, right) = if parallel {
+            rayon::join(|| self.build_node(left, depth + 1), || self.build_node(right, depth + 1))
+        } else {
+            (self.build_subtree(left), self.build_subtree(right))
+        };
+        merge(node, left, right)
+    }
+
+    /// Builds subtree on the current thread with explicit stack, so deep trees can't overflow the thread stack
+    fn build_subtree(&self, task: BuildTask) -> SubTree {
+        let mut steps = vec![BuildStep::Build(task)];
+        let mut built: Vec<SubTree> = vec![];
+        while let Some(step) = steps.pop() {
+            match step {
+                BuildStep::Build(task) => match self.split_node(task) {
+                    NodeSplit::Leaf(tree) => built.push(tree),
+                    NodeSplit::Inner(node, left, right) => {
+                        steps.push(BuildStep::Merge(node));
+                        steps.push(BuildStep::Build(right));
+                        steps.push(BuildStep::Build(left));
+                    },
+                },
+                BuildStep::Merge(node) => {
+                    let right = built.pop().expect("Right subtree is built before merge");
+                    let left = built.pop().expect("Left subtree is built before merge");
+                    built.push(merge(node, left, right));
+                },
+            }
+        }
+        built.pop().expect("Subtree is built")
+    }
+
+    /// Finds the best object or spatial split of node, returns leaf if splitting doesn't pay off
+    fn split_node(&self, task: BuildTask) -> NodeSplit {
+        let BuildTask { mut references, budget } = task;
+        let count = references.len();
+        let aabb = objects_aabb(&references.bounds);
+        let node = BvhNode { aabb_min: aabb.bmin, aabb_max: aabb.bmax, first_object: 0, object_count: count };
+
+        let node_cost: f32 = count as f32 * aabb.area();
+        let (object_pos, object_axis, object_cost) = division_plane_binned(&references.bounds, &references.centroids, self.bins);
+        let mut best_split = Split::Object { axis: object_axis as usize, pos: object_pos };
+        let mut best_cost = object_cost;
+        // Spatial split helps only if children of object split overlap, or objects can't be divided at all
+        let try_spatial = object_cost >= 1e30
+            || self.object_split_overlap(&references, object_axis as usize, object_pos) > OVERLAP_THRESHOLD * self.root_area;
+        if budget > 0 && try_spatial {
+            if let Some((axis, pos, cost, duplicates)) = self.spatial_plane(&references, &aabb) {
+                if cost < best_cost && duplicates <= budget {
+                    best_split = Split::Spatial { axis, pos };
+                    best_cost = cost;
+                }
+            }
+        }
+        if best_cost >= node_cost {
+            return NodeSplit::Leaf(SubTree { nodes: vec![node], references });
+        }
+        // Divide
+        let (left, right) = match best_split {
+            Split::Object { axis, pos } => Self::object_split(&mut references, axis, pos),
+            Split::Spatial { axis, pos } => self.spatial_split(&references, axis, pos),
+        };
+        if left.len() == 0 || right.len() == 0 {
+            return NodeSplit::Leaf(SubTree { nodes: vec![node], references });
+        }
+        // Split remaining budget between children by their size
+        let children_count = left.len() + right.len();
+        let budget = budget.saturating_sub(children_count - count);
+        let left_budget = budget * left.len() / children_count;
+        let right_budget = budget - left_budget;
+        NodeSplit::Inner(node, BuildTask { references: left, budget: left_budget }, BuildTask { references: right, budget: right_budget })
+    }
+
+    /// Area of overlap of children boxes after object split
+    #[inline]
+    fn object_split_overlap(&self, references: &References, axis: usize, pos: f32) -> f32 {
+        let mut left_box = Aabb::default();
+        let mut right_box = Aabb::default();
+        for (centroid, bounds) in references.centroids.iter().zip(&references.bounds) {
+            let side = if centroid[axis] < pos { &mut left_box } else { &mut right_box };
+            side.grow(bounds.aabb_min);
+            side.grow(bounds.aabb_max);
+        }
+        left_box.intersection(right_box).map_or(0.0, |x| x.area())
+    }
+
+    /// Returns (axis, split_pos, cost, duplicated references count) of the best spatial split
+    fn spatial_plane(&self, references: &References, aabb: &Aabb) -> Option<(usize, f32, f32, usize)> {
+        let bins_count = self.bins;
+        let mut best: Option<(usize, f32, f32, usize)> = None;
+        let mut bins: Vec<SpatialBin> = vec![SpatialBin::default(); bins_count];
+        let mut left_area: Vec<f32> = vec![0.0; bins_count - 1];
+        let mut left_count: Vec<usize> = vec![0; bins_count - 1];
+        for axis in 0..3 {
+            let bounds_min: f32 = aabb.bmin[axis];
+            let bounds_max: f32 = aabb.bmax[axis];
+            if bounds_min == bounds_max {
+                continue;
+            }
+            // Clip every reference to the bins it overlaps
+            bins.fill(SpatialBin::default());
+            let scale: f32 = bins_count as f32 / (bounds_max - bounds_min);
+            let plane_step: f32 = (bounds_max - bounds_min) / bins_count as f32;
+            let bin_index = |x: f32| usize::min(bins_count - 1, ((x - bounds_min) * scale).max(0.0) as usize);
+            for (bounds, index) in references.bounds.iter().zip(&references.indexes) {
+                let first_bin = bin_index(bounds.aabb_min[axis]);
+                let last_bin = bin_index(bounds.aabb_max[axis]);
+                bins[first_bin].entries += 1;
+                bins[last_bin].exits += 1;
+                let mut rest = Some(Bounds::new(bounds.centroid, bounds.aabb_min, bounds.aabb_max));
+                for (bin, i) in bins[first_bin..last_bin].iter_mut().zip(first_bin..) {
+                    let Some(current) = rest else {
+                        break;
+                    };
+                    let (left, right) = self.clipper.split(*index, &current, axis, bounds_min + plane_step * (i + 1) as f32);
+                    if let Some(left) = left {
+                        bin.aabb.grow(left.aabb_min);
+                        bin.aabb.grow(left.aabb_max);
+                    }
+                    rest = right;
+                }
+                if let Some(rest) = rest {
+                    bins[last_bin].aabb.grow(rest.aabb_min);
+                    bins[last_bin].aabb.grow(rest.aabb_max);
+                }
+            }
+            // Sweep from the left, then from the right evaluating cost of each plane
+            let mut left_box = Aabb::default();
+            let mut left_sum = 0;
+            for i in 0..bins_count - 1 {
+                left_sum += bins[i].entries;
+                if !bins[i].aabb.is_empty() {
+                    left_box = left_box.union(bins[i].aabb);
+                }
+                left_count[i] = left_sum;
+                left_area[i] = left_box.area();
+            }
+            let mut right_box = Aabb::default();
+            let mut right_sum = 0;
+            for i in (0..bins_count - 1).rev() {
+                right_sum += bins[i + 1].exits;
+                if !bins[i + 1].aabb.is_empty() {
+                    right_box = right_box.union(bins[i + 1].aabb);
+                }
+                if left_count[i] == 0 || right_sum == 0 {
+                    continue;
+                }
+                let plane_cost: f32 = left_count[i] as f32 * left_area[i] + right_sum as f32 * right_box.area();
+                if best.is_none_or(|x| plane_cost < x.2) {
+                    let duplicates = (left_count[i] + right_sum).saturating_sub(references.len());
+                    best = Some((axis, bounds_min + plane_step * (i + 1) as f32, plane_cost, duplicates));
+                }
+            }
+        }
+        best
+    }
+
+    #[inline]
+    fn object_split(references: &mut References, axis: usize, pos: f32) -> (References, References) {
+        let mut left = References::with_capacity(references.len());
+        let mut right = References::with_capacity(references.len());
+        references.centroids.clear();
+        for (bounds, index) in references.bounds.drain(..).zip(references.indexes.drain(..)) {
+            let side = if bounds.centroid[axis] < pos { &mut left } else { &mut right };
+            side.push(index, bounds);
+        }
+        (left, right)
+    }
+
+    /// Puts references to the sides of plane, clips references crossing it to both sides
+    #[inline]
+    fn spatial_split(&self, references: &References, axis: usize, pos: f32) -> (References, References) {
+        let mut left = References::with_capacity(references.len());
+        let mut right = References::with_capacity(references.len());
+        for (bounds, index) in references.bounds.iter().zip(&references.indexes) {
+            let bounds = Bounds::new(bounds.centroid, bounds.aabb_min, bounds.aabb_max);
+            if bounds.aabb_max[axis] <= pos {
+                left.push(*index, bounds);
+            } else if bounds.aabb_min[axis] >= pos {
+                right.push(*index, bounds);
+            } else {
+                match self.clipper.split(*index, &bounds, axis, pos) {
+                    (Some(left_part), Some(right_part)) => {
+                        left.push(*index, left_part);
+                        right.push(*index, right_part);
+                    },
+                    (Some(_), None) => left.push(*index, bounds),
+                    (None, _) => right.push(*index, bounds),
+                }
+            }
+        }
+        (left, right)
+    }
+}
+
+/// Places children roots right after the node, then descendants of the left and the right child
+fn merge(mut node: BvhNode, left: SubTree, right: SubTree) -> SubTree {
+    let left_nodes = left.nodes.len();
+    let left_objects = left.references.len();
+    let mut nodes: Vec<BvhNode> = Vec::with_capacity(1 + left_nodes + right.nodes.len());
+    node.first_object = 1;
+    node.object_count = 0;
+    nodes.push(node);
+
+    let remap_left = |i: usize| if i == 0 { 1 } else { i + 2 };
+    let remap_right = |i: usize| if i == 0 { 2 } else { i + left_nodes + 1 };
+    let relocate = |mut node: BvhNode, remap: &dyn Fn(usize) -> usize, objects_offset: usize| {
+        if node.is_leaf() {
+            node.first_object += objects_offset;
+        } else {
+            node.first_object = remap(node.first_object);
+        }
+        node
+    };
+    let mut left_nodes_iter = left.nodes.into_iter();
+    let mut right_nodes_iter = right.nodes.into_iter();
+    nodes.extend(left_nodes_iter.next().map(|x| relocate(x, &remap_left, 0)));
+    nodes.extend(right_nodes_iter.next().map(|x| relocate(x, &remap_right, left_objects)));
+    nodes.extend(left_nodes_iter.map(|x| relocate(x, &remap_left, 0)));
+    nodes.extend(right_nodes_iter.map(|x| relocate(x, &remap_right, left_objects)));
+
+    let mut references = left.references;
+    let mut right_references = right.references;
+    references.append(&mut right_references);
+    SubTree { nodes, references }
+}
@@ -0,0 +1,231 @@
+use wide::{f32x4, f32x8, CmpGe, CmpGt, CmpLt};
+use crate::math::ray::Ray;
+use crate::entity::hit::{Hit, HittableSet, Intersection};
+use super::{Bvh, BvhNode, Aabb};
+
+/// Traversal stack entries kept on the stack, deeper trees spill the rest to the heap
+const STACK_SIZE: usize = 512;
+
+/// Node of wide bvh with bounds of up to `N` children in SoA layout.
+/// Unused child slots have bounds at infinity, so rays never hit them.
+#[derive(Debug, Clone)]
+pub struct WideNode<const N: usize> {
+    pub min_x: [f32; N],
+    pub min_y: [f32; N],
+    pub min_z: [f32; N],
+    pub max_x: [f32; N],
+    pub max_y: [f32; N],
+    pub max_z: [f32; N],
+    /// Index of the child node, or first object reference if child is a leaf
+    pub children: [u32; N],
+    /// Object count of leaf child, 0 for inner child
+    pub counts: [u32; N],
+}
+
+impl<const N: usize> Default for WideNode<N> {
+    #[inline]
+    fn default() -> Self {
+        WideNode {
+            min_x: [f32::INFINITY; N],
+            min_y: [f32::INFINITY; N],
+            min_z: [f32::INFINITY; N],
+            max_x: [f32::INFINITY; N],
+            max_y: [f32::INFINITY; N],
+            max_z: [f32::INFINITY; N],
+            children: [0; N],
+            counts: [0; N],
+        }
+    }
+}
+
+/// Intersection of a ray with all child boxes at once
+pub trait WideBoxes<const N: usize> {
+    /// Entry distances to child boxes, infinity for missed boxes,
+    /// boxes before ray interval and boxes further than `t_max`
+    fn intersect_children(&self, ray: &Ray, t_max: f32) -> [f32; N];
+}
+
+macro_rules! impl_wide_boxes {
+    ($lanes:literal, $simd:ident) => {
+        impl WideBoxes<$lanes> for WideNode<$lanes> {
+            #[inline(always)]
+            fn intersect_children(&self, ray: &Ray, t_max: f32) -> [f32; $lanes] {
+                let dirfrac = ray.get_frac_direction();
+                let slab = |min: &[f32; $lanes], max: &[f32; $lanes], origin: f32, dirfrac: f32| {
+                    let origin = $simd::splat(origin);
+                    let dirfrac = $simd::splat(dirfrac);
+                    let t1 = ($simd::from(*min) - origin) * dirfrac;
+                    let t2 = ($simd::from(*max) - origin) * dirfrac;
+                    (t1.min(t2), t1.max(t2))
+                };
+                let (x_near, x_far) = slab(&self.min_x, &self.max_x, ray.origin.x, dirfrac.x);
+                let (y_near, y_far) = slab(&self.min_y, &self.max_y, ray.origin.y, dirfrac.y);
+                let (z_near, z_far) = slab(&self.min_z, &self.max_z, ray.origin.z, dirfrac.z);
+                let tmin = x_near.max(y_near).max(z_near);
+                let tmax = x_far.min(y_far).min(z_far);
+                let hit = tmax.cmp_ge(tmin) & tmax.cmp_gt($simd::splat(ray.t_min)) & tmin.cmp_lt($simd::splat(t_max));
+                hit.blend(tmin, $simd::splat(f32::INFINITY)).to_array()
+            }
+        }
+    };
+}
+
+impl_wide_boxes!(4, f32x4);
+impl_wide_boxes!(8, f32x8);
+
+/// Bvh with `N` children per node, made by collapsing binary [`Bvh`].
+/// Tests boxes of all children with one SIMD operation.
+#[derive(Debug, Clone, Default)]
+pub struct WideBvh<const N: usize> {
+    nodes: Vec<WideNode<N>>,
+    objects_indexes: Vec<usize>,
+}
+
+pub type Bvh4 = WideBvh<4>;
+pub type Bvh8 = WideBvh<8>;
+
+#[derive(Clone, Copy)]
+struct StackEntry {
+    child: u32,
+    count: u32,
+    distance: f32,
+}
+
+impl<const N: usize> WideBvh<N> where WideNode<N>: WideBoxes<N> {
+    /// Collapses binary tree, children with the biggest area are opened first
+    pub fn new(bvh: &Bvh) -> Self {
+        assert!(N >= 2, "Wide bvh node needs at least 2 children");
+        let mut wide = WideBvh { nodes: vec![], objects_indexes: bvh.objects_indexes.clone() };
+        if let Some(root) = bvh.bvhs.first() {
+            wide.nodes.push(WideNode::default());
+            wide.collapse(&bvh.bvhs, root, 0);
+        }
+        wide
+    }
+
+    #[inline]
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Fills wide node `index` with descendants of binary `node`
+    fn collapse(&mut self, bvhs: &[BvhNode], node: &BvhNode, index: usize) {
+        let mut children: Vec<&BvhNode> = if node.is_leaf() {
+            vec![node]
+        } else {
+            vec![&bvhs[node.first_object], &bvhs[node.first_object + 1]]
+        };
+        while children.len() < N {
+            // Open inner child with the biggest area
+            let Some(open) = children.iter().enumerate()
+                .filter(|x| !x.1.is_leaf())
+                .max_by(|a, b| Aabb::from(*a.1).area().total_cmp(&Aabb::from(*b.1).area()))
+                .map(|x| x.0) else {
+                break;
+            };
+            let child = children.remove(open);
+            children.insert(open, &bvhs[child.first_object + 1]);
+            children.insert(open, &bvhs[child.first_object]);
+        }
+
+        let mut wide_node = WideNode::default();
+        for (slot, child) in children.iter().enumerate() {
+            wide_node.min_x[slot] = child.aabb_min.x;
+            wide_node.min_y[slot] = child.aabb_min.y;
+            wide_node.min_z[slot] = child.aabb_min.z;
+            wide_node.max_x[slot] = child.aabb_max.x;
+            wide_node.max_y[slot] = child.aabb_max.y;
+            wide_node.max_z[slot] = child.aabb_max.z;
+            if child.is_leaf() {
+                wide_node.children[slot] = child.first_object as u32;
+                wide_node.counts[slot] = child.object_count as u32;
+            } else {
+                let child_index = self.nodes.len();
+                self.nodes.push(WideNode::default());
+                wide_node.children[slot] = child_index as u32;
+                self.collapse(bvhs, child, child_index);
+            }
+        }
+        self.nodes[index] = wide_node;
+    }
+
+    pub fn intersect<'a, T, S>(&'a self, ray: &Ray, objects: &'a S) -> Option<Hit<'a, T>>
+    where S: HittableSet<T> + ?Sized {
+        self.traverse::<T, S, false>(ray, ray.t_max, objects).map(|hit| hit.into_hit(ray))
+    }
+
+    /// Returns true if any object is hit closer than `t_max`, stops on the first found hit
+    #[inline]
+    pub fn occluded<T, S>(&self, ray: &Ray, t_max: f32, objects: &S) -> bool
+    where S: HittableSet<T> + ?Sized {
+        self.traverse::<T, S, true>(ray, t_max.min(ray.t_max), objects).is_some()
+    }
+
+    /// Returns the closest hit closer than `t_max`, or the first found one with `ANY_HIT`
+    fn traverse<'a, T, S, const ANY_HIT: bool>(&self, ray: &Ray, t_max: f32, objects: &'a S) -> Option<Intersection<'a, T>>
+    where S: HittableSet<T> + ?Sized {
+        let mut closest_hit: Option<Intersection<'a, T>> = None;
+        let mut closest_dist = t_max;
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut stack = [StackEntry { child: 0, count: 0, distance: 0.0 }; STACK_SIZE];
+        let mut stack_size = 1;
+        // Entries pushed after the stack is full, they are the most recent ones and are popped first
+        let mut spilled: Vec<StackEntry> = vec![];
+        while stack_size > 0 || !spilled.is_empty() {
+            let entry = match spilled.pop() {
+                Some(entry) => entry,
+                None => {
+                    stack_size -= 1;
+                    stack[stack_size]
+                },
+            };
+            if entry.distance >= closest_dist {
+                continue;
+            }
+            if entry.count > 0 {
+                let first = entry.child as usize;
+                for object in &self.objects_indexes[first..first + entry.count as usize] {
+                    if let Some(hit) = objects.intersect_object(*object, ray) {
+                        if hit.t < closest_dist {
+                            closest_dist = hit.t;
+                            closest_hit = Some(hit);
+                            if ANY_HIT {
+                                return closest_hit;
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
+            let node = &self.nodes[entry.child as usize];
+            let distances = node.intersect_children(ray, closest_dist);
+            // Push hit children from the farthest, so the nearest is visited first
+            let mut order = [0; N];
+            let mut hit_count = 0;
+            for slot in 0..N {
+                if distances[slot] == f32::INFINITY {
+                    continue;
+                }
+                let mut i = hit_count;
+                while i > 0 && distances[order[i - 1]] < distances[slot] {
+                    order[i] = order[i - 1];
+                    i -= 1;
+                }
+                order[i] = slot;
+                hit_count += 1;
+            }
+            for slot in &order[..hit_count] {
+                let entry = StackEntry { child: node.children[*slot], count: node.counts[*slot], distance: distances[*slot] };
+                if stack_size < STACK_SIZE {
+                    stack[stack_size] = entry;
+                    stack_size += 1;
+                } else {
+                    spilled.push(entry);
+                }
+            }
+        }
+        closest_hit
+    }
+}
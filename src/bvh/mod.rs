@@ -1,13 +1,27 @@
 mod bvh_intersection;
 mod bvh_depth;
 mod bvh_node;
+mod bvh_builder;
+mod bvh_spatial;
+mod bvh_lbvh;
+mod bvh_refit;
+mod bvh_cache;
+mod bvh_wide;
+mod bvh_packet;
 use bvh_intersection::BvhIntersection;
 pub use bvh_depth::BvhDepth;
 pub use bvh_node::BvhNode;
+pub use bvh_builder::BvhBuilder;
+pub use bvh_cache::Fnv1a;
+pub use bvh_wide::{WideBvh, WideNode, WideBoxes, Bvh4, Bvh8};
+pub use bvh_packet::{RayPacket, Frustum, PACKET_TILE_SIZE};
+use bvh_builder::BuildObjects;
+pub use bvh_spatial::{ReferenceClipper, BoxClipper, clip_bounds};
+use bvh_spatial::{SpatialBuild, References};
 
 use nalgebra::Vector3;
 use crate::math::ray::Ray;
-use crate::entity::hit::{Hit, Hittable};
+use crate::entity::hit::{Hit, HittableSet};
 use crate::entity::Bounds;
 
 pub struct Bvh {
@@ -15,7 +29,9 @@ pub struct Bvh {
     objects_bounds: Vec<Bounds>,
     objects_centroids: Vec<Vector3<f32>>,
     objects_indexes: Vec<usize>,
-    nodes_used: usize
+    builder: BvhBuilder,
+    /// SAH cost of the tree right after build, refit compares against it
+    built_sah_cost: f32,
 }
 
 impl Default for Bvh {
@@ -26,268 +42,143 @@ impl Default for Bvh {
             objects_bounds: vec![],
             objects_centroids: vec![],
             objects_indexes: vec![],
-            nodes_used: 0
+            builder: BvhBuilder::default(),
+            built_sah_cost: 0.0,
         }
     }
 }
 
 impl Bvh {
     #[inline]
-    pub fn intersect<'a, T>(&'a self, ray: &Ray, objects: &'a [T]) -> Option<Hit<T>>
-    where T: Hittable<T> {
+    pub fn new(builder: BvhBuilder) -> Self {
+        Bvh { builder, ..Default::default() }
+    }
+
+    #[inline]
+    pub fn builder(&self) -> BvhBuilder {
+        self.builder
+    }
+
+    /// Used on next `calculate_bvh` call
+    #[inline]
+    pub fn set_builder(&mut self, builder: BvhBuilder) {
+        self.builder = builder;
+    }
+
+    #[inline]
+    pub fn intersect<'a, T, S>(&'a self, ray: &Ray, objects: &'a S) -> Option<Hit<'a, T>>
+    where S: HittableSet<T> + ?Sized {
         let ray = ray.clone();
         // Get closest hit
         let mut bvh_intersection = BvhIntersection::new(self, &ray, objects);
         bvh_intersection.intersect_hierarchy();
-        bvh_intersection.closest_hit.map(|hit| Hit::<'a, T> {
-            t: hit.t,
-            point: ray.origin + ray.get_direction() * hit.t,
-            object: hit.object
-        })
+        bvh_intersection.closest_hit.map(|hit| hit.into_hit(&ray))
     }
 
+    /// Returns true if any object is hit closer than `t_max`.
+    /// Faster than `intersect`, because search stops on the first found hit.
     #[inline]
-    pub fn bvh_count(&self) -> usize {
-        self.bvhs.len()
+    pub fn occluded<T, S>(&self, ray: &Ray, t_max: f32, objects: &S) -> bool
+    where S: HittableSet<T> + ?Sized {
+        BvhIntersection::new(self, ray, objects).occluded(t_max)
     }
 
+    /// Same as `intersect`, but traverses the tree with recursion.
+    /// Slower, kept as reference for tests and benchmarks.
     #[inline]
-    pub fn get_bvh_by_depth(&self, depth: u32) -> Vec<BvhNode> {
-        let mut bd = BvhDepth::new(&self.bvhs, depth);
-        bd.intersect_hierarchy();
-        bd.bvhs
+    pub fn intersect_recursive<'a, T, S>(&'a self, ray: &Ray, objects: &'a S) -> Option<Hit<'a, T>>
+    where S: HittableSet<T> + ?Sized {
+        let mut bvh_intersection = BvhIntersection::new(self, ray, objects);
+        bvh_intersection.intersect_hierarchy_recursive();
+        bvh_intersection.closest_hit.map(|hit| hit.into_hit(ray))
     }
 
     #[inline]
-    pub fn calculate_bvh(&mut self, objects_bounds: Vec<Bounds>, objects_centroids: Vec<Vector3<f32>>) {
-        self.objects_indexes = (0..objects_bounds.len()).collect();
-        self.objects_bounds = objects_bounds;
-        self.objects_centroids = objects_centroids;
-
-        self.bvhs = Vec::with_capacity(self.objects_bounds.len() * 2 - 1);
-        self.bvhs.resize(self.bvhs.capacity(), BvhNode::new(0, 0));
-
-        self.nodes_used = 1;
-
-        let root_bvh = &mut self.bvhs[0];
-        root_bvh.first_object = 0;
-        root_bvh.object_count = self.objects_bounds.len();
-        self.calculate_bvh_bounds(0);
-
-        let mut current_index = 0;
-
-        while current_index < self.nodes_used {
-            self.calculate_childs(current_index);
-            current_index += 1;
-        }
-
-        self.bvhs.resize(self.nodes_used, BvhNode::new(0, 0));
+    pub fn bvh_count(&self) -> usize {
+        self.bvhs.len()
     }
 
+    /// Number of object references in leaves, more than objects count if spatial splits duplicated them
     #[inline]
-    fn calculate_childs(&mut self, bvh_index: usize) {
-        let bvh = &self.bvhs[bvh_index];
-
-        let e: Vector3<f32> = bvh.aabb_max - bvh.aabb_min; // extent of parent
-        let node_area: f32 = e.x * e.y + e.y * e.z + e.z * e.x;
-        let node_cost: f32 = bvh.object_count as f32 * node_area;
+    pub fn reference_count(&self) -> usize {
+        self.objects_indexes.len()
+    }
 
-        let (split_pos, divide_axis, best_cost) = self.division_plane(bvh);
-        if best_cost >= node_cost {
-            return;
-        }
-        // Divide
-        let mut i = bvh.first_object;
-        let mut j = i + bvh.object_count - 1;
-        while i <= j {
-            if self.objects_centroids[i][divide_axis as usize] < split_pos {
-                i += 1;
-            }
-            else {
-                self.objects_bounds.swap(i, j);
-                self.objects_indexes.swap(i, j);
-                self.objects_centroids.swap(i, j);
-                j -= 1;
+    /// Surface area heuristic cost of the tree relative to the root area.
+    /// Lower is better, allows to compare trees built for the same objects.
+    pub fn sah_cost(&self) -> f32 {
+        const TRAVERSAL_COST: f32 = 1.0;
+        const INTERSECTION_COST: f32 = 1.0;
+        let Some(root) = self.bvhs.first() else {
+            return 0.0;
+        };
+        let root_area = Aabb::from(root).area();
+        self.bvhs.iter().map(|x| {
+            let relative_area = Aabb::from(x).area() / root_area;
+            if x.is_leaf() {
+                relative_area * x.object_count as f32 * INTERSECTION_COST
+            } else {
+                relative_area * TRAVERSAL_COST
             }
-        }
-        let left_count = i - bvh.first_object;
-        if left_count == 0 || left_count == bvh.object_count {
-            return;
-        }
-        // Set bvhs
-        let left_node_index = self.nodes_used;
-        self.nodes_used += 1;
-        let right_node_index = self.nodes_used;
-        self.nodes_used += 1;
-        // Borrow checker wants this values to be copied
-        // So we don't have 2 mutable references
-        let first_object = bvh.first_object;
-        let object_count = bvh.object_count;
-        let bvh = &mut self.bvhs[bvh_index];
-        bvh.first_object = left_node_index;
-        bvh.object_count = 0;
-        
-        self.bvhs[left_node_index].first_object = first_object;
-        self.bvhs[left_node_index].object_count = left_count;
-        self.calculate_bvh_bounds(left_node_index);
-        
-        self.bvhs[right_node_index].first_object = i;
-        self.bvhs[right_node_index].object_count = object_count - left_count;
-        self.calculate_bvh_bounds(right_node_index);
+        }).sum()
     }
 
     #[inline]
-    /// Returns (split_pos, divide_axis, best_cost)
-    pub fn division_plane(&self, bvh: &BvhNode) -> (f32, i32, f32) {
-        // determine split axis using SAH
-        let mut best_axis: usize = 0;
-        let mut best_pos: f32 = 0.0;
-        let mut best_cost: f32 = 1e30;
-        for axis in 0..3 {
-            
-            // Calculate bounds by centroids of objects in this node
-            let mut bounds_min: f32 = 1e30;
-            let mut bounds_max: f32 = -1e30;
-            for i in 0..bvh.object_count {
-                let centroid = &self.objects_centroids[bvh.first_object + i];
-                bounds_min = bounds_min.min(centroid[axis]);
-                bounds_max = bounds_max.max(centroid[axis]);
-            }
-            if bounds_min == bounds_max {
-                continue;
-            }
-            let scale: f32 = (bounds_max - bounds_min) / 16.0;
-            for i in 0..16 {
-                let candidate_pos: f32 = bounds_min + i as f32 * scale;
-                let cost: f32 = self.evaluate_sah(bvh, axis, candidate_pos);
-                if cost < best_cost {
-                    best_axis = axis;
-                    best_pos = candidate_pos;
-                    best_cost = cost;
-                }
-            }
-            // Bins implementation (don't work)
-            /*
-            const BINS: usize = 8;
-            // Calculate bounds by centroids of objects in this node
-            let mut bounds_min: f32 = 1e30;
-            let mut bounds_max: f32 = -1e30;
-            for i in 0..bvh.object_count {
-                let centroid = &self.objects_centroids[bvh.first_object + i];
-                bounds_min = bounds_min.min(centroid[axis]);
-                bounds_max = bounds_max.max(centroid[axis]);
-            }
-            if bounds_min == bounds_max {
-                continue;
-            }
-            // populate the bins
-            let mut bins: [Bin; BINS] = [Bin::default(); BINS];
-            let mut scale: f32 = BINS as f32 / (bounds_max - bounds_min);
-            for i in 0..bvh.object_count {
-                let centroid = &self.objects_centroids[bvh.first_object + i];
-                let bounds = &self.objects_bounds[bvh.first_object + i];
-                let bin_ind: usize = usize::min(BINS - 1, ((centroid[axis] - bounds_min) * scale) as usize);
-                bins[bin_ind].object_count += 1;
-                bins[bin_ind].aabb.grow(bounds.aabb_min);
-                bins[bin_ind].aabb.grow(bounds.aabb_max);
-            }
-            // gather data for the (BINS count - 1) planes between the (BINS count) bins
-            let mut left_area: [f32; BINS - 1] = [0.0; BINS - 1];
-            let mut right_area: [f32; BINS - 1] = [0.0; BINS - 1];
-            let mut left_count: [usize; BINS - 1] = [0; BINS - 1];
-            let mut right_count: [usize; BINS - 1] = [0; BINS - 1];
-            let mut left_box: Aabb = Aabb::default();
-            let mut right_box: Aabb = Aabb::default();
-            let mut left_sum = 0;
-            let mut right_sum = 0;
-
-            for i in 0..BINS - 1 {
-                left_sum += bins[i].object_count;
-                left_count[i] = left_sum;
-                left_box.grow(bins[i].aabb.bmin);
-                left_box.grow(bins[i].aabb.bmax);
-                left_area[i] = left_box.area();
-
-                right_sum += bins[BINS - 1 - i].object_count;
-                right_count[BINS - 2 - i] = right_sum;
-                right_box.grow(bins[BINS - 1 - i].aabb.bmin);
-                right_box.grow(bins[BINS - 1 - i].aabb.bmax);
-                right_area[BINS - 2 - i] = right_box.area();
-            }
-            // calculate SAH cost for the (BINS count) planes
-            scale = (bounds_max - bounds_min) / BINS as f32;
-            for i in 0..BINS - 1 {
-                let plane_cost: f32 = left_count[i] as f32 * left_area[i] + right_count[i] as f32 * right_area[i];
-                if plane_cost < best_cost {
-                    best_axis = axis;
-                    best_pos = bounds_min + scale * (i + 1) as f32;
-                    best_cost = plane_cost;
-                }
-            }*/
-        }
-        let axis: i32 = best_axis as i32;
-        let split_pos: f32 = best_pos;
-        (split_pos, axis, best_cost)
+    pub fn get_bvh_by_depth(&self, depth: u32) -> Vec<BvhNode> {
+        let mut bd = BvhDepth::new(&self.bvhs, depth);
+        bd.intersect_hierarchy();
+        bd.bvhs
     }
 
+    /// Builds tree for objects, subtrees of big nodes are built in parallel.
+    /// Resulting tree is the same for any number of threads.
+    /// Spatial splits clip only bounding boxes of objects, see [`Bvh::calculate_bvh_clipped`].
     #[inline]
-    fn evaluate_sah(&self, bvh: &BvhNode, axis: usize, pos: f32) -> f32 {
-        // determine triangle counts and bounds for this split candidate
-        let mut left_box = Aabb::default();
-        let mut right_box = Aabb::default();
-        let mut left_count = 0;
-        let mut right_count = 0;
-        for i in 0..bvh.object_count
-        {
-            let centroid = &self.objects_centroids[bvh.first_object + i];
-            let bounds = &self.objects_bounds[bvh.first_object + i];
-            if centroid[axis] < pos {
-                left_count += 1;
-                left_box.grow(bounds.aabb_min);
-                left_box.grow(bounds.aabb_max);
-            } else {
-                right_count += 1;
-                right_box.grow(bounds.aabb_min);
-                right_box.grow(bounds.aabb_max);
-            }
-        }
-        let cost: f32 = left_count as f32 * left_box.area() + right_count as f32 * right_box.area();
-        if cost > 0.0 { cost } else { 1e30 }
+    pub fn calculate_bvh(&mut self, objects_bounds: Vec<Bounds>, objects_centroids: Vec<Vector3<f32>>) {
+        self.calculate_bvh_clipped(objects_bounds, objects_centroids, &BoxClipper);
     }
 
-    #[inline]
-    fn calculate_bvh_bounds(&mut self, bvh_index: usize) {
-        let bvh = &mut self.bvhs[bvh_index];
-        if self.objects_bounds.is_empty() || bvh.object_count == 0 {
+    /// Same as [`Bvh::calculate_bvh`], `clipper` splits objects for spatial splits.
+    /// With spatial split builder one object can be referenced from several leaves.
+    pub fn calculate_bvh_clipped<C>(&mut self, objects_bounds: Vec<Bounds>, objects_centroids: Vec<Vector3<f32>>, clipper: &C)
+    where C: ReferenceClipper + Sync {
+        if let BvhBuilder::Spatial { bins, max_duplication } = self.builder {
+            let references = References {
+                bounds: objects_bounds.iter().zip(&objects_centroids)
+                    .map(|(bounds, centroid)| Bounds::new(*centroid, bounds.aabb_min, bounds.aabb_max)).collect(),
+                centroids: objects_centroids,
+                indexes: (0..objects_bounds.len()).collect(),
+            };
+            let (nodes, references) = SpatialBuild::new(clipper, bins).build(references, max_duplication);
+            self.bvhs = nodes;
+            self.objects_bounds = references.bounds;
+            self.objects_centroids = references.centroids;
+            self.objects_indexes = references.indexes;
+            self.built_sah_cost = self.sah_cost();
             return;
         }
-        let objects: &[Bounds] = &self.objects_bounds[(bvh.first_object)..(bvh.first_object + bvh.object_count)];
-        let points: Vec<Vector3<f32>> = objects.iter().flat_map(|x| [x.aabb_min, x.aabb_max]).collect();
-
-        let mut min: Vector3<f32> = Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
-        let mut max: Vector3<f32> = Vector3::new(-f32::INFINITY, -f32::INFINITY, -f32::INFINITY);
-        points.iter().for_each(|x: &Vector3<f32>| {
-            min.x = min.x.min(x.x);
-            min.y = min.y.min(x.y);
-            min.z = min.z.min(x.z);
+        self.objects_indexes = (0..objects_bounds.len()).collect();
+        self.objects_bounds = objects_bounds;
+        self.objects_centroids = objects_centroids;
 
-            max.x = max.x.max(x.x);
-            max.y = max.y.max(x.y);
-            max.z = max.z.max(x.z);
-        });
+        let objects = BuildObjects {
+            bounds: &mut self.objects_bounds,
+            centroids: &mut self.objects_centroids,
+            indexes: &mut self.objects_indexes,
+        };
+        self.bvhs = self.builder.build(objects);
+        self.built_sah_cost = self.sah_cost();
+    }
 
-        bvh.aabb_min = min;
-        bvh.aabb_max = max;
+    #[inline]
+    /// Returns (split_pos, divide_axis, best_cost)
+    pub fn division_plane(&self, bvh: &BvhNode) -> (f32, i32, f32) {
+        let objects = bvh.first_object..(bvh.first_object + bvh.object_count);
+        self.builder.division_plane(&self.objects_bounds[objects.clone()], &self.objects_centroids[objects])
     }
 }
 
-/*#[derive(Default, Clone)]
-struct Bin {
-    pub aabb: Aabb,
-    pub object_count: usize
-}*/
-
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 struct Aabb {
     pub bmin: Vector3<f32>,
     pub bmax: Vector3<f32>
@@ -307,13 +198,85 @@ impl Aabb {
         let e = self.bmax - self.bmin; // box extent
         e.x * e.y + e.y * e.z + e.z * e.x
     }
+
+    #[inline]
+    fn union(self, other: Aabb) -> Aabb {
+        Aabb { bmin: self.bmin.inf(&other.bmin), bmax: self.bmax.sup(&other.bmax) }
+    }
+
+    #[inline]
+    fn intersection(self, other: Aabb) -> Option<Aabb> {
+        let aabb = Aabb { bmin: self.bmin.sup(&other.bmin), bmax: self.bmax.inf(&other.bmax) };
+        if aabb.is_empty() { None } else { Some(aabb) }
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.bmin.x > self.bmax.x || self.bmin.y > self.bmax.y || self.bmin.z > self.bmax.z
+    }
+}
+
+impl From<&BvhNode> for Aabb {
+    #[inline]
+    fn from(value: &BvhNode) -> Self {
+        Aabb { bmin: value.aabb_min, bmax: value.aabb_max }
+    }
 }
 
 #[cfg(test)]
-mod tests {
-    use nalgebra::Vector3;
-    use crate::math::ray::Ray;
-    use super::{BvhNode, Aabb};
+pub(crate) mod tests {
+    use nalgebra::{Affine3, Vector3};
+    use crate::{math::{ray::Ray, pcg}, entity::{Bounds, mesh::Mesh, geometry::Geometry, hit::HittableSet}, material::Material};
+    use super::{Bvh, BvhBuilder, BvhNode, Aabb, ReferenceClipper, BoxClipper};
+
+    /// Random small triangles scattered in a box and then transformed
+    pub(crate) fn random_mesh(count: usize, seed: u32, transform: &Affine3<f32>) -> Mesh {
+        let mut seed = seed;
+        let mut positions = vec![];
+        for _ in 0..count {
+            let center = pcg::random_vector3(&mut seed) * 20.0;
+            for _ in 0..3 {
+                let position = center + pcg::random_vector3(&mut seed) - Vector3::repeat(0.5);
+                positions.push(transform.transform_point(&position.into()).coords);
+            }
+        }
+        let indices = (0..count as u32).map(|i| [i * 3, i * 3 + 1, i * 3 + 2]).collect();
+        Mesh::new(positions, vec![], vec![], indices, Material::default().into())
+    }
+
+    fn random_geometry(count: usize, seed: u32) -> Geometry {
+        Geometry::new(vec![random_mesh(count, seed, &Affine3::identity())])
+    }
+
+    /// Tree for objects with `bounds`, `clipper` splits them for spatial splits
+    pub(crate) fn build_clipped<C>(bounds: Vec<Bounds>, clipper: &C, builder: BvhBuilder) -> Bvh
+    where C: ReferenceClipper + Sync {
+        let centroids = bounds.iter().map(|x| x.centroid).collect();
+        let mut bvh = Bvh::new(builder);
+        bvh.calculate_bvh_clipped(bounds, centroids, clipper);
+        bvh
+    }
+
+    fn build(geometry: &Geometry, builder: BvhBuilder) -> Bvh {
+        let bounds = geometry.triangles.iter().map(|x| geometry.triangle_bounds(x)).collect();
+        build_clipped(bounds, &BoxClipper, builder)
+    }
+
+    /// Checks that bvh finds the same closest hits as testing every object
+    fn assert_matches_brute_force(bvh: &Bvh, geometry: &Geometry) {
+        let mut seed = 7;
+        for _ in 0..500 {
+            let origin = pcg::random_vector3(&mut seed) * 30.0 - Vector3::repeat(5.0);
+            let target = pcg::random_vector3(&mut seed) * 20.0;
+            let ray = Ray::new(origin, (target - origin).normalize());
+            let expected = (0..geometry.triangles.len())
+                .filter_map(|i| geometry.intersect_object(i, &ray))
+                .map(|x| x.t)
+                .min_by(|a, b| a.partial_cmp(b).unwrap());
+            let hit = bvh.intersect(&ray, geometry).map(|x| x.t);
+            assert_eq!(hit, expected);
+        }
+    }
 
     #[test]
     fn aabb_grow() {
@@ -343,12 +306,340 @@ mod tests {
     
     #[test]
     fn division_plane() {
-        let mut bvh = BvhNode::new(0, 0);
-        bvh.aabb_min = Vector3::new(-1.0, -1.0, -2.0);
-        bvh.aabb_max = Vector3::new(1.0, 1.0, 2.0);
+        // Two clusters of objects separated along Z axis
+        let objects_bounds: Vec<Bounds> = [-2.0, -1.5, 1.5, 2.0].iter().map(|z| {
+            let centroid = Vector3::new(0.0, 0.0, *z);
+            Bounds::new(centroid, centroid - Vector3::repeat(0.25), centroid + Vector3::repeat(0.25))
+        }).collect();
+        let bvh = Bvh {
+            objects_centroids: objects_bounds.iter().map(|x| x.centroid).collect(),
+            objects_bounds,
+            ..Default::default()
+        };
+        let node = BvhNode::new(0, 4);
+
+        let (split_pos, division_plane, _) = bvh.division_plane(&node);
+        assert_eq!(division_plane, 2);
+        assert!(split_pos > -1.5 && split_pos <= 1.5);
+    }
+
+    #[test]
+    fn binned_sah_quality() {
+        let geometry = random_geometry(2000, 42);
+        let planes = build(&geometry, BvhBuilder::Planes { candidates: 16 });
+        let binned = build(&geometry, BvhBuilder::Binned { bins: 16 });
+        let binned_fine = build(&geometry, BvhBuilder::Binned { bins: 64 });
+        // Binned build evaluates the same planes, so quality must not be worse
+        assert!(binned.sah_cost() <= planes.sah_cost() * 1.01,
+            "binned: {}, planes: {}", binned.sah_cost(), planes.sah_cost());
+        assert!(binned_fine.sah_cost() <= planes.sah_cost() * 1.01,
+            "binned fine: {}, planes: {}", binned_fine.sah_cost(), planes.sah_cost());
+        assert_matches_brute_force(&binned, &geometry);
+        assert_matches_brute_force(&binned_fine, &geometry);
+    }
 
-        //let (split_pos, division_plane) = bvh.division_plane();
-        //assert_eq!(division_plane, 2);
-        //assert_eq!(split_pos, 0.0);
+    #[test]
+    fn too_few_bins() {
+        let geometry = random_geometry(500, 43);
+        let two = build(&geometry, BvhBuilder::Binned { bins: 2 });
+        for bins in [0, 1] {
+            let bvh = build(&geometry, BvhBuilder::Binned { bins });
+            assert_eq!(bvh.bvhs, two.bvhs);
+            assert_matches_brute_force(&bvh, &geometry);
+        }
+        let two = build(&geometry, BvhBuilder::Spatial { bins: 2, max_duplication: 0.5 });
+        for bins in [0, 1] {
+            let bvh = build(&geometry, BvhBuilder::Spatial { bins, max_duplication: 0.5 });
+            assert_eq!(bvh.bvhs, two.bvhs);
+            assert_matches_brute_force(&bvh, &geometry);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parallel_build_is_deterministic() {
+        let geometry = random_geometry(20000, 3);
+        let build_with_threads = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| build(&geometry, BvhBuilder::default()))
+        };
+        let single = build_with_threads(1);
+        let multi = build_with_threads(4);
+        assert_eq!(single.bvhs, multi.bvhs);
+        assert_eq!(single.objects_indexes, multi.objects_indexes);
+        assert_matches_brute_force(&multi, &geometry);
+    }
+
+    #[test]
+    fn empty_bvh() {
+        let bvh = build(&Geometry::default(), BvhBuilder::default());
+        let ray = Ray::new(Vector3::zeros(), Vector3::new(0.0, 0.0, 1.0));
+        assert!(bvh.intersect(&ray, &Geometry::default()).is_none());
+        assert!(bvh.get_bvh_by_depth(1).is_empty());
+    }
+
+    #[test]
+    fn spatial_split_long_triangles() {
+        // Long thin diagonal triangles overlap each other's bounds heavily
+        let count = 500;
+        let mut seed = 11;
+        let mut positions = vec![];
+        for _ in 0..count {
+            let start = pcg::random_vector3(&mut seed) * 20.0;
+            let end = Vector3::new(20.0, 20.0, 20.0) - start;
+            positions.extend([start, end, start + Vector3::new(0.05, 0.0, 0.0)]);
+        }
+        let indices = (0..count as u32).map(|i| [i * 3, i * 3 + 1, i * 3 + 2]).collect();
+        let geometry = Geometry::new(vec![Mesh::new(positions, vec![], vec![], indices, Material::default().into())]);
+
+        let binned = build(&geometry, BvhBuilder::Binned { bins: 16 });
+        let bounds: Vec<Bounds> = geometry.triangles.iter().map(|x| geometry.triangle_bounds(x)).collect();
+        let centroids = bounds.iter().map(|x| x.centroid).collect();
+        let mut spatial = Bvh::new(BvhBuilder::Spatial { bins: 16, max_duplication: 1.0 });
+        spatial.calculate_bvh_clipped(bounds, centroids, &geometry);
+
+        assert!(spatial.sah_cost() < binned.sah_cost(),
+            "spatial: {}, binned: {}", spatial.sah_cost(), binned.sah_cost());
+        assert!(spatial.reference_count() > count);
+        assert!(spatial.reference_count() <= count * 2);
+        assert_matches_brute_force(&spatial, &geometry);
+    }
+
+    #[test]
+    fn spatial_split_without_budget() {
+        let geometry = random_geometry(1000, 5);
+        let spatial = build(&geometry, BvhBuilder::Spatial { bins: 16, max_duplication: 0.0 });
+        assert_eq!(spatial.reference_count(), 1000);
+        assert_matches_brute_force(&spatial, &geometry);
+    }
+
+    #[test]
+    fn lbvh_build() {
+        let geometry = random_geometry(5000, 9);
+        let binned = build(&geometry, BvhBuilder::default());
+        let lbvh = build(&geometry, BvhBuilder::Lbvh);
+        // Lower quality is expected, but it must stay in the same range
+        assert!(lbvh.sah_cost() <= binned.sah_cost() * 1.25,
+            "lbvh: {}, binned: {}", lbvh.sah_cost(), binned.sah_cost());
+        assert_eq!(lbvh.reference_count(), 5000);
+        assert_matches_brute_force(&lbvh, &geometry);
+    }
+
+    #[test]
+    fn refit_moved_objects() {
+        let mut geometry = random_geometry(2000, 13);
+        let mut bvh = build(&geometry, BvhBuilder::default());
+        let nodes = bvh.bvhs.clone();
+        let objects_bounds = |geometry: &Geometry| -> Vec<Bounds> {
+            geometry.triangles.iter().map(|x| geometry.triangle_bounds(x)).collect()
+        };
+        // Nothing moved
+        let degradation = bvh.refit(&objects_bounds(&geometry));
+        assert_eq!(bvh.bvhs, nodes);
+        assert!((degradation - 1.0).abs() < 1e-5);
+
+        // Scatter every tenth triangle across the scene
+        let mut seed = 17;
+        for triangle in geometry.meshes[0].positions_mut().chunks_mut(3).step_by(10) {
+            let offset = pcg::random_vector3(&mut seed) * 20.0 - Vector3::repeat(10.0);
+            triangle.iter_mut().for_each(|x| *x += offset);
+        }
+        let degradation = bvh.refit(&objects_bounds(&geometry));
+        assert!(degradation > 1.0, "degradation: {}", degradation);
+        assert_eq!(bvh.bvh_count(), nodes.len());
+        assert_matches_brute_force(&bvh, &geometry);
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let geometry = random_geometry(1000, 21);
+        let bvh = build(&geometry, BvhBuilder::default());
+        let objects_bounds: Vec<Bounds> = geometry.triangles.iter().map(|x| geometry.triangle_bounds(x)).collect();
+        let path = std::env::temp_dir().join(format!("rtracer_cache_round_trip_{}.bvh", std::process::id()));
+        bvh.save(&path, 42).unwrap();
+
+        let mut loaded = Bvh::default();
+        assert!(!loaded.load(&path, 43, &objects_bounds).unwrap());
+        assert_eq!(loaded.bvh_count(), 0);
+        assert!(loaded.load(&path, 42, &objects_bounds).unwrap());
+        assert_eq!(loaded.bvhs, bvh.bvhs);
+        assert_eq!(loaded.objects_indexes, bvh.objects_indexes);
+        assert_matches_brute_force(&loaded, &geometry);
+
+        // Truncated file
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(loaded.load(&path, 42, &objects_bounds).is_err());
+        // Node count overflowing file size
+        let mut corrupted = data.clone();
+        corrupted[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &corrupted).unwrap();
+        assert!(loaded.load(&path, 42, &objects_bounds).is_err());
+        // Root pointing to itself
+        let mut corrupted = data.clone();
+        corrupted[56..60].copy_from_slice(&0u32.to_le_bytes());
+        std::fs::write(&path, &corrupted).unwrap();
+        assert!(loaded.load(&path, 42, &objects_bounds).is_err());
+        assert_eq!(loaded.bvhs, bvh.bvhs);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wide_bvh_intersection() {
+        let geometry = random_geometry(3000, 23);
+        let bvh = build(&geometry, BvhBuilder::default());
+        let bvh4 = super::Bvh4::new(&bvh);
+        let bvh8 = super::Bvh8::new(&bvh);
+        assert!(bvh8.node_count() < bvh4.node_count());
+        assert!(bvh4.node_count() < bvh.bvh_count());
+        let mut seed = 29;
+        for _ in 0..500 {
+            let origin = pcg::random_vector3(&mut seed) * 30.0 - Vector3::repeat(5.0);
+            let target = pcg::random_vector3(&mut seed) * 20.0;
+            let ray = Ray::new(origin, (target - origin).normalize());
+            let expected = bvh.intersect(&ray, &geometry).map(|x| x.t);
+            assert_eq!(bvh4.intersect(&ray, &geometry).map(|x| x.t), expected);
+            assert_eq!(bvh8.intersect(&ray, &geometry).map(|x| x.t), expected);
+        }
+    }
+
+    /// Triangles along Z axis, tree is a chain: every inner node has a leaf and the rest of the chain
+    fn chain_bvh(count: usize) -> (Geometry, Bvh) {
+        let positions = (0..count).flat_map(|i| {
+            let z = i as f32;
+            [Vector3::new(-1.0, -1.0, z), Vector3::new(1.0, -1.0, z), Vector3::new(0.0, 1.0, z)]
+        }).collect();
+        let indices = (0..count as u32).map(|i| [i * 3, i * 3 + 1, i * 3 + 2]).collect();
+        let geometry = Geometry::new(vec![Mesh::new(positions, vec![], vec![], indices, Material::default().into())]);
+
+        let mut bvhs = vec![BvhNode::new(0, 0); count * 2 - 1];
+        for i in (0..count).rev() {
+            let leaf = if i + 1 == count { i * 2 } else { i * 2 + 1 };
+            bvhs[leaf] = BvhNode::new(i, 1);
+            bvhs[leaf].aabb_min = Vector3::new(-1.0, -1.0, i as f32);
+            bvhs[leaf].aabb_max = Vector3::new(1.0, 1.0, i as f32);
+            if i + 1 < count {
+                let inner = i * 2;
+                bvhs[inner] = BvhNode::new(inner + 1, 0);
+                bvhs[inner].aabb_min = bvhs[leaf].aabb_min;
+                bvhs[inner].aabb_max = bvhs[inner + 2].aabb_max;
+            }
+        }
+        let bvh = Bvh { bvhs, objects_indexes: (0..count).collect(), ..Default::default() };
+        (geometry, bvh)
+    }
+
+    #[test]
+    fn traversal_deeper_than_stack() {
+        let count = 100;
+        let (geometry, bvh) = chain_bvh(count);
+        // Inner nodes are nearer than leaves, so every far leaf goes to the stack
+        let ray = Ray::new(Vector3::new(0.0, 0.0, 200.0), Vector3::new(0.0, 0.0, -1.0));
+        let hit = bvh.intersect(&ray, &geometry).unwrap();
+        assert_eq!(hit.t, 200.0 - (count - 1) as f32);
+        assert_eq!(bvh.intersect_recursive(&ray, &geometry).unwrap().t, hit.t);
+    }
+
+    #[test]
+    fn wide_traversal_deeper_than_stack() {
+        // Every collapsed node pushes three far children, so the chain overflows the fixed stack
+        let count = 2000;
+        let (geometry, bvh) = chain_bvh(count);
+        let ray = Ray::new(Vector3::new(0.0, 0.0, 3000.0), Vector3::new(0.0, 0.0, -1.0));
+        let expected = 3000.0 - (count - 1) as f32;
+        assert_eq!(super::Bvh4::new(&bvh).intersect(&ray, &geometry).unwrap().t, expected);
+        assert_eq!(super::Bvh8::new(&bvh).intersect(&ray, &geometry).unwrap().t, expected);
+    }
+
+    #[test]
+    fn occlusion_query() {
+        let geometry = random_geometry(2000, 31);
+        let bvh = build(&geometry, BvhBuilder::default());
+        let bvh4 = super::Bvh4::new(&bvh);
+        let mut seed = 37;
+        for _ in 0..500 {
+            let origin = pcg::random_vector3(&mut seed) * 30.0 - Vector3::repeat(5.0);
+            let target = pcg::random_vector3(&mut seed) * 20.0;
+            let ray = Ray::new(origin, (target - origin).normalize());
+            let t_max = (target - origin).norm();
+            let expected = bvh.intersect(&ray, &geometry).is_some_and(|x| x.t < t_max);
+            assert_eq!(bvh.occluded(&ray, t_max, &geometry), expected);
+            assert_eq!(bvh4.occluded(&ray, t_max, &geometry), expected);
+        }
+    }
+
+    #[test]
+    fn ray_interval_culling() {
+        let geometry = random_geometry(2000, 41);
+        let bvh = build(&geometry, BvhBuilder::default());
+        let bvh4 = super::Bvh4::new(&bvh);
+        let mut seed = 43;
+        for _ in 0..500 {
+            let origin = pcg::random_vector3(&mut seed) * 30.0 - Vector3::repeat(5.0);
+            let target = pcg::random_vector3(&mut seed) * 20.0;
+            let direction = (target - origin).normalize();
+            let t_min = pcg::random_f32(&mut seed) * 20.0;
+            let t_max = t_min + pcg::random_f32(&mut seed) * 20.0;
+            let ray = Ray::with_interval(origin, direction, t_min, t_max);
+            let expected = (0..geometry.triangles.len())
+                .filter_map(|i| geometry.intersect_object(i, &Ray::new(origin, direction)))
+                .map(|x| x.t)
+                .filter(|t| *t > t_min && *t < t_max)
+                .min_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(bvh.intersect(&ray, &geometry).map(|x| x.t), expected);
+            assert_eq!(bvh4.intersect(&ray, &geometry).map(|x| x.t), expected);
+            assert_eq!(bvh.occluded(&ray, f32::INFINITY, &geometry), expected.is_some());
+        }
+    }
+
+    /// Camera-like packet of a tile of rays from `origin` to a square at `target`
+    fn tile_rays(origin: Vector3<f32>, target: Vector3<f32>, size: f32) -> Vec<Ray> {
+        let tile = super::PACKET_TILE_SIZE;
+        (0..tile * tile).map(|i| {
+            let offset = Vector3::new((i % tile) as f32, (i / tile) as f32, 0.0) / tile as f32 - Vector3::new(0.5, 0.5, 0.0);
+            Ray::new(origin, (target + offset * size - origin).normalize())
+        }).collect()
+    }
+
+    #[test]
+    fn packet_intersection() {
+        let geometry = random_geometry(2000, 47);
+        let bvh = build(&geometry, BvhBuilder::default());
+        let mut seed = 53;
+        for _ in 0..100 {
+            let origin = pcg::random_vector3(&mut seed) * 40.0 - Vector3::repeat(10.0);
+            let target = pcg::random_vector3(&mut seed) * 20.0;
+            let size = pcg::random_f32(&mut seed) * 10.0;
+            let mut rays = tile_rays(origin, target, size);
+            let packet = super::RayPacket::new(rays.clone());
+            assert!(packet.frustum().is_some());
+            let hits = bvh.intersect_packet(&packet, &geometry);
+            for (ray, hit) in rays.iter().zip(&hits) {
+                let expected = bvh.intersect(ray, &geometry);
+                assert_eq!(hit.as_ref().map(|x| (x.t, x.object as *const _)), expected.map(|x| (x.t, x.object as *const _)));
+            }
+            // Without common origin packet is traced without frustum
+            rays.iter_mut().for_each(|x| x.origin += pcg::random_vector3(&mut seed));
+            let packet = super::RayPacket::new(rays.clone());
+            assert!(packet.frustum().is_none());
+            let hits = bvh.intersect_packet(&packet, &geometry);
+            for (ray, hit) in rays.iter().zip(&hits) {
+                assert_eq!(hit.as_ref().map(|x| x.t), bvh.intersect(ray, &geometry).map(|x| x.t));
+            }
+        }
+    }
+
+    #[test]
+    fn frustum_culling() {
+        let rays = tile_rays(Vector3::zeros(), Vector3::new(0.0, 0.0, -10.0), 2.0);
+        let frustum = super::Frustum::new(&rays).unwrap();
+        let node = |min: Vector3<f32>, max: Vector3<f32>| BvhNode { aabb_min: min, aabb_max: max, ..BvhNode::new(0, 1) };
+        // Inside, containing the origin and crossing a side plane
+        assert!(!frustum.culls(&node(Vector3::new(-0.1, -0.1, -20.0), Vector3::new(0.1, 0.1, -19.0))));
+        assert!(!frustum.culls(&node(Vector3::repeat(-1.0), Vector3::repeat(1.0))));
+        assert!(!frustum.culls(&node(Vector3::new(0.5, -0.1, -10.1), Vector3::new(5.0, 0.1, -9.9))));
+        // Behind, to the side and above
+        assert!(frustum.culls(&node(Vector3::new(-1.0, -1.0, 1.0), Vector3::new(1.0, 1.0, 2.0))));
+        assert!(frustum.culls(&node(Vector3::new(1.5, -0.1, -10.1), Vector3::new(5.0, 0.1, -9.9))));
+        assert!(frustum.culls(&node(Vector3::new(-0.1, 1.5, -10.1), Vector3::new(0.1, 5.0, -9.9))));
+    }
+}
@@ -1,6 +1,6 @@
 use nalgebra::Vector3;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Bounds {
     pub centroid: Vector3<f32>,
     pub aabb_min: Vector3<f32>,
@@ -16,28 +16,17 @@ impl Bounds {
 
 #[cfg(test)]
 mod tests {
-    use nalgebra::{Vector3, Vector2};
-    use crate::{entity::triangle::Triangle, material::Material};
+    use nalgebra::Vector3;
 
     use super::Bounds;
 
     #[test]
     fn triangle_bounds_from_points() {
-        let triangle = Bounds::from(
-            Triangle::new(
+        let triangle = Bounds::from([
             Vector3::new(-0.5, 0.0, 0.0),
             Vector3::new(1.2, 0.0, -0.25),
             Vector3::new(0.0, 1.0, 0.0),
-            Vector3::zeros(),
-            Vector3::zeros(),
-            Vector3::zeros(),
-            Vector2::zeros(),
-            Vector2::zeros(),
-            Vector2::zeros(),
-            Material::default().into(),
-            0
-            )
-        );
+        ]);
         assert_eq!(triangle.aabb_min, Vector3::new(-0.5, 0.0, -0.25));
         assert_eq!(triangle.aabb_max, Vector3::new(1.2, 1.0, 0.0));
     }
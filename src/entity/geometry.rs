@@ -0,0 +1,65 @@
+use crate::{math::ray::Ray, bvh::ReferenceClipper};
+use super::{mesh::{Mesh, MeshTriangle}, triangle::{Triangle, TriangleIntersection}, hit::{HittableSet, Intersection}, Bounds};
+
+/// All meshes of the scene with flat list of their triangles.
+/// Acceleration structures index into `triangles`.
+#[derive(Debug, Default)]
+pub struct Geometry {
+    pub meshes: Vec<Mesh>,
+    pub triangles: Vec<Triangle>,
+    /// Can be changed at any time, acceleration structures don't depend on it
+    pub triangle_intersection: TriangleIntersection,
+}
+
+impl Geometry {
+    #[inline]
+    pub fn new(meshes: Vec<Mesh>) -> Self {
+        let mut geometry = Geometry::default();
+        for mesh in meshes {
+            geometry.add_mesh(mesh);
+        }
+        geometry
+    }
+
+    /// Returns index of the added mesh
+    #[inline]
+    pub fn add_mesh(&mut self, mesh: Mesh) -> usize {
+        let mesh_index = self.meshes.len();
+        self.triangles.extend((0..mesh.triangle_count()).map(|i| Triangle::new(mesh_index as u32, i as u32)));
+        self.meshes.push(mesh);
+        mesh_index
+    }
+
+    #[inline(always)]
+    pub fn triangle(&self, triangle: &Triangle) -> MeshTriangle<'_> {
+        self.meshes[triangle.mesh as usize].triangle(triangle.primitive as usize)
+    }
+
+    #[inline]
+    pub fn triangle_bounds(&self, triangle: &Triangle) -> Bounds {
+        self.meshes[triangle.mesh as usize].bounds(triangle.primitive as usize)
+    }
+
+    #[inline]
+    pub fn vertex_count(&self) -> usize {
+        self.meshes.iter().map(|x| x.vertex_count()).sum()
+    }
+}
+
+impl HittableSet<Triangle> for Geometry {
+    #[inline(always)]
+    fn intersect_object(&self, index: usize, ray: &Ray) -> Option<Intersection<'_, Triangle>> {
+        let triangle = &self.triangles[index];
+        let vertices = self.meshes[triangle.mesh as usize].vertices(triangle.primitive as usize);
+        self.triangle_intersection.intersect(&vertices, ray)
+            .map(|(t, bar_coords)| Intersection::with_bar_coords(t, bar_coords, triangle))
+    }
+}
+
+impl ReferenceClipper for Geometry {
+    #[inline]
+    fn split(&self, object: usize, bounds: &Bounds, axis: usize, pos: f32) -> (Option<Bounds>, Option<Bounds>) {
+        let triangle = &self.triangles[object];
+        self.meshes[triangle.mesh as usize].split_bounds(triangle.primitive as usize, bounds, axis, pos)
+    }
+}
@@ -1,4 +1,4 @@
-use nalgebra::Vector3;
+use nalgebra::{Vector3, Vector2};
 use crate::math::ray::Ray;
 
 //use crate::math::ray::Ray;
@@ -6,33 +6,61 @@ use crate::math::ray::Ray;
 pub struct Hit<'a, T> {
     pub t: f32,
     pub point: Vector3<f32>,
+    /// Barycentric coordinates of the hit on triangle, zero for other objects
+    pub bar_coords: Vector2<f32>,
     pub object: &'a T,
 }
 
 impl<'a, T> Hit<'a, T> {
     #[inline]
-    pub fn new(t: f32, point: Vector3<f32>, object: &'a T) -> Self {
-        Hit { t, point, object }
+    pub fn new(t: f32, point: Vector3<f32>, bar_coords: Vector2<f32>, object: &'a T) -> Self {
+        Hit { t, point, bar_coords, object }
     }
 }
 
 #[derive(Clone)]
 pub struct Intersection<'a, T> {
     pub t: f32,
+    /// Barycentric coordinates of the hit on triangle (u/v), zero for other objects
+    pub bar_coords: Vector2<f32>,
     pub object: &'a T,
 }
 
 impl<'a, T> Intersection<'a, T> {
     #[inline]
     pub fn new(t: f32, object: &'a T) -> Self {
-        Intersection { t, object }
+        Self::with_bar_coords(t, Vector2::zeros(), object)
+    }
+
+    #[inline]
+    pub fn with_bar_coords(t: f32, bar_coords: Vector2<f32>, object: &'a T) -> Self {
+        Intersection { t, bar_coords, object }
+    }
+
+    /// Hit of `ray` which found this intersection
+    #[inline]
+    pub fn into_hit(self, ray: &Ray) -> Hit<'a, T> {
+        Hit::new(self.t, ray.origin + ray.get_direction() * self.t, self.bar_coords, self.object)
     }
 }
 
 pub trait Hittable<T>{
-    fn intersect(&self, ray: &Ray) -> Option<Intersection<T>>;
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_, T>>;
     // distance to plane from point = n * (a - p)
     // Where n - plane normal, p - plane pos, a - point pos
     // Do this for all three points to get info about the triangle for an example
     // fn plane_cull(&self, plane: &Ray) -> bool;
+}
+
+/// Collection of objects referenced by index from acceleration structures
+pub trait HittableSet<T> {
+    fn intersect_object(&self, index: usize, ray: &Ray) -> Option<Intersection<'_, T>>;
+}
+
+impl<T> HittableSet<T> for [T]
+where T: Hittable<T> {
+    #[inline(always)]
+    fn intersect_object(&self, index: usize, ray: &Ray) -> Option<Intersection<'_, T>> {
+        self[index].intersect(ray)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,285 @@
+use std::sync::Arc;
+use nalgebra::{Vector3, Vector2};
+use crate::{math::{ray::Ray, pcg}, material::Material};
+use super::{triangle::{intersect_triangle, split_triangle_bounds}, Bounds};
+
+/// Triangle mesh with shared vertices.
+/// Vertex attributes are stored once and referenced by triangles through the index buffer.
+#[derive(Debug)]
+pub struct Mesh {
+    positions: Vec<Vector3<f32>>,
+    /// Empty if mesh has no vertex normals, flat shading is used then
+    normals: Vec<Vector3<f32>>,
+    /// Empty if mesh has no texture coordinates
+    uvs: Vec<Vector2<f32>>,
+    indices: Vec<[u32; 3]>,
+    pub material: Arc<Material>,
+}
+
+impl Mesh {
+    #[inline]
+    pub fn new(positions: Vec<Vector3<f32>>, normals: Vec<Vector3<f32>>, uvs: Vec<Vector2<f32>>,
+        indices: Vec<[u32; 3]>, material: Arc<Material>) -> Self {
+        assert!(normals.is_empty() || normals.len() == positions.len(), "Mesh normals count must match positions count");
+        assert!(uvs.is_empty() || uvs.len() == positions.len(), "Mesh uvs count must match positions count");
+        Mesh { positions, normals, uvs, indices, material }
+    }
+
+    #[inline]
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    #[inline]
+    pub fn vertex_count(&self) -> usize {
+        self.positions.len()
+    }
+
+    #[inline]
+    pub fn positions(&self) -> &[Vector3<f32>] {
+        &self.positions
+    }
+
+    /// Bvh built for the mesh must be refitted or rebuilt after positions change
+    #[inline]
+    pub fn positions_mut(&mut self) -> &mut [Vector3<f32>] {
+        &mut self.positions
+    }
+
+    #[inline]
+    pub fn indices(&self) -> &[[u32; 3]] {
+        &self.indices
+    }
+
+    #[inline(always)]
+    pub fn vertices(&self, primitive: usize) -> [Vector3<f32>; 3] {
+        let [i1, i2, i3] = self.indices[primitive];
+        [self.positions[i1 as usize], self.positions[i2 as usize], self.positions[i3 as usize]]
+    }
+
+    /// Returns distance and barycentric coordinates of the hit
+    #[inline(always)]
+    pub fn intersect(&self, primitive: usize, ray: &Ray) -> Option<(f32, Vector2<f32>)> {
+        intersect_triangle(&self.vertices(primitive), ray)
+    }
+
+    #[inline]
+    pub fn bounds(&self, primitive: usize) -> Bounds {
+        self.vertices(primitive).into()
+    }
+
+    /// Bounds of triangle parts on both sides of plane, limited by `bounds`
+    #[inline]
+    pub fn split_bounds(&self, primitive: usize, bounds: &Bounds, axis: usize, pos: f32) -> (Option<Bounds>, Option<Bounds>) {
+        split_triangle_bounds(&self.vertices(primitive), bounds, axis, pos)
+    }
+
+    #[inline(always)]
+    pub fn triangle(&self, primitive: usize) -> MeshTriangle<'_> {
+        MeshTriangle { mesh: self, primitive }
+    }
+}
+
+/// Triangle of the mesh, fetches its attributes from mesh buffers on demand
+#[derive(Clone, Copy)]
+pub struct MeshTriangle<'a> {
+    pub mesh: &'a Mesh,
+    pub primitive: usize,
+}
+
+impl MeshTriangle<'_> {
+    #[inline(always)]
+    pub fn material(&self) -> &Arc<Material> {
+        &self.mesh.material
+    }
+
+    #[inline(always)]
+    pub fn vertices(&self) -> [Vector3<f32>; 3] {
+        self.mesh.vertices(self.primitive)
+    }
+
+    #[inline]
+    /// Barycentric coordinates
+    pub fn bar_coords(&self, hit_point: &Vector3<f32>) -> Vector2<f32> {
+        let [vertex1, vertex2, vertex3] = self.vertices();
+        let v0v1: Vector3<f32> = vertex2 - vertex1;
+        let v0v2: Vector3<f32> = vertex3 - vertex1;
+        let n: Vector3<f32> = v0v1.cross(&v0v2);
+        let denom = n.dot(&n);
+
+        let mut c: Vector3<f32>;
+
+        let edge1: Vector3<f32> = vertex3 - vertex2;
+        let vp1: Vector3<f32> = hit_point - vertex2;
+        c = edge1.cross(&vp1);
+        let mut u = n.dot(&c);
+
+        let edge2: Vector3<f32> = vertex1 - vertex3;
+        let vp2: Vector3<f32> = hit_point - vertex3;
+        c = edge2.cross(&vp2);
+        let mut v = n.dot(&c);
+
+        u /= denom;
+        v /= denom;
+
+        Vector2::new(u, v)
+    }
+
+    #[inline]
+    pub fn vertex_color(&self, bar_coords: &Vector2<f32>) -> Vector3<f32> {
+        let c1: Vector3<f32> = Vector3::new(1.0, 0.0, 0.0);
+        let c2: Vector3<f32> = Vector3::new(0.0, 1.0, 0.0);
+        let c3: Vector3<f32> = Vector3::new(0.0, 0.0, 1.0);
+
+        bar_coords.x * c1 + bar_coords.y * c2 + (1.0 - bar_coords.x - bar_coords.y) * c3
+    }
+
+    /// Texture coordinates of vertices, `None` if mesh has no uvs
+    #[inline]
+    pub fn vertex_uvs(&self) -> Option<[Vector2<f32>; 3]> {
+        if self.mesh.uvs.is_empty() {
+            return None;
+        }
+        Some(self.mesh.indices[self.primitive].map(|i| self.mesh.uvs[i as usize]))
+    }
+
+    #[inline]
+    pub fn uv_coords(&self, bar_coords: &Vector2<f32>) -> Vector2<f32> {
+        if self.mesh.uvs.is_empty() {
+            return Vector2::zeros();
+        }
+        let [i1, i2, i3] = self.mesh.indices[self.primitive];
+        let uvs = &self.mesh.uvs;
+        bar_coords.x * uvs[i1 as usize] + bar_coords.y * uvs[i2 as usize] + (1.0 - bar_coords.x - bar_coords.y) * uvs[i3 as usize]
+    }
+
+    #[inline]
+    pub fn normal(&self, bar_coords: &Vector2<f32>, ray_direction: &Vector3<f32>) -> Vector3<f32> {
+        let intrerp_normal: Vector3<f32> = if self.mesh.normals.is_empty() {
+            self.plane_normal()
+        } else {
+            let [n1, n2, n3] = self.mesh.indices[self.primitive].map(|i| self.mesh.normals[i as usize]);
+            // Vertices without normal in obj are stored as zero normals, then the whole triangle is flat
+            if [n1, n2, n3].iter().any(|x| x.norm_squared() <= f32::EPSILON) {
+                self.plane_normal()
+            } else {
+                let n = bar_coords.x * n1 + bar_coords.y * n2 + (1.0 - bar_coords.x - bar_coords.y) * n3;
+                if n.norm_squared() > f32::EPSILON { n } else { self.plane_normal() }
+            }
+        };
+        if intrerp_normal.dot(ray_direction) > 0.0 {
+            -intrerp_normal
+        } else {
+            intrerp_normal
+        }
+    }
+
+    #[inline]
+    pub fn plane_normal(&self) -> Vector3<f32> {
+        // Calculate the normal vector of the triangle (cross product of two edges)
+        let [vertex1, vertex2, vertex3] = self.vertices();
+        let v1: Vector3<f32> = vertex2 - vertex1;
+        let v2: Vector3<f32> = vertex3 - vertex1;
+        v1.cross(&v2).normalize()
+    }
+
+    #[inline]
+    pub fn random_point(&self, seed: &mut u32) -> Vector3<f32> {
+        self.point(&Self::random_bar_coords(seed))
+    }
+
+    /// Barycentric coordinates of point uniformly distributed over triangle area
+    #[inline]
+    pub fn random_bar_coords(seed: &mut u32) -> Vector2<f32> {
+        // Shape Distributions
+        // ROBERT OSADA, THOMAS FUNKHOUSER, BERNARD CHAZELLE, and DAVID DOBKIN
+        // Princeton University
+        // P = (1 - sqrt(r1))*A + sqrt(r1)*(1 - r2)*B + sqrt(r1)*r2*C
+        // Where A, B, C is vertices and r1, r2 is uniform random values in range 0-1
+        let r1sqrt = pcg::random_f32(seed).sqrt();
+        let r2 = pcg::random_f32(seed);
+        Vector2::new(1.0 - r1sqrt, r1sqrt * (1.0 - r2))
+    }
+
+    /// Point at barycentric coordinates
+    #[inline]
+    pub fn point(&self, bar_coords: &Vector2<f32>) -> Vector3<f32> {
+        let [vertex1, vertex2, vertex3] = self.vertices();
+        bar_coords.x * vertex1 + bar_coords.y * vertex2 + (1.0 - bar_coords.x - bar_coords.y) * vertex3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector3, Vector2};
+    use crate::{material::Material, math::ray::Ray};
+    use super::Mesh;
+
+    fn quad() -> Mesh {
+        Mesh::new(
+            vec![
+                Vector3::new(-1.0, -1.0, 0.0),
+                Vector3::new(1.0, -1.0, 0.0),
+                Vector3::new(1.0, 1.0, 0.0),
+                Vector3::new(-1.0, 1.0, 0.0),
+            ],
+            vec![],
+            vec![
+                Vector2::new(0.0, 0.0),
+                Vector2::new(1.0, 0.0),
+                Vector2::new(1.0, 1.0),
+                Vector2::new(0.0, 1.0),
+            ],
+            vec![[0, 1, 2], [0, 2, 3]],
+            Material::default().into()
+        )
+    }
+
+    #[test]
+    fn shared_vertices_intersection() {
+        let mesh = quad();
+        assert_eq!(mesh.vertex_count(), 4);
+        assert_eq!(mesh.triangle_count(), 2);
+
+        let ray = Ray::new(Vector3::new(0.5, -0.5, -2.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!((mesh.intersect(0, &ray).unwrap().0 - 2.0).abs() < 1e-5);
+        assert_eq!(mesh.intersect(1, &ray), None);
+    }
+
+    #[test]
+    fn lazy_attributes() {
+        let mesh = quad();
+        let triangle = mesh.triangle(1);
+        let point = Vector3::new(-1.0, 1.0, 0.0);
+        let bar_coords = triangle.bar_coords(&point);
+        let uv = triangle.uv_coords(&bar_coords);
+        assert!((uv - Vector2::new(0.0, 1.0)).norm() < 1e-5);
+        // No vertex normals, flat normal facing against the ray
+        let normal = triangle.normal(&bar_coords, &Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(normal, Vector3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn partially_missing_normals() {
+        let quad = quad();
+        let tilted = Vector3::new(0.0, 0.6, 0.8);
+        // The second vertex has no normal
+        let normals = vec![tilted, Vector3::zeros(), tilted, tilted];
+        let mesh = Mesh::new(quad.positions, normals, vec![], quad.indices, Material::default().into());
+        let direction = Vector3::new(0.0, 0.0, -1.0);
+        let bar_coords = Vector2::new(0.2, 0.3);
+        assert_eq!(mesh.triangle(0).normal(&bar_coords, &direction), Vector3::new(0.0, 0.0, 1.0));
+        assert!((mesh.triangle(1).normal(&bar_coords, &direction) - tilted).norm() < 1e-5);
+    }
+
+    #[test]
+    fn ray_interval() {
+        let mesh = quad();
+        let origin = Vector3::new(0.5, -0.5, -2.0);
+        let direction = Vector3::new(0.0, 0.0, 1.0);
+        assert!(mesh.intersect(0, &Ray::with_interval(origin, direction, 1.0, 3.0)).is_some());
+        // Hit is before or after the interval
+        assert_eq!(mesh.intersect(0, &Ray::with_interval(origin, direction, 2.5, 3.0)), None);
+        assert_eq!(mesh.intersect(0, &Ray::segment(origin, origin + direction)), None);
+    }
+}
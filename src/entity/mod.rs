@@ -1,5 +1,10 @@
 pub mod hit;
 pub mod anchor;
 pub mod triangle;
+pub mod mesh;
+pub mod geometry;
+pub mod surface_interaction;
+pub mod sphere;
+pub mod primitive;
 pub mod bounds;
 pub use bounds::*;
\ No newline at end of file
@@ -0,0 +1,317 @@
+use std::sync::Arc;
+use nalgebra::{Affine3, Point3, Vector3};
+use rayon::prelude::*;
+use crate::{math::ray::Ray, bvh::{Bvh, ReferenceClipper, BoxClipper}, material::Material};
+use super::{geometry::Geometry, mesh::Mesh, sphere::Sphere, triangle::Triangle, hit::{Hit, HittableSet, Intersection}, Bounds};
+
+/// Object of [`Primitives`], kind of object is dispatched with `match`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Primitive {
+    /// Triangle of `Primitives::geometry`
+    Triangle(Triangle),
+    /// Index in `Primitives::spheres`
+    Sphere(u32),
+    /// Index in `Primitives::instances`
+    Instance(u32),
+}
+
+impl Primitive {
+    /// Both primitives belong to one closed surface: triangles of one mesh, one sphere or one instance
+    #[inline]
+    pub fn same_surface(&self, other: &Primitive) -> bool {
+        match (self, other) {
+            (Primitive::Triangle(a), Primitive::Triangle(b)) => a.mesh == b.mesh,
+            _ => self == other,
+        }
+    }
+}
+
+/// Transformed reference to primitives with their own bvh.
+/// The same primitives can be placed many times without copying them.
+pub struct Instance {
+    pub primitives: Arc<Primitives>,
+    pub bvh: Arc<Bvh>,
+    local_to_world: Affine3<f32>,
+    world_to_local: Affine3<f32>,
+    /// Bounds of primitives in local space
+    local_bounds: Bounds,
+}
+
+impl Instance {
+    /// `bvh` must be built for bounds of `primitives`
+    pub fn new(primitives: Arc<Primitives>, bvh: Arc<Bvh>, local_to_world: Affine3<f32>) -> Self {
+        let world_to_local = local_to_world.try_inverse().expect("Instance transform must be invertible");
+        let mut local_bounds = Bounds::new(Vector3::zeros(), Vector3::repeat(f32::INFINITY), Vector3::repeat(f32::NEG_INFINITY));
+        for bounds in primitives.calculate_bounds() {
+            local_bounds.aabb_min = local_bounds.aabb_min.inf(&bounds.aabb_min);
+            local_bounds.aabb_max = local_bounds.aabb_max.sup(&bounds.aabb_max);
+        }
+        Instance { primitives, bvh, local_to_world, world_to_local, local_bounds }
+    }
+
+    #[inline]
+    pub fn transform(&self) -> &Affine3<f32> {
+        &self.local_to_world
+    }
+
+    /// Normal in local space transformed by inverse transpose to world space and normalized
+    #[inline]
+    pub fn normal_to_world(&self, normal: &Vector3<f32>) -> Vector3<f32> {
+        (self.world_to_local.matrix().fixed_view::<3, 3>(0, 0).transpose() * normal).normalize()
+    }
+
+    /// World bounds of the transformed local bounds
+    pub fn bounds(&self) -> Bounds {
+        let (min, max) = (self.local_bounds.aabb_min, self.local_bounds.aabb_max);
+        let mut aabb_min = Vector3::repeat(f32::INFINITY);
+        let mut aabb_max = Vector3::repeat(f32::NEG_INFINITY);
+        for corner in 0..8 {
+            let local = Point3::new(
+                if corner & 1 == 0 { min.x } else { max.x },
+                if corner & 2 == 0 { min.y } else { max.y },
+                if corner & 4 == 0 { min.z } else { max.z },
+            );
+            let world = (self.local_to_world * local).coords;
+            aabb_min = aabb_min.inf(&world);
+            aabb_max = aabb_max.sup(&world);
+        }
+        Bounds::new((aabb_min + aabb_max) * 0.5, aabb_min, aabb_max)
+    }
+
+    /// Ray in local space, direction isn't normalized, so hit distances are the same as in world space
+    #[inline]
+    pub fn local_ray(&self, ray: &Ray) -> Ray {
+        Ray::with_interval(
+            (self.world_to_local * Point3::from(ray.origin)).coords,
+            self.world_to_local * ray.get_direction(),
+            ray.t_min,
+            ray.t_max
+        )
+    }
+
+    /// Closest hit of inner primitive, useful for shading of instance hits
+    #[inline]
+    pub fn intersect_inner(&self, ray: &Ray) -> Option<Intersection<'_, Primitive>> {
+        let local_ray = self.local_ray(ray);
+        self.bvh.intersect(&local_ray, self.primitives.as_ref())
+            .map(|hit| Intersection::with_bar_coords(hit.t, hit.bar_coords, hit.object))
+    }
+}
+
+/// Objects of different kinds in one bvh: triangles, analytic shapes and instances.
+/// Triangle-only sets can be intersected through [`Geometry`] directly, see [`Primitives::triangles_only`].
+#[derive(Default)]
+pub struct Primitives {
+    pub geometry: Geometry,
+    pub spheres: Vec<Sphere>,
+    pub instances: Vec<Instance>,
+    /// Acceleration structures index into `primitives`
+    pub primitives: Vec<Primitive>,
+    /// Index of the first triangle primitive of every mesh, triangles of a mesh are next to each other
+    mesh_first_primitives: Vec<usize>,
+}
+
+impl Primitives {
+    /// All triangles of `geometry` become primitives
+    #[inline]
+    pub fn new(geometry: Geometry) -> Self {
+        let primitives = geometry.triangles.iter().map(|x| Primitive::Triangle(*x)).collect();
+        let mesh_first_primitives = geometry.meshes.iter().scan(0, |first, mesh| {
+            let mesh_first = *first;
+            *first += mesh.triangle_count();
+            Some(mesh_first)
+        }).collect();
+        Primitives { geometry, primitives, mesh_first_primitives, ..Default::default() }
+    }
+
+    /// Returns index of the added mesh
+    #[inline]
+    pub fn add_mesh(&mut self, mesh: Mesh) -> usize {
+        let first_triangle = self.geometry.triangles.len();
+        self.mesh_first_primitives.push(self.primitives.len());
+        let mesh_index = self.geometry.add_mesh(mesh);
+        self.primitives.extend(self.geometry.triangles[first_triangle..].iter().map(|x| Primitive::Triangle(*x)));
+        mesh_index
+    }
+
+    #[inline]
+    pub fn add_sphere(&mut self, sphere: Sphere) {
+        self.primitives.push(Primitive::Sphere(self.spheres.len() as u32));
+        self.spheres.push(sphere);
+    }
+
+    #[inline]
+    pub fn add_instance(&mut self, instance: Instance) {
+        self.primitives.push(Primitive::Instance(self.instances.len() as u32));
+        self.instances.push(instance);
+    }
+
+    #[inline]
+    pub fn bounds(&self, primitive: &Primitive) -> Bounds {
+        match primitive {
+            Primitive::Triangle(triangle) => self.geometry.triangle_bounds(triangle),
+            Primitive::Sphere(index) => self.spheres[*index as usize].bounds(),
+            Primitive::Instance(index) => self.instances[*index as usize].bounds(),
+        }
+    }
+
+    /// Bounds of all primitives for bvh build
+    #[inline]
+    pub fn calculate_bounds(&self) -> Vec<Bounds> {
+        self.primitives.par_iter().map(|x| self.bounds(x)).collect()
+    }
+
+    /// Primitives are only triangles of `geometry` in the same order,
+    /// so acceleration structures can intersect `geometry` without dispatch
+    #[inline]
+    pub fn triangles_only(&self) -> bool {
+        self.spheres.is_empty() && self.instances.is_empty()
+    }
+
+    /// Primitive of triangle from `geometry`
+    #[inline]
+    pub fn triangle_primitive(&self, triangle: &Triangle) -> &Primitive {
+        &self.primitives[self.mesh_first_primitives[triangle.mesh as usize] + triangle.primitive as usize]
+    }
+
+    /// Hit found in `geometry` as hit of primitive
+    #[inline]
+    pub fn triangle_hit<'a>(&'a self, hit: Hit<'_, Triangle>) -> Hit<'a, Primitive> {
+        Hit::new(hit.t, hit.point, hit.bar_coords, self.triangle_primitive(hit.object))
+    }
+
+    /// Some material of meshes, spheres or instanced primitives matches `predicate`
+    pub fn any_material(&self, predicate: &dyn Fn(&Material) -> bool) -> bool {
+        self.geometry.meshes.iter().any(|x| predicate(&x.material))
+            || self.spheres.iter().any(|x| predicate(&x.material))
+            || self.instances.iter().any(|x| x.primitives.any_material(predicate))
+    }
+}
+
+impl HittableSet<Primitive> for Primitives {
+    /// Instance hit refers to the instance primitive, see [`Instance::intersect_inner`] for the inner one
+    #[inline(always)]
+    fn intersect_object(&self, index: usize, ray: &Ray) -> Option<Intersection<'_, Primitive>> {
+        let primitive = &self.primitives[index];
+        match primitive {
+            Primitive::Triangle(triangle) => {
+                let vertices = self.geometry.meshes[triangle.mesh as usize].vertices(triangle.primitive as usize);
+                self.geometry.triangle_intersection.intersect(&vertices, ray)
+                    .map(|(t, bar_coords)| Intersection::with_bar_coords(t, bar_coords, primitive))
+            },
+            Primitive::Sphere(sphere) => self.spheres[*sphere as usize].intersect(ray).map(|t| Intersection::new(t, primitive)),
+            Primitive::Instance(instance) => self.instances[*instance as usize].intersect_inner(ray)
+                .map(|hit| Intersection::with_bar_coords(hit.t, hit.bar_coords, primitive)),
+        }
+    }
+}
+
+impl ReferenceClipper for Primitives {
+    /// Triangles are clipped exactly, other primitives by their bounding box
+    #[inline]
+    fn split(&self, object: usize, bounds: &Bounds, axis: usize, pos: f32) -> (Option<Bounds>, Option<Bounds>) {
+        if let Primitive::Triangle(triangle) = &self.primitives[object] {
+            return self.geometry.meshes[triangle.mesh as usize].split_bounds(triangle.primitive as usize, bounds, axis, pos);
+        }
+        BoxClipper.split(object, bounds, axis, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use nalgebra::{Affine3, Matrix4, Vector3};
+    use crate::{bvh::{Bvh, BvhBuilder, tests::{random_mesh, build_clipped}}, entity::{geometry::Geometry, hit::HittableSet, sphere::Sphere}, material::Material, math::{pcg, ray::Ray}};
+    use super::{Instance, Primitive, Primitives};
+
+    fn build(primitives: &Primitives, builder: BvhBuilder) -> Bvh {
+        build_clipped(primitives.calculate_bounds(), primitives, builder)
+    }
+
+    fn transform() -> Affine3<f32> {
+        Affine3::from_matrix_unchecked(Matrix4::new_translation(&Vector3::new(8.0, -2.0, 5.0))
+            * Matrix4::from_euler_angles(0.3, 0.5, -0.2) * Matrix4::new_scaling(0.8))
+    }
+
+    /// Triangles, spheres and a transformed instance of other triangles in one bvh
+    fn mixed_scene() -> Primitives {
+        let mut primitives = Primitives::new(Geometry::new(vec![random_mesh(500, 3, &Affine3::identity())]));
+        let mut seed = 5;
+        for _ in 0..100 {
+            primitives.add_sphere(Sphere::new(pcg::random_vector3(&mut seed) * 20.0, pcg::random_f32(&mut seed), Material::default().into()));
+        }
+        let inner = Primitives::new(Geometry::new(vec![random_mesh(500, 7, &Affine3::identity())]));
+        let inner_bvh = build(&inner, BvhBuilder::default());
+        primitives.add_instance(Instance::new(Arc::new(inner), Arc::new(inner_bvh), transform()));
+        primitives
+    }
+
+    #[test]
+    fn mixed_primitives() {
+        let primitives = mixed_scene();
+        // The same instance triangles placed in world space directly
+        let flattened = Geometry::new(vec![random_mesh(500, 7, &transform())]);
+        for builder in [BvhBuilder::default(), BvhBuilder::Spatial { bins: 16, max_duplication: 0.5 }] {
+            let bvh = build(&primitives, builder);
+            let mut seed = 11;
+            for _ in 0..500 {
+                let origin = pcg::random_vector3(&mut seed) * 50.0 - Vector3::repeat(15.0);
+                let target = pcg::random_vector3(&mut seed) * 30.0;
+                let ray = Ray::new(origin, (target - origin).normalize());
+                let expected = (0..primitives.primitives.len())
+                    .filter_map(|i| primitives.intersect_object(i, &ray))
+                    .min_by(|a, b| a.t.total_cmp(&b.t));
+                let hit = bvh.intersect(&ray, &primitives);
+                assert_eq!(hit.as_ref().map(|x| (x.t, x.object)), expected.as_ref().map(|x| (x.t, x.object)));
+                // Objects can also be used through trait object
+                let dynamic: &dyn HittableSet<Primitive> = &primitives;
+                assert_eq!(bvh.intersect(&ray, dynamic).map(|x| x.t), hit.as_ref().map(|x| x.t));
+
+                if let Some(Primitive::Instance(index)) = hit.as_ref().map(|x| x.object) {
+                    let instance = &primitives.instances[*index as usize];
+                    let inner = instance.intersect_inner(&ray).unwrap();
+                    let flat = (0..flattened.triangles.len())
+                        .filter_map(|i| flattened.intersect_object(i, &ray))
+                        .min_by(|a, b| a.t.total_cmp(&b.t))
+                        .unwrap();
+                    assert!((inner.t - flat.t).abs() < 1e-3 * flat.t);
+                    assert_eq!(inner.object, &Primitive::Triangle(*flat.object));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn instance_matches_flattened() {
+        let primitives = mixed_scene();
+        let instance = &primitives.instances[0];
+        let flattened = Geometry::new(vec![random_mesh(500, 7, &transform())]);
+        let mut seed = 13;
+        for triangle in &flattened.triangles {
+            let target = flattened.triangle(triangle).random_point(&mut seed);
+            let origin = target + pcg::random_direction(&mut seed) * 10.0;
+            let ray = Ray::new(origin, (target - origin).normalize());
+            let expected = (0..flattened.triangles.len())
+                .filter_map(|i| flattened.intersect_object(i, &ray))
+                .min_by(|a, b| a.t.total_cmp(&b.t))
+                .unwrap();
+            let inner = instance.intersect_inner(&ray).unwrap();
+            assert!((inner.t - expected.t).abs() < 1e-3 * expected.t);
+            assert_eq!(inner.object, &Primitive::Triangle(*expected.object));
+        }
+    }
+
+    #[test]
+    fn instance_bounds() {
+        let primitives = mixed_scene();
+        let instance = &primitives.instances[0];
+        let bounds = instance.bounds();
+        for triangle in &instance.primitives.geometry.triangles {
+            for vertex in instance.primitives.geometry.triangle(triangle).vertices() {
+                let world = instance.transform().transform_point(&vertex.into()).coords;
+                assert!(world.iter().zip(bounds.aabb_min.iter()).all(|(x, min)| *x >= *min - 1e-4));
+                assert!(world.iter().zip(bounds.aabb_max.iter()).all(|(x, max)| *x <= *max + 1e-4));
+            }
+        }
+    }
+}
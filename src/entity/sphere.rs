@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use nalgebra::Vector3;
+use crate::{math::ray::Ray, material::Material};
+use super::Bounds;
+
+/// Analytic sphere, intersected exactly without tessellation
+#[derive(Debug, Clone)]
+pub struct Sphere {
+    pub center: Vector3<f32>,
+    pub radius: f32,
+    pub material: Arc<Material>,
+}
+
+impl Sphere {
+    #[inline]
+    pub fn new(center: Vector3<f32>, radius: f32, material: Arc<Material>) -> Self {
+        Sphere { center, radius, material }
+    }
+
+    #[inline]
+    pub fn bounds(&self) -> Bounds {
+        let extent = Vector3::repeat(self.radius);
+        Bounds::new(self.center, self.center - extent, self.center + extent)
+    }
+
+    /// Distance to the nearest hit in ray interval, ray direction doesn't have to be normalized
+    #[inline]
+    pub fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let direction = ray.get_direction();
+        let oc = ray.origin - self.center;
+        let a = direction.norm_squared();
+        let half_b = oc.dot(direction);
+        // Distance from the center to the ray line, more precise than `oc² - r²` for far rays
+        let perpendicular = oc - direction * (half_b / a);
+        let discriminant = a * (self.radius * self.radius - perpendicular.norm_squared());
+        if discriminant < 0.0 {
+            return None;
+        }
+        let sqrt_discriminant = discriminant.sqrt();
+        [(-half_b - sqrt_discriminant) / a, (-half_b + sqrt_discriminant) / a]
+            .into_iter()
+            .find(|t| *t > ray.t_min && *t < ray.t_max)
+    }
+
+    /// Outward normal at point on the sphere
+    #[inline]
+    pub fn normal(&self, point: &Vector3<f32>) -> Vector3<f32> {
+        (point - self.center) / self.radius
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use crate::{material::Material, math::ray::Ray};
+    use super::Sphere;
+
+    #[test]
+    fn sphere_intersection() {
+        let sphere = Sphere::new(Vector3::new(0.0, 0.0, 5.0), 1.0, Material::default().into());
+        let ray = Ray::new(Vector3::zeros(), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(sphere.intersect(&ray), Some(4.0));
+        // From inside the far side is hit
+        assert_eq!(sphere.intersect(&Ray::with_interval(Vector3::zeros(), Vector3::z(), 5.0, 10.0)), Some(6.0));
+        // Not normalized direction scales distance
+        assert_eq!(sphere.intersect(&Ray::new(Vector3::zeros(), Vector3::new(0.0, 0.0, 2.0))), Some(2.0));
+        assert_eq!(sphere.intersect(&Ray::new(Vector3::new(0.0, 1.5, 0.0), Vector3::z())), None);
+        assert_eq!(sphere.intersect(&Ray::segment(Vector3::zeros(), Vector3::new(0.0, 0.0, 3.0))), None);
+        assert_eq!(sphere.normal(&Vector3::new(0.0, 0.0, 4.0)), Vector3::new(0.0, 0.0, -1.0));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{f32::consts::PI, sync::Arc};
+use nalgebra::{Point3, Vector3, Vector2};
+use crate::{material::Material, math::{extensions::orthonormal_basis, ray::Ray}};
+use super::{geometry::Geometry, hit::Hit, triangle::Triangle, sphere::Sphere, primitive::{Primitive, Primitives, Instance}};
+
+/// Geometry of the surface at a ray hit, calculated once and shared by shading code
+#[derive(Debug, Clone)]
+pub struct SurfaceInteraction<'a> {
+    /// Interpolated from vertices, more precise than point on the ray
+    pub position: Vector3<f32>,
+    /// Normal of triangle plane or of sphere, facing the side ray came from
+    pub geometric_normal: Vector3<f32>,
+    /// Ray hit the side with counter clockwise winding, see [`crate::material::EmissionSides::Front`]
+    pub front_face: bool,
+    /// Interpolated vertex normal or geometric normal without vertex normals, facing the side ray came from
+    pub shading_normal: Vector3<f32>,
+    /// Barycentric coordinates on triangle, see [`super::mesh::MeshTriangle::bar_coords`], zero for spheres
+    pub bar_coords: Vector2<f32>,
+    /// Texture coordinates, zero if mesh has no uvs
+    pub uv: Vector2<f32>,
+    /// Unit tangent orthogonal to shading normal, along increasing `u` if mesh has uvs
+    pub tangent: Vector3<f32>,
+    /// `shading_normal × tangent`
+    pub bitangent: Vector3<f32>,
+    /// Hit primitive, instance itself for primitives inside of instance
+    pub primitive: Primitive,
+    pub material: &'a Arc<Material>,
+}
+
+impl<'a> SurfaceInteraction<'a> {
+    /// Surface at `hit` of ray with `ray_direction`
+    pub fn new(primitives: &'a Primitives, hit: &Hit<'_, Primitive>, ray_direction: &Vector3<f32>) -> Self {
+        match hit.object {
+            Primitive::Triangle(triangle) => Self::triangle(&primitives.geometry, triangle, &hit.bar_coords, ray_direction),
+            Primitive::Sphere(index) => Self::sphere(&primitives.spheres[*index as usize], hit, ray_direction),
+            Primitive::Instance(index) => Self::instance(&primitives.instances[*index as usize], hit, ray_direction),
+        }
+    }
+
+    /// Surface at barycentric coordinates of triangle from `geometry`
+    pub fn triangle(geometry: &'a Geometry, primitive: &Triangle, bar_coords: &Vector2<f32>, ray_direction: &Vector3<f32>) -> Self {
+        let triangle = geometry.triangle(primitive);
+        let bar_coords = *bar_coords;
+        let [vertex1, vertex2, vertex3] = triangle.vertices();
+        let position = bar_coords.x * vertex1 + bar_coords.y * vertex2 + (1.0 - bar_coords.x - bar_coords.y) * vertex3;
+
+        let geometric_normal = triangle.plane_normal();
+        let front_face = geometric_normal.dot(ray_direction) <= 0.0;
+        let geometric_normal = if front_face { geometric_normal } else { -geometric_normal };
+        let shading_normal = triangle.normal(&bar_coords, ray_direction).normalize();
+
+        // Position derivative along u, from edges and their uv differences
+        let dpdu = triangle.vertex_uvs().and_then(|[uv1, uv2, uv3]| {
+            let (duv1, duv2) = (uv2 - uv1, uv3 - uv1);
+            let determinant = duv1.x * duv2.y - duv1.y * duv2.x;
+            (determinant.abs() > f32::EPSILON).then(|| (duv2.y * (vertex2 - vertex1) - duv1.y * (vertex3 - vertex1)) / determinant)
+        });
+        let tangent = Self::tangent(dpdu, &shading_normal);
+
+        SurfaceInteraction {
+            position,
+            geometric_normal,
+            front_face,
+            shading_normal,
+            bar_coords,
+            uv: triangle.uv_coords(&bar_coords),
+            tangent,
+            bitangent: shading_normal.cross(&tangent),
+            primitive: Primitive::Triangle(*primitive),
+            material: &geometry.meshes[primitive.mesh as usize].material,
+        }
+    }
+
+    /// Sphere has no vertex normals, `u` goes around y axis and `v` from the bottom pole to the top one
+    fn sphere(sphere: &'a Sphere, hit: &Hit<'_, Primitive>, ray_direction: &Vector3<f32>) -> Self {
+        let outward = sphere.normal(&hit.point).normalize();
+        let front_face = outward.dot(ray_direction) <= 0.0;
+        let normal = if front_face { outward } else { -outward };
+        let uv = Vector2::new(0.5 + outward.z.atan2(outward.x) / (2.0 * PI), 0.5 + outward.y.clamp(-1.0, 1.0).asin() / PI);
+        let tangent = Self::tangent(Some(Vector3::new(-outward.z, 0.0, outward.x)), &normal);
+        SurfaceInteraction {
+            // Projected on the sphere, more precise than point on the ray
+            position: sphere.center + outward * sphere.radius,
+            geometric_normal: normal,
+            front_face,
+            shading_normal: normal,
+            bar_coords: Vector2::zeros(),
+            uv,
+            tangent,
+            bitangent: normal.cross(&tangent),
+            primitive: *hit.object,
+            material: &sphere.material,
+        }
+    }
+
+    /// Surface of inner primitive hit by the ray in local space, transformed to world space
+    fn instance(instance: &'a Instance, hit: &Hit<'_, Primitive>, ray_direction: &Vector3<f32>) -> Self {
+        // Instance hit doesn't keep the inner primitive, it's found again with the same ray
+        // or with a short segment around the hit if rounding of the origin misses it
+        let margin = 1e-4 * (hit.t + hit.point.amax());
+        let rays = [
+            Ray::new(hit.point - ray_direction * hit.t, *ray_direction),
+            Ray::with_interval(hit.point - ray_direction * margin, *ray_direction, 0.0, 2.0 * margin),
+        ];
+        let (ray, inner) = rays.iter().find_map(|ray| instance.intersect_inner(ray).map(|inner| (ray, inner)))
+            .expect("Instance hit must be found in its primitives");
+        let local_ray = instance.local_ray(ray);
+        let local_hit = inner.into_hit(&local_ray);
+        let local = SurfaceInteraction::new(instance.primitives.as_ref(), &local_hit, local_ray.get_direction());
+
+        // Side of the surface doesn't change, normals are transformed by inverse transpose
+        let shading_normal = instance.normal_to_world(&local.shading_normal);
+        let tangent = Self::tangent(Some(instance.transform() * local.tangent), &shading_normal);
+        SurfaceInteraction {
+            position: (instance.transform() * Point3::from(local.position)).coords,
+            geometric_normal: instance.normal_to_world(&local.geometric_normal),
+            shading_normal,
+            tangent,
+            bitangent: shading_normal.cross(&tangent),
+            primitive: *hit.object,
+            ..local
+        }
+    }
+
+    /// Unit tangent from `direction` made orthogonal to `normal`, arbitrary one if it's degenerate
+    #[inline]
+    fn tangent(direction: Option<Vector3<f32>>, normal: &Vector3<f32>) -> Vector3<f32> {
+        direction
+            .map(|x| x - normal * normal.dot(&x))
+            .filter(|x| x.norm_squared() > f32::EPSILON)
+            .map(|x| x.normalize())
+            .unwrap_or_else(|| orthonormal_basis(normal).0)
+    }
+
+    /// Transforms direction from tangent space, where z is the shading normal, to world space
+    #[inline]
+    pub fn to_world(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        self.tangent * direction.x + self.bitangent * direction.y + self.shading_normal * direction.z
+    }
+
+    /// Transforms world direction to tangent space, where z is the shading normal
+    #[inline]
+    pub fn to_local(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        Vector3::new(self.tangent.dot(direction), self.bitangent.dot(direction), self.shading_normal.dot(direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use nalgebra::{Affine3, Matrix4, Point3, Vector3, Vector2};
+    use crate::{entity::{geometry::Geometry, mesh::Mesh, hit::HittableSet, triangle::TriangleIntersection}, material::Material, math::ray::Ray};
+    use crate::{bvh::Bvh, entity::{sphere::Sphere, primitive::{Primitive, Primitives, Instance}}};
+    use super::SurfaceInteraction;
+
+    /// Quad in xy plane with u along x and v along y
+    fn quad(uvs: bool) -> Geometry {
+        transformed_quad(uvs, &Affine3::identity())
+    }
+
+    fn transformed_quad(uvs: bool, transform: &Affine3<f32>) -> Geometry {
+        let uvs = if uvs {
+            vec![Vector2::new(0.0, 0.0), Vector2::new(1.0, 0.0), Vector2::new(1.0, 1.0), Vector2::new(0.0, 1.0)]
+        } else {
+            vec![]
+        };
+        Geometry::new(vec![Mesh::new(
+            [
+                Vector3::new(-1.0, -1.0, 0.0),
+                Vector3::new(1.0, -1.0, 0.0),
+                Vector3::new(1.0, 1.0, 0.0),
+                Vector3::new(-1.0, 1.0, 0.0),
+            ].iter().map(|x| (transform * Point3::from(*x)).coords).collect(),
+            vec![],
+            uvs,
+            vec![[0, 1, 2], [0, 2, 3]],
+            Material::default().into()
+        )])
+    }
+
+    #[test]
+    fn surface_at_hit() {
+        let mut geometry = quad(true);
+        for intersection in [TriangleIntersection::MollerTrumbore, TriangleIntersection::Watertight] {
+            geometry.triangle_intersection = intersection;
+            for (origin, triangle) in [(Vector3::new(0.5, -0.5, -2.0), 0), (Vector3::new(-0.5, 0.5, -2.0), 1)] {
+                let direction = Vector3::new(0.0, 0.0, 1.0);
+                let ray = Ray::new(origin, direction);
+                let hit = geometry.intersect_object(triangle, &ray).unwrap().into_hit(&ray);
+                let surface = SurfaceInteraction::triangle(&geometry, hit.object, &hit.bar_coords, &direction);
+                // Barycentric coordinates from intersection match the ones from hit point
+                let bar_coords = geometry.triangle(hit.object).bar_coords(&hit.point);
+                assert!((surface.bar_coords - bar_coords).norm() < 1e-5);
+                assert!((surface.position - Vector3::new(origin.x, origin.y, 0.0)).norm() < 1e-5);
+                assert!((surface.uv - (origin.xy() + Vector2::repeat(1.0)) / 2.0).norm() < 1e-5);
+                assert_eq!(surface.geometric_normal, Vector3::new(0.0, 0.0, -1.0));
+                assert!(!surface.front_face);
+                assert_eq!(surface.shading_normal, Vector3::new(0.0, 0.0, -1.0));
+                assert!((surface.tangent - Vector3::new(1.0, 0.0, 0.0)).norm() < 1e-5);
+                assert!((surface.bitangent - Vector3::new(0.0, -1.0, 0.0)).norm() < 1e-5);
+                assert_eq!(surface.primitive, Primitive::Triangle(*hit.object));
+            }
+        }
+    }
+
+    #[test]
+    fn tangent_frame_without_uvs() {
+        let geometry = quad(false);
+        let direction = Vector3::new(0.3, 0.2, -1.0).normalize();
+        let ray = Ray::new(Vector3::new(0.0, 0.0, 2.0), direction);
+        let hit = geometry.intersect_object(0, &ray).or_else(|| geometry.intersect_object(1, &ray)).unwrap().into_hit(&ray);
+        let surface = SurfaceInteraction::triangle(&geometry, hit.object, &hit.bar_coords, &direction);
+        assert_eq!(surface.uv, Vector2::zeros());
+        assert!(surface.tangent.dot(&surface.shading_normal).abs() < 1e-5);
+        assert!((surface.tangent.norm() - 1.0).abs() < 1e-5);
+        assert!((surface.bitangent.norm() - 1.0).abs() < 1e-5);
+        let local = Vector3::new(0.48, 0.6, 0.64);
+        assert!((surface.to_local(&surface.to_world(&local)) - local).norm() < 1e-5);
+        assert!((surface.to_world(&Vector3::z()) - surface.shading_normal).norm() < 1e-5);
+    }
+
+    #[test]
+    fn surface_of_sphere() {
+        let mut primitives = Primitives::default();
+        primitives.add_sphere(Sphere::new(Vector3::new(0.0, 0.0, 5.0), 2.0, Material::default().into()));
+        let direction = Vector3::new(0.0, 0.0, 1.0);
+        for (ray, front_face) in [(Ray::new(Vector3::new(0.0, 1.0, 0.0), direction), true), (Ray::new(Vector3::new(0.0, 1.0, 5.0), direction), false)] {
+            let hit = primitives.intersect_object(0, &ray).unwrap().into_hit(&ray);
+            let surface = SurfaceInteraction::new(&primitives, &hit, &direction);
+            assert_eq!(surface.front_face, front_face);
+            assert!(((surface.position - Vector3::new(0.0, 0.0, 5.0)).norm() - 2.0).abs() < 1e-5);
+            assert!(surface.geometric_normal.dot(&direction) < 0.0);
+            assert_eq!(surface.shading_normal, surface.geometric_normal);
+            assert!(surface.tangent.dot(&surface.shading_normal).abs() < 1e-5);
+            assert!((surface.tangent.norm() - 1.0).abs() < 1e-5);
+            assert!(surface.uv.iter().all(|x| (0.0..=1.0).contains(x)));
+            assert_eq!(surface.primitive, Primitive::Sphere(0));
+        }
+        // Poles still have tangent frame
+        let ray = Ray::new(Vector3::new(0.0, 10.0, 5.0), -Vector3::y());
+        let hit = primitives.intersect_object(0, &ray).unwrap().into_hit(&ray);
+        let surface = SurfaceInteraction::new(&primitives, &hit, ray.get_direction());
+        assert!((surface.tangent.norm() - 1.0).abs() < 1e-5);
+        assert!((surface.uv.y - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn surface_of_instance() {
+        // Rotated and non-uniformly scaled quad matches the same quad placed in world space directly
+        let transform = Affine3::from_matrix_unchecked(Matrix4::new_translation(&Vector3::new(1.0, 2.0, 3.0))
+            * Matrix4::from_euler_angles(0.4, -0.3, 0.2) * Matrix4::new_nonuniform_scaling(&Vector3::new(2.0, 0.5, 1.5)));
+        let flattened = transformed_quad(true, &transform);
+        let inner = Primitives::new(quad(true));
+        let mut bvh = Bvh::default();
+        let bounds = inner.calculate_bounds();
+        bvh.calculate_bvh(bounds.clone(), bounds.iter().map(|x| x.centroid).collect());
+        let mut primitives = Primitives::default();
+        primitives.add_instance(Instance::new(Arc::new(inner), Arc::new(bvh), transform));
+
+        for local_target in [Vector3::new(0.5, -0.5, 0.0), Vector3::new(-0.3, 0.6, 0.0)] {
+            let target = (transform * Point3::from(local_target)).coords;
+            for origin in [Vector3::new(5.0, 6.0, 7.0), Vector3::new(-4.0, -3.0, -2.0)] {
+                let direction = (target - origin).normalize();
+                let ray = Ray::new(origin, direction);
+                let hit = primitives.intersect_object(0, &ray).unwrap().into_hit(&ray);
+                let surface = SurfaceInteraction::new(&primitives, &hit, &direction);
+                let flat_hit = (0..2).find_map(|i| flattened.intersect_object(i, &ray)).unwrap().into_hit(&ray);
+                let expected = SurfaceInteraction::triangle(&flattened, flat_hit.object, &flat_hit.bar_coords, &direction);
+                assert!((surface.position - expected.position).norm() < 1e-4);
+                assert!((surface.geometric_normal - expected.geometric_normal).norm() < 1e-4);
+                assert!((surface.shading_normal - expected.shading_normal).norm() < 1e-4);
+                assert!((surface.tangent - expected.tangent).norm() < 1e-4);
+                assert!((surface.uv - expected.uv).norm() < 1e-4);
+                assert_eq!(surface.front_face, expected.front_face);
+                assert_eq!(surface.primitive, Primitive::Instance(0));
+            }
+        }
+    }
+}
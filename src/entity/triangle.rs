@@ -1,219 +1,243 @@
-use std::sync::Arc;
 use nalgebra::{Vector3, Vector2};
-use crate::{math::{ray::Ray, pcg}, material::Material, entity::hit::Intersection};
+use crate::{math::ray::Ray, bvh::clip_bounds};
 
-use super::{hit::Hittable, Bounds};
+use super::Bounds;
 
-// Maybe change it to pointer to vertex slice of vertexes
-#[derive(Debug)]
+/// Reference to triangle `primitive` of mesh `mesh`.
+/// Triangle data itself lives in mesh buffers, see [`super::mesh::Mesh`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Triangle {
-    vertex1: Vector3<f32>,
-    vertex2: Vector3<f32>,
-    vertex3: Vector3<f32>,
-    norm1: Vector3<f32>,
-    norm2: Vector3<f32>,
-    norm3: Vector3<f32>,
-    normal: Vector3<f32>,
-    uv1: Vector2<f32>,
-    uv2: Vector2<f32>,
-    uv3: Vector2<f32>,
-    pub material: Arc<Material>,
-    pub index: usize,
+    pub mesh: u32,
+    pub primitive: u32,
 }
 
-#[allow(dead_code)]
 impl Triangle {
-    #[allow(clippy::too_many_arguments)]
-    #[inline]
-    pub fn new(vertex1: Vector3<f32>, vertex2: Vector3<f32>, vertex3: Vector3<f32>,
-        norm1: Vector3<f32>, norm2: Vector3<f32>, norm3: Vector3<f32>,
-        uv1: Vector2<f32>, uv2: Vector2<f32>, uv3: Vector2<f32>,
-        material: Arc<Material>, index: usize) -> Self
-        {
-        let mut tr = Triangle {
-            vertex1, vertex2, vertex3,
-            norm1, norm2, norm3,
-            normal: Vector3::zeros(),
-            uv1, uv2, uv3,
-            material, index
-        };
-        tr.normal = tr.plane_normal();
-        tr
-    }
-
     #[inline(always)]
-    pub fn vertex1(&self) -> Vector3<f32> {
-        self.vertex1
+    pub fn new(mesh: u32, primitive: u32) -> Self {
+        Triangle { mesh, primitive }
     }
+}
 
-    #[inline(always)]
-    pub fn vertex2(&self) -> Vector3<f32> {
-        self.vertex2
-    }
+/// Algorithm used for ray-triangle intersection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TriangleIntersection {
+    /// Fast, but rays hitting shared edges exactly can pass between triangles
+    #[cfg_attr(not(feature = "watertight"), default)]
+    MollerTrumbore,
+    /// Never misses shared edges and vertices, a bit slower.
+    /// Default if built with `watertight` feature.
+    #[cfg_attr(feature = "watertight", default)]
+    Watertight,
+}
 
+impl TriangleIntersection {
+    /// Returns distance and barycentric coordinates of the hit, see [`intersect_triangle`]
     #[inline(always)]
-    pub fn vertex3(&self) -> Vector3<f32> {
-        self.vertex3
+    pub fn intersect(self, vertices: &[Vector3<f32>; 3], ray: &Ray) -> Option<(f32, Vector2<f32>)> {
+        match self {
+            TriangleIntersection::MollerTrumbore => intersect_triangle(vertices, ray),
+            TriangleIntersection::Watertight => intersect_triangle_watertight(vertices, ray),
+        }
     }
+}
 
-    #[inline]
-    /// Barycentric coordinates
-    pub fn bar_coords(&self, hit_point: &Vector3<f32>) -> Vector2<f32> {
-        let v0v1: Vector3<f32> = self.vertex2 - self.vertex1;
-        let v0v2: Vector3<f32> = self.vertex3 - self.vertex1;
-        let n: Vector3<f32> = v0v1.cross(&v0v2);
-        let denom = n.dot(&n);
-
-        let mut c: Vector3<f32>;
-
-        let edge1: Vector3<f32> = self.vertex3 - self.vertex2;
-        let vp1: Vector3<f32> = hit_point - self.vertex2;
-        c = edge1.cross(&vp1);
-        let mut u = n.dot(&c);
-
-        let edge2: Vector3<f32> = self.vertex1 - self.vertex3;
-        let vp2: Vector3<f32> = hit_point - self.vertex3;
-        c = edge2.cross(&vp2);
-        let mut v = n.dot(&c);
-
-        u /= denom;
-        v /= denom;
-
-        Vector2::new(u, v)
+// Möller–Trumbore intersection modified algorithm
+// Hits outside of ray interval are rejected
+/// Returns distance and barycentric coordinates of the hit.
+/// Barycentric `x` is the weight of the first vertex and `y` of the second one,
+/// same as [`super::mesh::MeshTriangle::bar_coords`].
+#[inline(always)]
+#[allow(clippy::manual_range_contains)]
+pub fn intersect_triangle(vertices: &[Vector3<f32>; 3], ray: &Ray) -> Option<(f32, Vector2<f32>)> {
+    let [vertex1, vertex2, vertex3] = vertices;
+    let edge1 = vertex2 - vertex1;
+    let edge2 = vertex3 - vertex1;
+    let h = ray.get_direction().cross(&edge2);
+    let a = edge1.dot(&h);
+
+    // Without this check render is faster
+    // if a > -EPSILON && a < EPSILON {
+    //     return None; // This ray is parallel to this triangle.
+    // }
+
+    let f = 1.0 / a;
+    let s = ray.origin - vertex1;
+    let u = f * s.dot(&h);
+    if u < 0.0 || u > 1.0 {
+        return None;
     }
 
-    #[inline]
-    pub fn vertex_color(&self, bar_coords: &Vector2<f32>) -> Vector3<f32> {
-        let c1: Vector3<f32> = Vector3::new(1.0, 0.0, 0.0);
-        let c2: Vector3<f32> = Vector3::new(0.0, 1.0, 0.0);
-        let c3: Vector3<f32> = Vector3::new(0.0, 0.0, 1.0);
+    let q = s.cross(&edge1);
+    // At this stage we can compute t to find out where the intersection point is on the line.
+    let t = f * edge2.dot(&q);
+    // This means that there is a line intersection but not a ray intersection.
+    if t <= ray.t_min || t >= ray.t_max || t.is_nan() {
+        return None;
+    }
 
-        bar_coords.x * c1 + bar_coords.y * c2 + (1.0 - bar_coords.x - bar_coords.y) * c3
+    let v = f * ray.get_direction().dot(&q);
+    if v < 0.0 || u + v > 1.0 {
+        return None;
     }
+    // If everything passed - we hit
+    Some((t, Vector2::new(1.0 - u - v, u)))
+}
 
-    #[inline]
-    pub fn uv_coords(&self, bar_coords: &Vector2<f32>) -> Vector2<f32> {
-        bar_coords.x * self.uv1 + bar_coords.y * self.uv2 + (1.0 - bar_coords.x - bar_coords.y) * self.uv3
+/// Watertight ray-triangle intersection.
+/// "Watertight Ray/Triangle Intersection", Woop, Benthin, Wald, 2013.
+/// Vertices are transformed to the space where ray goes along Z axis from the origin,
+/// then edge functions are evaluated in 2D, which is consistent for shared edges.
+#[inline(always)]
+pub fn intersect_triangle_watertight(vertices: &[Vector3<f32>; 3], ray: &Ray) -> Option<(f32, Vector2<f32>)> {
+    let direction = ray.get_direction();
+    // Dimension where the ray direction is maximal becomes Z
+    let kz = direction.iamax();
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    // Swap to preserve winding of the triangle
+    if direction[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
     }
-    
-    #[inline]
-    pub fn normal(&self, bar_coords: &Vector2<f32>, ray_direction: &Vector3<f32>) -> Vector3<f32> {
-        let intrerp_normal: Vector3<f32> = bar_coords.x * self.norm1 + bar_coords.y * self.norm2 + (1.0 - bar_coords.x - bar_coords.y) * self.norm3;
-        if intrerp_normal.dot(ray_direction) > 0.0 {
-            -intrerp_normal
-        } else {
-            intrerp_normal
-        }
+    let shear_x = direction[kx] / direction[kz];
+    let shear_y = direction[ky] / direction[kz];
+    let shear_z = 1.0 / direction[kz];
+
+    let [a, b, c] = vertices.map(|x| x - ray.origin);
+    let ax = a[kx] - shear_x * a[kz];
+    let ay = a[ky] - shear_y * a[kz];
+    let bx = b[kx] - shear_x * b[kz];
+    let by = b[ky] - shear_y * b[kz];
+    let cx = c[kx] - shear_x * c[kz];
+    let cy = c[ky] - shear_y * c[kz];
+
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+    // Ray goes exactly through the edge, recalculate with double precision
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+        v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+        w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
     }
-
-    #[inline]
-    fn plane_normal(&self) -> Vector3<f32> {
-        // Calculate the normal vector of the triangle (cross product of two edges)
-        let v1: Vector3<f32> = self.vertex2 - self.vertex1;
-        let v2: Vector3<f32> = self.vertex3 - self.vertex1;
-        v1.cross(&v2).normalize()
+    // Both sides of the triangle are hit
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
     }
-
-    #[inline]
-    pub fn get_plane_normal(&self) -> Vector3<f32> {
-        self.normal
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
     }
-
-    #[inline]
-    pub fn random_point(&self, seed: &mut u32) -> Vector3<f32> {
-        // Shape Distributions
-        // ROBERT OSADA, THOMAS FUNKHOUSER, BERNARD CHAZELLE, and DAVID DOBKIN
-        // Princeton University
-        // P = (1 - sqrt(r1))*A + sqrt(r1)*(1 - r2)*B + sqrt(r1)*r2*C
-        // Where A, B, C is vertices and r1, r2 is uniform random values in range 0-1
-        let r1sqrt = pcg::random_f32(seed).sqrt();
-        let r2 = pcg::random_f32(seed);
-        (1.0 - r1sqrt) * self.vertex1 + r1sqrt * (1.0 - r2) * self.vertex2 + r1sqrt * r2 * self.vertex3
+    let t = (u * shear_z * a[kz] + v * shear_z * b[kz] + w * shear_z * c[kz]) / det;
+    if t > ray.t_min && t < ray.t_max {
+        Some((t, Vector2::new(u / det, v / det)))
+    } else {
+        None
     }
 }
 
-impl Hittable<Triangle> for Triangle {
-    // Möller–Trumbore intersection modified algorithm
-    #[inline]
-    #[allow(clippy::manual_range_contains)]
-    fn intersect(&self, ray: &Ray) -> Option<Intersection<Self>> {
-        const EPSILON: f32 = 0.0000001;
-        let edge1 = self.vertex2 - self.vertex1;
-        let edge2 = self.vertex3 - self.vertex1;
-        let h = ray.get_direction().cross(&edge2);
-        let a = edge1.dot(&h);
-        
-        // Without this check render is faster
-        // if a > -EPSILON && a < EPSILON {
-        //     return None; // This ray is parallel to this triangle.
-        // } 
-
-        let f = 1.0 / a;
-        let s = ray.origin - self.vertex1;
-        let u = f * s.dot(&h);
-        if u < 0.0 || u > 1.0 {
-            return None;
+/// Splits triangle by plane `pos` on `axis`, returns bounds of both parts limited by `bounds`.
+/// `None` if triangle has no part on that side inside `bounds`.
+#[inline]
+pub fn split_triangle_bounds(vertices: &[Vector3<f32>; 3], bounds: &Bounds, axis: usize, pos: f32) -> (Option<Bounds>, Option<Bounds>) {
+    let mut left = (Vector3::repeat(f32::INFINITY), Vector3::repeat(f32::NEG_INFINITY));
+    let mut right = left;
+    let grow = |side: &mut (Vector3<f32>, Vector3<f32>), point: &Vector3<f32>| {
+        side.0 = side.0.inf(point);
+        side.1 = side.1.sup(point);
+    };
+    for i in 0..3 {
+        let vertex1 = &vertices[i];
+        let vertex2 = &vertices[(i + 1) % 3];
+        if vertex1[axis] <= pos {
+            grow(&mut left, vertex1);
         }
-
-        let q = s.cross(&edge1);
-        // At this stage we can compute t to find out where the intersection point is on the line.
-        let t = f * edge2.dot(&q);
-        // This means that there is a line intersection but not a ray intersection.
-        if t <= EPSILON || t.is_nan() {
-            return None;
+        if vertex1[axis] >= pos {
+            grow(&mut right, vertex1);
         }
-
-        let v = f * ray.get_direction().dot(&q);
-        if v < 0.0 || u + v > 1.0 {
-            return None;
+        // Edge crosses the plane, intersection point belongs to both parts
+        if (vertex1[axis] < pos && vertex2[axis] > pos) || (vertex1[axis] > pos && vertex2[axis] < pos) {
+            let t = (pos - vertex1[axis]) / (vertex2[axis] - vertex1[axis]);
+            let mut point = vertex1 + (vertex2 - vertex1) * t;
+            point[axis] = pos;
+            grow(&mut left, &point);
+            grow(&mut right, &point);
         }
-        // If everything passed - we hit
-        Some(Intersection::new(t, self))
     }
+    (clip_bounds(left.0, left.1, bounds), clip_bounds(right.0, right.1, bounds))
 }
 
-impl From<Triangle> for Bounds {
+impl From<[Vector3<f32>; 3]> for Bounds {
     #[inline]
-    fn from(value: Triangle) -> Self {
-        let points: [Vector3<f32>; 3] = [value.vertex1, value.vertex2, value.vertex3];
-
-        let x_min = points.iter().map(|x: &Vector3<f32>| x.x).min_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-        let y_min = points.iter().map(|x: &Vector3<f32>| x.y).min_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-        let z_min = points.iter().map(|x: &Vector3<f32>| x.z).min_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-
-        let x_max = points.iter().map(|x: &Vector3<f32>| x.x).max_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-        let y_max = points.iter().map(|x: &Vector3<f32>| x.y).max_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-        let z_max = points.iter().map(|x: &Vector3<f32>| x.z).max_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-
-        let min_bounds = Vector3::new(x_min, y_min, z_min);
-        let max_bounds = Vector3::new(x_max, y_max, z_max);
+    fn from(points: [Vector3<f32>; 3]) -> Self {
+        let [vertex1, vertex2, vertex3] = points;
         Bounds {
-            centroid: (value.vertex1 + value.vertex2 + value.vertex3) / 3.0,
-            aabb_min: min_bounds,
-            aabb_max: max_bounds
+            centroid: (vertex1 + vertex2 + vertex3) / 3.0,
+            aabb_min: vertex1.inf(&vertex2).inf(&vertex3),
+            aabb_max: vertex1.sup(&vertex2).sup(&vertex3)
         }
     }
 }
 
-impl From<&Triangle> for Bounds {
-    #[inline]
-    fn from(value: &Triangle) -> Self {
-        let points: [Vector3<f32>; 3] = [value.vertex1, value.vertex2, value.vertex3];
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use crate::math::{ray::Ray, pcg};
+    use super::{TriangleIntersection, intersect_triangle_watertight};
 
-        let x_min = points.iter().map(|x: &Vector3<f32>| x.x).min_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-        let y_min = points.iter().map(|x: &Vector3<f32>| x.y).min_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-        let z_min = points.iter().map(|x: &Vector3<f32>| x.z).min_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
+    /// Returns true if ray hits any of triangles
+    fn hits_any(triangles: &[[Vector3<f32>; 3]], ray: &Ray, intersection: TriangleIntersection) -> bool {
+        triangles.iter().any(|x| intersection.intersect(x, ray).is_some())
+    }
+
+    #[test]
+    fn watertight_shared_edges() {
+        // Planar quad split by diagonal, plane is not axis aligned.
+        // Edges of folded surfaces can be legitimately missed at silhouettes.
+        let plane = |x: f32, y: f32| Vector3::new(x, y, 0.3 * x - 0.2 * y + 0.1);
+        let quad = [plane(-1.3, -0.7), plane(1.1, -1.2), plane(0.9, 1.4), plane(-1.2, 0.8)];
+        let triangles = [[quad[0], quad[1], quad[2]], [quad[0], quad[2], quad[3]]];
+        let mut seed = 5;
+        for _ in 0..10000 {
+            let target = quad[0].lerp(&quad[2], pcg::random_f32(&mut seed));
+            let origin = pcg::random_vector3(&mut seed) * 10.0 - Vector3::new(5.0, 5.0, 10.0);
+            let ray = Ray::new(origin, (target - origin).normalize());
+            assert!(hits_any(&triangles, &ray, TriangleIntersection::Watertight), "ray to {:?} missed shared edge", target);
+        }
+    }
 
-        let x_max = points.iter().map(|x: &Vector3<f32>| x.x).max_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-        let y_max = points.iter().map(|x: &Vector3<f32>| x.y).max_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
-        let z_max = points.iter().map(|x: &Vector3<f32>| x.z).max_by(|x, y| x.partial_cmp(y).unwrap()).unwrap();
+    #[test]
+    fn watertight_shared_vertex() {
+        // Planar fan of triangles around the center vertex
+        let center = Vector3::new(0.1, 0.2, 0.3);
+        let segments = 7;
+        let triangles: Vec<[Vector3<f32>; 3]> = (0..segments).map(|i| {
+            let angle = |i: usize| std::f32::consts::TAU * i as f32 / segments as f32;
+            let point = |a: f32| center + Vector3::new(a.cos(), a.sin(), 0.3 * a.cos() - 0.2 * a.sin());
+            [center, point(angle(i)), point(angle(i + 1))]
+        }).collect();
+        let mut seed = 7;
+        for _ in 0..10000 {
+            let origin = pcg::random_vector3(&mut seed) * 10.0 - Vector3::new(5.0, 5.0, 10.0);
+            let ray = Ray::new(origin, (center - origin).normalize());
+            assert!(hits_any(&triangles, &ray, TriangleIntersection::Watertight), "ray from {:?} missed shared vertex", origin);
+        }
+    }
 
-        let min_bounds = Vector3::new(x_min, y_min, z_min);
-        let max_bounds = Vector3::new(x_max, y_max, z_max);
-        Bounds {
-            centroid: (value.vertex1 + value.vertex2 + value.vertex3) / 3.0,
-            aabb_min: min_bounds,
-            aabb_max: max_bounds
+    #[test]
+    fn watertight_matches_moller_trumbore() {
+        let triangle = [Vector3::new(-1.0, -1.0, 2.0), Vector3::new(1.0, -1.0, 2.5), Vector3::new(0.0, 1.0, 3.0)];
+        let mut seed = 9;
+        for _ in 0..1000 {
+            let origin = pcg::random_vector3(&mut seed) * 2.0 - Vector3::repeat(1.0);
+            let target = pcg::random_vector3(&mut seed) * 3.0 - Vector3::new(1.5, 1.5, -1.0);
+            let ray = Ray::new(origin, (target - origin).normalize());
+            let expected = TriangleIntersection::MollerTrumbore.intersect(&triangle, &ray);
+            let watertight = intersect_triangle_watertight(&triangle, &ray);
+            assert_eq!(expected.is_some(), watertight.is_some());
+            if let (Some(expected), Some(watertight)) = (expected, watertight) {
+                assert!((expected.0 - watertight.0).abs() < 1e-4);
+                assert!((expected.1 - watertight.1).norm() < 1e-4);
+            }
         }
     }
-}
\ No newline at end of file
+}
@@ -7,4 +7,6 @@ pub mod render;
 pub mod material;
 pub mod camera;
 pub mod bvh;
+pub mod lights;
+pub mod media;
 pub mod gamma_lut;
\ No newline at end of file
@@ -0,0 +1,301 @@
+use std::f32::consts::PI;
+use nalgebra::Vector3;
+use crate::{math::{distribution::Distribution2D, extensions::luminance, pcg}, textures::texture::{Texture, TextureSamplingMode}};
+use super::sky::SunDisk;
+
+/// Least number of rows in the sampling distribution
+const MIN_DISTRIBUTION_ROWS: usize = 64;
+/// Rows of texture baked from gradient
+const GRADIENT_ROWS: usize = 65;
+
+/// Direction towards the environment with its radiance and solid angle pdf
+#[derive(Debug, Clone, Copy)]
+pub struct EnvironmentSample {
+    pub direction: Vector3<f32>,
+    pub radiance: Vector3<f32>,
+    pub pdf: f32,
+}
+
+/// Light coming from infinitely far sphere, described by equirectangular texture.
+/// Directions are importance sampled proportionally to texel luminance.
+#[derive(Debug)]
+pub struct EnvironmentLight {
+    texture: Texture<Vector3<f32>>,
+    /// Distribution over texture cells in `[0, 1)²` texture space
+    distribution: Distribution2D,
+    /// Small bright disk added on top of the texture, in texture space
+    sun: Option<SunDisk>,
+    /// Probability of sampling the sun instead of the texture
+    sun_probability: f32,
+    /// Rotation around y axis in radians, with its sine and cosine
+    yaw: f32,
+    yaw_sin_cos: (f32, f32),
+    /// Multiplier of texture radiance
+    pub intensity: f32,
+    /// Environment is seen by rays escaping from camera
+    pub visible_to_camera: bool,
+    /// Environment lights surfaces
+    pub visible_to_lighting: bool,
+}
+
+impl EnvironmentLight {
+    pub fn new(texture: Texture<Vector3<f32>>) -> Self {
+        // Nearest lookup maps `[0, 1]` to `size - 1` cells, the last row and column are only hit at the border
+        let columns = (texture.width() - 1).max(1);
+        let rows = (texture.height() - 1).max(1);
+        // Low resolution textures are split into more rows, so cosine of latitude is approximated well
+        let subdivisions = MIN_DISTRIBUTION_ROWS.div_ceil(rows);
+        let (buffer, width) = (texture.get_buffer_read(), texture.width());
+        let weights: Vec<f32> = (0..rows * subdivisions).flat_map(|row| {
+            // Cells near poles cover smaller solid angle
+            let cos_latitude = (PI * (0.5 - (row as f32 + 0.5) / (rows * subdivisions) as f32)).cos();
+            let texture_row = row / subdivisions;
+            (0..columns).map(move |column| luminance(&buffer[texture_row * width + column]) * cos_latitude)
+        }).collect();
+        let distribution = Distribution2D::new(&weights, columns, rows * subdivisions);
+        EnvironmentLight {
+            texture,
+            distribution,
+            sun: None,
+            sun_probability: 0.0,
+            yaw: 0.0,
+            yaw_sin_cos: (0.0, 1.0),
+            intensity: 1.0,
+            visible_to_camera: true,
+            visible_to_lighting: true,
+        }
+    }
+
+    /// Environment of single color
+    pub fn constant(color: Vector3<f32>) -> Self {
+        Self::new(Texture::from_buffer(vec![color], 1, 1, TextureSamplingMode::Repeat))
+    }
+
+    /// Vertical gradient from `bottom` color straight down to `top` color straight up
+    pub fn gradient(bottom: Vector3<f32>, top: Vector3<f32>) -> Self {
+        // Texture rows go from top to bottom, every row has color at its center
+        let buffer = (0..GRADIENT_ROWS).flat_map(|row| {
+            let v = (1.0 - (row as f32 + 0.5) / (GRADIENT_ROWS - 1) as f32).max(0.0);
+            let color = bottom.lerp(&top, v);
+            [color, color]
+        }).collect();
+        // Clamped, so straight down direction isn't wrapped to the top row
+        Self::new(Texture::from_buffer(buffer, 2, GRADIENT_ROWS, TextureSamplingMode::Clamp))
+    }
+
+    /// Adds `sun` on top of the texture, it is sampled with probability proportional to its power
+    pub fn with_sun(mut self, sun: SunDisk) -> Self {
+        let texture_power = self.distribution.integral() * 2.0 * PI * PI;
+        let sun_power = luminance(&sun.radiance) * sun.solid_angle();
+        self.sun_probability = if sun_power > 0.0 { sun_power / (sun_power + texture_power) } else { 0.0 };
+        self.sun = Some(sun);
+        self
+    }
+
+    #[inline]
+    pub fn sun(&self) -> Option<&SunDisk> {
+        self.sun.as_ref()
+    }
+
+    #[inline]
+    pub fn sun_probability(&self) -> f32 {
+        self.sun_probability
+    }
+
+    /// Rotation around y axis in radians
+    #[inline]
+    pub fn yaw(&self) -> f32 {
+        self.yaw
+    }
+
+    #[inline]
+    pub fn set_yaw(&mut self, yaw: f32) {
+        self.yaw = yaw;
+        self.yaw_sin_cos = yaw.sin_cos();
+    }
+
+    #[inline]
+    pub fn texture(&self) -> &Texture<Vector3<f32>> {
+        &self.texture
+    }
+
+    /// Radiance coming from unit `direction`
+    #[inline]
+    pub fn radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let local = self.to_local(direction);
+        let (u, v) = Self::uv_on_sphere(&local);
+        let sun = self.sun.filter(|x| x.contains(&local)).map_or(Vector3::zeros(), |x| x.radiance);
+        (self.texture.sample(-u, v) + sun) * self.intensity
+    }
+
+    /// Random direction towards the environment, `None` if the sample has zero pdf
+    #[inline]
+    pub fn sample(&self, seed: &mut u32) -> Option<EnvironmentSample> {
+        let local = match self.sun {
+            Some(sun) if pcg::random_f32(seed) < self.sun_probability => sun.sample(seed),
+            _ => {
+                let (x, y, pdf) = self.distribution.sample_continuous(pcg::random_f32(seed), pcg::random_f32(seed));
+                if pdf <= 0.0 {
+                    return None;
+                }
+                Self::texture_direction(x, y)
+            }
+        };
+        let direction = self.to_world(&local);
+        let pdf = self.local_pdf(&local);
+        (pdf > 0.0).then(|| EnvironmentSample { direction, radiance: self.radiance(&direction), pdf })
+    }
+
+    /// Solid angle pdf of sampling unit `direction` with [`Self::sample`]
+    #[inline]
+    pub fn pdf(&self, direction: &Vector3<f32>) -> f32 {
+        self.local_pdf(&self.to_local(direction))
+    }
+
+    #[inline]
+    fn local_pdf(&self, direction: &Vector3<f32>) -> f32 {
+        let cos_latitude = (1.0 - direction.y * direction.y).max(0.0).sqrt();
+        let texture_pdf = if cos_latitude > 0.0 {
+            // Same texture space as in `Texture::sample`
+            let (u, v) = Self::uv_on_sphere(direction);
+            let (x, y) = ((-u).rem_euclid(1.0), (1.0 - v).rem_euclid(1.0));
+            self.distribution.pdf(x, y) / (2.0 * PI * PI * cos_latitude)
+        } else {
+            0.0
+        };
+        match self.sun {
+            Some(sun) if sun.contains(direction) => {
+                self.sun_probability / sun.solid_angle() + (1.0 - self.sun_probability) * texture_pdf
+            },
+            _ => (1.0 - self.sun_probability) * texture_pdf,
+        }
+    }
+
+    /// Direction in texture space from point in `[0, 1]²` of the texture
+    #[inline]
+    pub fn texture_direction(x: f32, y: f32) -> Vector3<f32> {
+        let longitude = (0.5 - x) * 2.0 * PI;
+        let latitude = (0.5 - y) * PI;
+        let cos_latitude = latitude.cos();
+        Vector3::new(cos_latitude * longitude.cos(), latitude.sin(), cos_latitude * longitude.sin())
+    }
+
+    /// Rotates world direction to texture space
+    #[inline(always)]
+    fn to_local(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let (sin, cos) = self.yaw_sin_cos;
+        Vector3::new(cos * direction.x - sin * direction.z, direction.y, sin * direction.x + cos * direction.z)
+    }
+
+    /// Rotates direction from texture space to world
+    #[inline(always)]
+    fn to_world(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let (sin, cos) = self.yaw_sin_cos;
+        Vector3::new(cos * direction.x + sin * direction.z, direction.y, -sin * direction.x + cos * direction.z)
+    }
+
+    /// Equirectangular coordinates of unit direction
+    #[inline(always)]
+    pub fn uv_on_sphere(dir: &Vector3<f32>) -> (f32, f32) {
+        let u = 0.5 + f32::atan2(dir.z, dir.x) / (2.0 * PI);
+        let v = 0.5 + dir.y.asin() / PI;
+        (u.max(0.0), v.max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use nalgebra::Vector3;
+    use crate::{math::pcg, textures::texture::{Texture, TextureSamplingMode}};
+    use super::EnvironmentLight;
+
+    /// Dim random texture with a small bright sun
+    fn sun_environment() -> EnvironmentLight {
+        let (width, height) = (33, 17);
+        let mut seed = 7;
+        let mut buffer: Vec<Vector3<f32>> = (0..width * height).map(|_| pcg::random_vector3(&mut seed).abs() * 0.1).collect();
+        buffer[4 * width + 20] = Vector3::new(500.0, 450.0, 400.0);
+        EnvironmentLight::new(Texture::from_buffer(buffer, width, height, TextureSamplingMode::Repeat))
+    }
+
+    #[test]
+    fn sample_pdf_matches_lookup() {
+        let environment = sun_environment();
+        let mut seed = 11;
+        let (mut sun_samples, mut mismatches) = (0, 0);
+        for _ in 0..10_000 {
+            let sample = environment.sample(&mut seed).unwrap();
+            assert!((sample.direction.norm() - 1.0).abs() < 1e-4);
+            assert_eq!(sample.radiance, environment.radiance(&sample.direction));
+            // Direction may be rounded into neighbour cell only at cell borders
+            let pdf = environment.pdf(&sample.direction);
+            mismatches += ((sample.pdf - pdf).abs() > sample.pdf * 1e-2) as u32;
+            sun_samples += (sample.radiance.x > 100.0) as u32;
+        }
+        assert!(mismatches < 10, "{mismatches}");
+        // Most samples go towards the sun
+        assert!(sun_samples > 9_000);
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        for environment in [sun_environment(), EnvironmentLight::constant(Vector3::repeat(0.3))] {
+            let mut seed = 5;
+            let samples = 200_000;
+            let integral: f32 = (0..samples)
+                .map(|_| environment.pdf(&pcg::random_direction(&mut seed)) * 4.0 * PI)
+                .sum::<f32>() / samples as f32;
+            assert!((integral - 1.0).abs() < 0.05, "{integral}");
+        }
+        // Constant environment is sampled uniformly over the sphere
+        let environment = EnvironmentLight::constant(Vector3::repeat(0.3));
+        assert!((environment.pdf(&Vector3::new(0.6, 0.0, 0.8)) * 4.0 * PI - 1.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn importance_sampled_radiance() {
+        // Estimate of radiance integral over the sphere matches uniform estimate with less samples
+        let environment = sun_environment();
+        let mut seed = 13;
+        let uniform_samples = 2_000_000;
+        let uniform: Vector3<f32> = (0..uniform_samples)
+            .map(|_| environment.radiance(&pcg::random_direction(&mut seed)) * 4.0 * PI)
+            .sum::<Vector3<f32>>() / uniform_samples as f32;
+        let samples = 5_000;
+        let importance: Vector3<f32> = (0..samples)
+            .map(|_| environment.sample(&mut seed).map(|x| x.radiance / x.pdf).unwrap_or_default())
+            .sum::<Vector3<f32>>() / samples as f32;
+        assert!((importance - uniform).norm() < uniform.norm() * 0.05, "{importance} {uniform}");
+    }
+
+    #[test]
+    fn rotated_environment() {
+        let mut environment = sun_environment();
+        let mut seed = 17;
+        let sun = environment.sample(&mut seed).unwrap();
+        environment.set_yaw(90f32.to_radians());
+        environment.intensity = 2.0;
+        // Sun moves around y axis
+        let rotated = Vector3::new(sun.direction.z, sun.direction.y, -sun.direction.x);
+        assert_eq!(environment.radiance(&rotated), sun.radiance * 2.0);
+        assert!((environment.pdf(&rotated) - sun.pdf).abs() < sun.pdf * 1e-3);
+        for _ in 0..100 {
+            let sample = environment.sample(&mut seed).unwrap();
+            assert!((environment.pdf(&sample.direction) - sample.pdf).abs() < sample.pdf * 1e-2);
+            assert_eq!(sample.radiance, environment.radiance(&sample.direction));
+        }
+    }
+
+    #[test]
+    fn gradient_environment() {
+        let environment = EnvironmentLight::gradient(Vector3::zeros(), Vector3::new(1.0, 0.5, 0.0));
+        assert!((environment.radiance(&Vector3::y()) - Vector3::new(1.0, 0.5, 0.0)).norm() < 0.02);
+        assert!(environment.radiance(&-Vector3::y()).norm() < 0.02);
+        assert!((environment.radiance(&Vector3::x()) - Vector3::new(0.5, 0.25, 0.0)).norm() < 0.02);
+        // Upper hemisphere is sampled more often
+        let mut seed = 19;
+        let upper = (0..1000).filter(|_| environment.sample(&mut seed).unwrap().direction.y > 0.0).count();
+        assert!(upper > 600);
+    }
+}
@@ -0,0 +1,490 @@
+use std::f32::consts::PI;
+use nalgebra::Vector3;
+use crate::{entity::{geometry::Geometry, triangle::Triangle, mesh::MeshTriangle}, material::EmissionSides, math::{distribution::Distribution1D, extensions::luminance}};
+
+/// Cone of directions around unit `axis`, `cos_theta = -1` contains all directions
+#[derive(Debug, Clone, Copy)]
+pub struct DirectionCone {
+    pub axis: Vector3<f32>,
+    pub cos_theta: f32,
+}
+
+impl DirectionCone {
+    #[inline]
+    pub fn new(axis: Vector3<f32>, cos_theta: f32) -> Self {
+        DirectionCone { axis, cos_theta }
+    }
+
+    #[inline]
+    pub fn entire_sphere() -> Self {
+        DirectionCone { axis: Vector3::z(), cos_theta: -1.0 }
+    }
+
+    /// Smallest cone containing both cones
+    pub fn union(&self, other: &DirectionCone) -> DirectionCone {
+        let theta_a = self.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_b = other.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_d = self.axis.dot(&other.axis).clamp(-1.0, 1.0).acos();
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return *self;
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return *other;
+        }
+        let theta_o = (theta_a + theta_d + theta_b) * 0.5;
+        if theta_o >= PI {
+            return Self::entire_sphere();
+        }
+        // Rotate own axis towards the other one
+        let rotation_axis = self.axis.cross(&other.axis);
+        if rotation_axis.norm_squared() < 1e-12 {
+            return Self::entire_sphere();
+        }
+        let rotation = nalgebra::Rotation3::from_axis_angle(&nalgebra::Unit::new_normalize(rotation_axis), theta_o - theta_a);
+        DirectionCone::new(rotation * self.axis, theta_o.cos())
+    }
+}
+
+/// Spatial and directional bounds of emitters with their total power.
+/// "Importance Sampling of Many Lights with Adaptive Tree Splitting", Conty Estevez, Kulla, 2018.
+#[derive(Debug, Clone, Copy)]
+pub struct LightBounds {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+    pub power: f32,
+    /// Bounds emitter normals
+    pub normals: DirectionCone,
+    /// Cosine of the largest emission angle from a normal
+    pub cos_theta_e: f32,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    pub fn union(&self, other: &LightBounds) -> LightBounds {
+        if self.power <= 0.0 {
+            return *other;
+        }
+        if other.power <= 0.0 {
+            return *self;
+        }
+        LightBounds {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+            power: self.power + other.power,
+            normals: self.normals.union(&other.normals),
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    #[inline]
+    pub fn centroid(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Conservative estimate of light arriving at `point` with surface `normal`
+    pub fn importance(&self, point: &Vector3<f32>, normal: &Vector3<f32>) -> f32 {
+        if self.power <= 0.0 {
+            return 0.0;
+        }
+        let center = self.centroid();
+        let to_point = point - center;
+        let distance_squared = to_point.norm_squared();
+        let radius_squared = (self.max - center).norm_squared();
+        // Distance is clamped to half of the radius, so points inside the bounds don't get infinite importance
+        let clamped_distance_squared = distance_squared.max(radius_squared * 0.25);
+        let wi = if distance_squared > 0.0 { to_point / distance_squared.sqrt() } else { Vector3::zeros() };
+
+        let cos_theta_w = self.normals.axis.dot(&wi);
+        let cos_theta_w = if self.two_sided { cos_theta_w.abs() } else { cos_theta_w };
+        // Angle of the bounding sphere seen from the point, everything is possible inside of it
+        let theta_b = if distance_squared > radius_squared { (1.0 - radius_squared / distance_squared).sqrt().acos() } else { PI };
+
+        // Smallest angle between emitter normals and direction to the point
+        let theta_w = cos_theta_w.clamp(-1.0, 1.0).acos();
+        let theta_o = self.normals.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta = (theta_w - theta_o - theta_b).max(0.0);
+        if theta.cos() <= self.cos_theta_e {
+            return 0.0;
+        }
+        // Smallest angle between surface normal and direction to the light, zero normal is used in media
+        let cos_theta_i = if *normal == Vector3::zeros() {
+            1.0
+        } else {
+            let theta_i = wi.dot(normal).abs().clamp(-1.0, 1.0).acos();
+            (theta_i - theta_b).max(0.0).cos()
+        };
+        self.power * theta.cos() * cos_theta_i / clamped_distance_squared
+    }
+}
+
+/// Node of [`LightTree`], children of interior node are adjacent
+#[derive(Debug, Clone, Copy)]
+struct LightNode {
+    bounds: LightBounds,
+    /// Range of lights in `LightTree::order`, node is a leaf if it has single light
+    first: u32,
+    count: u32,
+    /// Index of the first child for interior nodes
+    child: u32,
+}
+
+/// Bvh over emitters, traversed stochastically by importance of nodes at the shading point
+#[derive(Debug, Clone, Default)]
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+    /// Lights in order of tree leaves
+    order: Vec<u32>,
+    /// Position of every light in `order`
+    position: Vec<u32>,
+}
+
+impl LightTree {
+    pub fn new(bounds: &[LightBounds]) -> Self {
+        let mut tree = LightTree {
+            nodes: vec![],
+            order: (0..bounds.len() as u32).collect(),
+            position: vec![0; bounds.len()],
+        };
+        if bounds.is_empty() {
+            return tree;
+        }
+        tree.nodes.push(LightNode { bounds: bounds[0], first: 0, count: 0, child: 0 });
+        tree.build(bounds, 0, 0, bounds.len());
+        for (position, light) in tree.order.iter().enumerate() {
+            tree.position[*light as usize] = position as u32;
+        }
+        tree
+    }
+
+    fn build(&mut self, bounds: &[LightBounds], node: usize, start: usize, end: usize) {
+        let lights = &mut self.order[start..end];
+        let node_bounds = lights.iter().skip(1).fold(bounds[lights[0] as usize], |x, i| x.union(&bounds[*i as usize]));
+        self.nodes[node] = LightNode { bounds: node_bounds, first: start as u32, count: (end - start) as u32, child: 0 };
+        if end - start == 1 {
+            return;
+        }
+        // Split at median along the longest axis of centroids
+        let (min, max) = lights.iter().fold((Vector3::repeat(f32::INFINITY), Vector3::repeat(f32::NEG_INFINITY)), |(min, max), i| {
+            let centroid = bounds[*i as usize].centroid();
+            (min.inf(&centroid), max.sup(&centroid))
+        });
+        let axis = (max - min).imax();
+        lights.sort_by(|a, b| bounds[*a as usize].centroid()[axis].total_cmp(&bounds[*b as usize].centroid()[axis]));
+
+        let child = self.nodes.len();
+        self.nodes[node].child = child as u32;
+        self.nodes.extend_from_slice(&[self.nodes[node]; 2]);
+        let middle = (start + end) / 2;
+        self.build(bounds, child, start, middle);
+        self.build(bounds, child + 1, middle, end);
+    }
+
+    /// Probabilities of picking left and right child of interior node
+    #[inline]
+    fn child_probabilities(&self, node: &LightNode, point: &Vector3<f32>, normal: &Vector3<f32>) -> Option<(f32, f32)> {
+        let left = self.nodes[node.child as usize].bounds.importance(point, normal);
+        let right = self.nodes[node.child as usize + 1].bounds.importance(point, normal);
+        (left + right > 0.0).then(|| (left / (left + right), right / (left + right)))
+    }
+
+    /// Picks light with uniform `u`, returns the light and its probability
+    pub fn sample(&self, point: &Vector3<f32>, normal: &Vector3<f32>, mut u: f32) -> Option<(usize, f32)> {
+        let mut node = self.nodes.first()?;
+        if node.bounds.importance(point, normal) <= 0.0 {
+            return None;
+        }
+        let mut pmf = 1.0;
+        while node.count > 1 {
+            let (left, right) = self.child_probabilities(node, point, normal)?;
+            // Reuse `u` for the next level
+            if u < left {
+                u = (u / left).min(1.0 - f32::EPSILON);
+                pmf *= left;
+                node = &self.nodes[node.child as usize];
+            } else {
+                u = ((u - left) / right).min(1.0 - f32::EPSILON);
+                pmf *= right;
+                node = &self.nodes[node.child as usize + 1];
+            }
+        }
+        Some((self.order[node.first as usize] as usize, pmf))
+    }
+
+    /// Probability of [`Self::sample`] picking `light`
+    pub fn pmf(&self, point: &Vector3<f32>, normal: &Vector3<f32>, light: usize) -> f32 {
+        let Some(mut node) = self.nodes.first() else {
+            return 0.0;
+        };
+        if node.bounds.importance(point, normal) <= 0.0 {
+            return 0.0;
+        }
+        let position = self.position[light];
+        let mut pmf = 1.0;
+        while node.count > 1 {
+            let Some((left, right)) = self.child_probabilities(node, point, normal) else {
+                return 0.0;
+            };
+            let left_node = &self.nodes[node.child as usize];
+            if position < left_node.first + left_node.count {
+                pmf *= left;
+                node = left_node;
+            } else {
+                pmf *= right;
+                node = &self.nodes[node.child as usize + 1];
+            }
+        }
+        pmf
+    }
+}
+
+/// Strategy of picking emissive triangle for explicit light sampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LightSelection {
+    /// Proportionally to emitted power
+    Power,
+    /// By estimated contribution at the shading point with [`LightTree`]
+    #[default]
+    Tree,
+}
+
+/// Point on emissive triangle picked for the shading point
+#[derive(Debug, Clone, Copy)]
+pub struct EmitterSample {
+    /// Unit direction from the shading point towards the emitter
+    pub direction: Vector3<f32>,
+    pub distance: f32,
+    pub emission: Vector3<f32>,
+    /// Solid angle pdf including the probability of picking the triangle
+    pub pdf: f32,
+}
+
+/// Emissive triangles of the scene with structures for picking them
+#[derive(Debug, Clone, Default)]
+pub struct LightSampler {
+    pub selection: LightSelection,
+    /// Indices of emissive triangles in `Geometry::triangles`
+    triangles: Vec<usize>,
+    areas: Vec<f32>,
+    /// Index of the first triangle of every mesh in `Geometry::triangles`
+    first_triangle: Vec<u32>,
+    /// Light index of every triangle in `Geometry::triangles`, `u32::MAX` for not emissive ones
+    light_of_triangle: Vec<u32>,
+    power: Option<Distribution1D>,
+    tree: LightTree,
+}
+
+impl LightSampler {
+    /// `light_objects` are emissive triangles, see [`crate::scene::SceneData::light_objects`]
+    pub fn new(geometry: &Geometry, light_objects: &[usize]) -> Self {
+        let mut first_triangle = Vec::with_capacity(geometry.meshes.len());
+        let mut count = 0;
+        for mesh in &geometry.meshes {
+            first_triangle.push(count);
+            count += mesh.triangle_count() as u32;
+        }
+        let mut light_of_triangle = vec![u32::MAX; geometry.triangles.len()];
+        let mut areas = Vec::with_capacity(light_objects.len());
+        // Emission averaged over textures, once per mesh
+        let mut mesh_emission: Vec<Option<f32>> = vec![None; geometry.meshes.len()];
+        let bounds: Vec<LightBounds> = light_objects.iter().enumerate().map(|(light, triangle_index)| {
+            light_of_triangle[*triangle_index] = light as u32;
+            let triangle = geometry.triangle(&geometry.triangles[*triangle_index]);
+            let [vertex1, vertex2, vertex3] = triangle.vertices();
+            let cross = (vertex2 - vertex1).cross(&(vertex3 - vertex1));
+            let area = cross.norm() * 0.5;
+            areas.push(area);
+            let material = triangle.material();
+            let emission = *mesh_emission[geometry.triangles[*triangle_index].mesh as usize]
+                .get_or_insert_with(|| luminance(&material.average_emission()));
+            // Lambertian emitter on one or both sides
+            let two_sided = material.emission_sides == EmissionSides::Both;
+            let power = emission * area * PI * if two_sided { 2.0 } else { 1.0 };
+            LightBounds {
+                min: vertex1.inf(&vertex2).inf(&vertex3),
+                max: vertex1.sup(&vertex2).sup(&vertex3),
+                power: if power.is_finite() { power } else { 0.0 },
+                normals: DirectionCone::new(cross.try_normalize(0.0).unwrap_or(Vector3::z()), 1.0),
+                cos_theta_e: 0.0,
+                two_sided,
+            }
+        }).collect();
+        let power = (!bounds.is_empty()).then(|| Distribution1D::new(bounds.iter().map(|x| x.power).collect()));
+        LightSampler {
+            selection: LightSelection::default(),
+            triangles: light_objects.to_vec(),
+            areas,
+            first_triangle,
+            light_of_triangle,
+            power,
+            tree: LightTree::new(&bounds),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.triangles.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.triangles.is_empty()
+    }
+
+    /// Light index of `triangle`, `None` if it isn't emissive
+    #[inline]
+    pub fn light_index(&self, triangle: &Triangle) -> Option<usize> {
+        let index = *self.first_triangle.get(triangle.mesh as usize)? as usize + triangle.primitive as usize;
+        self.light_of_triangle.get(index).filter(|x| **x != u32::MAX).map(|x| *x as usize)
+    }
+
+    /// Picks light for shading `point` with uniform `u`, returns the light and its probability
+    #[inline]
+    pub fn pick(&self, point: &Vector3<f32>, normal: &Vector3<f32>, u: f32) -> Option<(usize, f32)> {
+        match self.selection {
+            LightSelection::Power => self.power.as_ref().map(|x| {
+                let (_, pdf, index) = x.sample_continuous(u);
+                (index, pdf / x.count() as f32)
+            }),
+            LightSelection::Tree => self.tree.sample(point, normal, u),
+        }
+    }
+
+    /// Probability of [`Self::pick`] returning `light`
+    #[inline]
+    pub fn pmf(&self, point: &Vector3<f32>, normal: &Vector3<f32>, light: usize) -> f32 {
+        match self.selection {
+            LightSelection::Power => self.power.as_ref().map_or(0.0, |x| x.pdf(light) / x.count() as f32),
+            LightSelection::Tree => self.tree.pmf(point, normal, light),
+        }
+    }
+
+    /// Random point on random emitter seen from shading `point`
+    pub fn sample(&self, geometry: &Geometry, point: &Vector3<f32>, normal: &Vector3<f32>, seed: &mut u32) -> Option<EmitterSample> {
+        let (light, pmf) = self.pick(point, normal, crate::math::pcg::random_f32(seed))?;
+        let triangle = geometry.triangle(&geometry.triangles[self.triangles[light]]);
+        let bar_coords = MeshTriangle::random_bar_coords(seed);
+        let to_light = triangle.point(&bar_coords) - point;
+        let distance = to_light.norm();
+        if distance <= 0.0 {
+            return None;
+        }
+        let direction = to_light / distance;
+        let front_face = triangle.plane_normal().dot(&direction) <= 0.0;
+        let emission = triangle.material().emitted(&triangle.uv_coords(&bar_coords), front_face, &-direction);
+        let pdf = pmf * self.area_to_solid_angle(geometry, light, &direction, distance);
+        (pdf > 0.0 && pdf.is_finite() && emission != Vector3::zeros()).then_some(EmitterSample { direction, distance, emission, pdf })
+    }
+
+    /// Solid angle pdf of sampling emitter `light` hit along `direction` at `distance`
+    #[inline]
+    pub fn pdf(&self, geometry: &Geometry, point: &Vector3<f32>, normal: &Vector3<f32>, light: usize,
+        direction: &Vector3<f32>, distance: f32) -> f32 {
+        self.pmf(point, normal, light) * self.area_to_solid_angle(geometry, light, direction, distance)
+    }
+
+    /// Converts uniform area density on triangle to solid angle density
+    #[inline]
+    fn area_to_solid_angle(&self, geometry: &Geometry, light: usize, direction: &Vector3<f32>, distance: f32) -> f32 {
+        let triangle = geometry.triangle(&geometry.triangles[self.triangles[light]]);
+        let cos = triangle.plane_normal().dot(direction).abs();
+        if cos <= 0.0 {
+            return 0.0;
+        }
+        distance * distance / (cos * self.areas[light])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use nalgebra::{Vector3, Vector2};
+    use crate::{entity::{geometry::Geometry, mesh::Mesh}, material::{Material, EmissionSides}, math::pcg};
+    use super::{LightSampler, LightSelection, DirectionCone};
+
+    /// Row of small quads along x facing down, emission grows with x
+    fn strip(count: usize, sides: EmissionSides) -> Geometry {
+        let meshes = (0..count).map(|i| {
+            let x = i as f32;
+            let emission = Vector3::repeat(1.0 + i as f32);
+            Mesh::new(
+                vec![
+                    Vector3::new(x, 2.0, 0.0),
+                    Vector3::new(x + 0.5, 2.0, 0.0),
+                    Vector3::new(x + 0.5, 2.0, 0.5),
+                    Vector3::new(x, 2.0, 0.5),
+                ],
+                vec![],
+                Vec::<Vector2<f32>>::new(),
+                vec![[0, 1, 2], [0, 2, 3]],
+                Arc::new(Material::new(Vector3::zeros(), emission, 1.0, 0.0, None).with_emission_sides(sides)),
+            )
+        }).collect();
+        Geometry::new(meshes)
+    }
+
+    #[test]
+    fn cone_union() {
+        let a = DirectionCone::new(Vector3::x(), 1.0);
+        let b = DirectionCone::new(Vector3::y(), 1.0);
+        let union = a.union(&b);
+        assert!((union.axis - Vector3::new(1.0, 1.0, 0.0).normalize()).norm() < 1e-5);
+        assert!((union.cos_theta - 45f32.to_radians().cos()).abs() < 1e-5);
+        assert_eq!(a.union(&DirectionCone::new(-Vector3::x(), 1.0)).cos_theta, -1.0);
+        assert_eq!(union.union(&a).cos_theta, union.cos_theta);
+    }
+
+    #[test]
+    fn pmf_matches_sampling() {
+        let geometry = strip(16, EmissionSides::Both);
+        let light_objects: Vec<usize> = (0..geometry.triangles.len()).collect();
+        let mut sampler = LightSampler::new(&geometry, &light_objects);
+        let mut seed = 31;
+        for selection in [LightSelection::Power, LightSelection::Tree] {
+            sampler.selection = selection;
+            for _ in 0..20 {
+                let point = Vector3::new(pcg::random_f32(&mut seed) * 16.0, 0.0, pcg::random_f32(&mut seed));
+                let normal = Vector3::y();
+                // Probabilities of all lights sum to one
+                let sum: f32 = (0..sampler.len()).map(|x| sampler.pmf(&point, &normal, x)).sum();
+                assert!((sum - 1.0).abs() < 1e-4, "{sum}");
+                let (light, pmf) = sampler.pick(&point, &normal, pcg::random_f32(&mut seed)).unwrap();
+                assert!((sampler.pmf(&point, &normal, light) - pmf).abs() < 1e-6);
+                let sample = sampler.sample(&geometry, &point, &normal, &mut seed).unwrap();
+                assert!(sample.direction.y > 0.0);
+            }
+        }
+        assert_eq!(sampler.light_index(&geometry.triangles[5]), Some(5));
+    }
+
+    #[test]
+    fn selection_by_power_and_distance() {
+        let geometry = strip(16, EmissionSides::Both);
+        let light_objects: Vec<usize> = (0..geometry.triangles.len()).collect();
+        let mut sampler = LightSampler::new(&geometry, &light_objects);
+        let point = Vector3::new(0.25, 1.5, 0.25);
+        let normal = Vector3::y();
+        // The brightest light is picked most by power, the closest one by the tree
+        sampler.selection = LightSelection::Power;
+        assert!(sampler.pmf(&point, &normal, 31) > sampler.pmf(&point, &normal, 0) * 10.0);
+        sampler.selection = LightSelection::Tree;
+        assert!(sampler.pmf(&point, &normal, 0) > sampler.pmf(&point, &normal, 31) * 10.0);
+    }
+
+    #[test]
+    fn one_sided_emitters() {
+        let geometry = strip(4, EmissionSides::Front);
+        let light_objects: Vec<usize> = (0..geometry.triangles.len()).collect();
+        let sampler = LightSampler::new(&geometry, &light_objects);
+        let mut seed = 37;
+        // Quads emit only downwards
+        let below = Vector3::new(1.0, 0.0, 0.25);
+        let above = Vector3::new(1.0, 4.0, 0.25);
+        for _ in 0..100 {
+            let sample = sampler.sample(&geometry, &below, &Vector3::y(), &mut seed).unwrap();
+            assert!(sample.emission.x > 0.0);
+            assert!(sampler.sample(&geometry, &above, &-Vector3::y(), &mut seed).is_none());
+        }
+        assert!((0..sampler.len()).all(|x| sampler.pmf(&above, &-Vector3::y(), x) == 0.0));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod environment;
+pub mod sky;
+pub mod punctual;
+pub mod light_tree;
@@ -0,0 +1,228 @@
+use std::{f32::consts::PI, sync::Arc};
+use nalgebra::Vector3;
+use crate::loaders::ies_loader::IesProfile;
+use crate::math::{extensions::{luminance, orthonormal_basis}, pcg};
+
+/// Light arriving at a point from a punctual light
+#[derive(Debug, Clone, Copy)]
+pub struct LightSample {
+    /// Unit direction from the point towards the light
+    pub direction: Vector3<f32>,
+    /// Distance to the sampled light position, infinite for directional lights
+    pub distance: f32,
+    /// Irradiance on surface perpendicular to `direction`
+    pub irradiance: Vector3<f32>,
+}
+
+/// Light which can't be hit by rays and is evaluated only with shadow rays
+#[derive(Debug, Clone)]
+pub enum PunctualLight {
+    /// Emits `intensity` in all directions, sphere of `radius` gives soft shadows.
+    /// Optional profile has nadir along -y.
+    Point {
+        position: Vector3<f32>,
+        intensity: Vector3<f32>,
+        radius: f32,
+        profile: Option<Arc<IesProfile>>,
+    },
+    /// Point light limited to cone around `direction`, falling off from inner to outer cone.
+    /// Optional profile has nadir along `direction`.
+    Spot {
+        position: Vector3<f32>,
+        direction: Vector3<f32>,
+        intensity: Vector3<f32>,
+        cos_inner: f32,
+        cos_outer: f32,
+        radius: f32,
+        profile: Option<Arc<IesProfile>>,
+    },
+    /// Parallel light going along `direction`, spread over cone of angular radius
+    Directional {
+        direction: Vector3<f32>,
+        irradiance: Vector3<f32>,
+        cos_radius: f32,
+    },
+}
+
+impl PunctualLight {
+    #[inline]
+    pub fn point(position: Vector3<f32>, intensity: Vector3<f32>, radius: f32) -> Self {
+        PunctualLight::Point { position, intensity, radius, profile: None }
+    }
+
+    /// Spot light with cone angles in radians measured from `direction`
+    #[inline]
+    pub fn spot(position: Vector3<f32>, direction: Vector3<f32>, intensity: Vector3<f32>,
+        inner_angle: f32, outer_angle: f32, radius: f32) -> Self {
+        let outer_angle = outer_angle.max(inner_angle);
+        PunctualLight::Spot {
+            position,
+            direction: direction.normalize(),
+            intensity,
+            cos_inner: inner_angle.cos(),
+            cos_outer: outer_angle.cos(),
+            radius,
+            profile: None,
+        }
+    }
+
+    /// Directional light with angular diameter in radians
+    #[inline]
+    pub fn directional(direction: Vector3<f32>, irradiance: Vector3<f32>, angular_diameter: f32) -> Self {
+        PunctualLight::Directional { direction: direction.normalize(), irradiance, cos_radius: (angular_diameter * 0.5).cos() }
+    }
+
+    /// Modulates intensity of point or spot light by candela distribution,
+    /// `intensity` then is the intensity in the brightest direction
+    #[inline]
+    pub fn with_profile(mut self, ies_profile: Option<Arc<IesProfile>>) -> Self {
+        match &mut self {
+            PunctualLight::Point { profile, .. } | PunctualLight::Spot { profile, .. } => *profile = ies_profile,
+            PunctualLight::Directional { .. } => (),
+        }
+        self
+    }
+
+    /// Approximate emitted power, used to compare lights
+    #[inline]
+    pub fn power(&self) -> f32 {
+        match self {
+            PunctualLight::Point { intensity, .. } => 4.0 * PI * luminance(intensity),
+            PunctualLight::Spot { intensity, cos_inner, cos_outer, .. } => {
+                2.0 * PI * (1.0 - 0.5 * (cos_inner + cos_outer)) * luminance(intensity)
+            },
+            // Doesn't depend on the scene size, so only relative to other directional lights
+            PunctualLight::Directional { irradiance, .. } => luminance(irradiance),
+        }
+    }
+
+    /// Light arriving at `point`, random position on the light is picked for lights with size
+    pub fn sample(&self, point: &Vector3<f32>, seed: &mut u32) -> Option<LightSample> {
+        match self {
+            PunctualLight::Point { position, intensity, radius, profile } => {
+                let relative = match profile {
+                    Some(profile) => profile.relative_intensity(&(point - position).normalize()),
+                    None => 1.0,
+                };
+                if relative <= 0.0 {
+                    return None;
+                }
+                Self::sample_position(point, position, *radius, seed).map(|(direction, distance)| LightSample {
+                    direction,
+                    distance,
+                    irradiance: intensity * (relative / (distance * distance)),
+                })
+            },
+            PunctualLight::Spot { position, direction: spot_direction, intensity, cos_inner, cos_outer, radius, profile } => {
+                let emitted = (point - position).normalize();
+                let mut falloff = smoothstep(*cos_outer, *cos_inner, emitted.dot(spot_direction));
+                if let Some(profile) = profile {
+                    // Luminaire space with nadir along the spot direction
+                    let (tangent, bitangent) = orthonormal_basis(spot_direction);
+                    let local = Vector3::new(emitted.dot(&tangent), -emitted.dot(spot_direction), emitted.dot(&bitangent));
+                    falloff *= profile.relative_intensity(&local);
+                }
+                if falloff <= 0.0 {
+                    return None;
+                }
+                Self::sample_position(point, position, *radius, seed).map(|(direction, distance)| LightSample {
+                    direction,
+                    distance,
+                    irradiance: intensity * (falloff / (distance * distance)),
+                })
+            },
+            &PunctualLight::Directional { direction, irradiance, cos_radius } => {
+                // Uniform direction in the cone around the direction towards the light
+                let cos_theta = 1.0 - pcg::random_f32(seed) * (1.0 - cos_radius);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * pcg::random_f32(seed);
+                let (tangent, bitangent) = orthonormal_basis(&-direction);
+                let direction = tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) - direction * cos_theta;
+                Some(LightSample { direction: direction.normalize(), distance: f32::INFINITY, irradiance })
+            },
+        }
+    }
+
+    /// Direction and distance to random point on the sphere of `radius` around `position`
+    #[inline]
+    fn sample_position(point: &Vector3<f32>, position: &Vector3<f32>, radius: f32, seed: &mut u32) -> Option<(Vector3<f32>, f32)> {
+        let position = if radius > 0.0 { position + pcg::random_direction(seed) * radius } else { *position };
+        let to_light = position - point;
+        let distance = to_light.norm();
+        (distance > 0.0).then(|| (to_light / distance, distance))
+    }
+}
+
+#[inline(always)]
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge1 <= edge0 {
+        return if x >= edge0 { 1.0 } else { 0.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use nalgebra::Vector3;
+    use crate::loaders::ies_loader::IesProfile;
+    use super::PunctualLight;
+
+    #[test]
+    fn point_and_spot_lights() {
+        let mut seed = 1;
+        let point = PunctualLight::point(Vector3::new(0.0, 2.0, 0.0), Vector3::repeat(8.0), 0.0);
+        let sample = point.sample(&Vector3::zeros(), &mut seed).unwrap();
+        assert_eq!(sample.direction, Vector3::y());
+        assert_eq!(sample.distance, 2.0);
+        assert_eq!(sample.irradiance, Vector3::repeat(2.0));
+        // Light with radius is sampled on its sphere
+        let sphere = PunctualLight::point(Vector3::new(0.0, 2.0, 0.0), Vector3::repeat(8.0), 0.5);
+        for _ in 0..100 {
+            let sample = sphere.sample(&Vector3::zeros(), &mut seed).unwrap();
+            assert!(sample.distance >= 1.5 - 1e-5 && sample.distance <= 2.5 + 1e-5);
+        }
+
+        let spot = PunctualLight::spot(Vector3::new(0.0, 2.0, 0.0), -Vector3::y(), Vector3::repeat(8.0),
+            20f32.to_radians(), 30f32.to_radians(), 0.0);
+        assert_eq!(spot.sample(&Vector3::zeros(), &mut seed).unwrap().irradiance, Vector3::repeat(2.0));
+        assert!(spot.sample(&Vector3::new(2.0, 0.0, 0.0), &mut seed).is_none());
+        // Between inner and outer cone light falls off
+        let edge = Vector3::new(2.0 * 25f32.to_radians().tan(), 0.0, 0.0);
+        let irradiance = spot.sample(&edge, &mut seed).unwrap().irradiance.x;
+        assert!(irradiance > 0.0 && irradiance < 8.0 / edge.norm_squared());
+        assert!(spot.power() < point.power());
+    }
+
+    #[test]
+    fn directional_light() {
+        let mut seed = 3;
+        let sun = PunctualLight::directional(Vector3::new(0.0, -1.0, 0.0), Vector3::repeat(3.0), 2f32.to_radians());
+        for _ in 0..100 {
+            let sample = sun.sample(&Vector3::new(5.0, 0.0, 1.0), &mut seed).unwrap();
+            assert!(sample.direction.dot(&Vector3::y()) >= 1f32.to_radians().cos() - 1e-6);
+            assert_eq!(sample.distance, f32::INFINITY);
+            assert_eq!(sample.irradiance, Vector3::repeat(3.0));
+        }
+    }
+
+    #[test]
+    fn lights_with_profile() {
+        let mut seed = 5;
+        // Profile with peak at 45 degrees from nadir and nothing at nadir
+        let profile = Arc::new(IesProfile::parse("TILT=NONE\n1 1000 1 3 1 1 2 0 0 0\n1 1 10\n0 45 90\n0\n0 100 0").unwrap());
+        let point = PunctualLight::point(Vector3::new(0.0, 2.0, 0.0), Vector3::repeat(8.0), 0.0)
+            .with_profile(Some(profile.clone()));
+        assert!(point.sample(&Vector3::zeros(), &mut seed).is_none());
+        let sample = point.sample(&Vector3::new(2.0, 0.0, 0.0), &mut seed).unwrap();
+        assert!((sample.irradiance.x - 1.0).abs() < 1e-4);
+
+        // Spot light pointing along +x has nadir of the profile along +x
+        let spot = PunctualLight::spot(Vector3::zeros(), Vector3::x(), Vector3::repeat(8.0),
+            60f32.to_radians(), 60f32.to_radians(), 0.0).with_profile(Some(profile));
+        assert!(spot.sample(&Vector3::new(2.0, 0.0, 0.0), &mut seed).is_none());
+        let sample = spot.sample(&Vector3::new(2.0, 0.0, 2.0), &mut seed).unwrap();
+        assert!((sample.irradiance.x - 1.0).abs() < 1e-4);
+    }
+}
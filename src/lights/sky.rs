@@ -0,0 +1,239 @@
+use std::f32::consts::PI;
+use nalgebra::Vector3;
+use crate::{math::{extensions::orthonormal_basis, pcg}, textures::texture::{Texture, TextureSamplingMode}};
+use super::environment::EnvironmentLight;
+
+/// Resolution of texture the sky is baked into
+const SKY_TEXTURE_WIDTH: usize = 1024;
+const SKY_TEXTURE_HEIGHT: usize = 512;
+/// Converts luminance in kcd/m² to scene radiance units, so overcast zenith is close to one
+const SKY_RADIANCE_SCALE: f32 = 0.05;
+/// Luminance of the sun disk outside of atmosphere in kcd/m²
+const SUN_LUMINANCE: f32 = 1.6e6;
+/// Angular radius of the sun seen from the earth in radians
+const SUN_ANGULAR_RADIUS: f32 = 0.004_65;
+
+/// Disk of constant radiance on the environment, sampled uniformly over its cone
+#[derive(Debug, Clone, Copy)]
+pub struct SunDisk {
+    /// Unit direction towards the center of the disk
+    pub direction: Vector3<f32>,
+    pub radiance: Vector3<f32>,
+    cos_radius: f32,
+}
+
+impl SunDisk {
+    #[inline]
+    pub fn new(direction: Vector3<f32>, radiance: Vector3<f32>, angular_radius: f32) -> Self {
+        SunDisk { direction: direction.normalize(), radiance, cos_radius: angular_radius.cos() }
+    }
+
+    #[inline]
+    pub fn solid_angle(&self) -> f32 {
+        2.0 * PI * (1.0 - self.cos_radius)
+    }
+
+    /// Returns true if unit `direction` points into the disk
+    #[inline]
+    pub fn contains(&self, direction: &Vector3<f32>) -> bool {
+        direction.dot(&self.direction) >= self.cos_radius
+    }
+
+    /// Uniform direction inside the disk cone, its pdf is `1 / solid_angle`
+    #[inline]
+    pub fn sample(&self, seed: &mut u32) -> Vector3<f32> {
+        let cos_theta = 1.0 - pcg::random_f32(seed) * (1.0 - self.cos_radius);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * pcg::random_f32(seed);
+        let (tangent, bitangent) = orthonormal_basis(&self.direction);
+        (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + self.direction * cos_theta).normalize()
+    }
+}
+
+/// Coefficients of Perez sky luminance distribution
+#[derive(Debug, Clone, Copy)]
+struct Perez([f32; 5]);
+
+impl Perez {
+    /// Relative luminance at zenith angle `theta` and angle `gamma` from the sun
+    #[inline]
+    fn evaluate(&self, cos_theta: f32, gamma: f32) -> f32 {
+        let [a, b, c, d, e] = self.0;
+        let cos_gamma = gamma.cos();
+        (1.0 + a * (b / cos_theta.max(0.01)).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+    }
+}
+
+/// Analytic daylight from "A Practical Analytic Model for Daylight", Preetham, Shirley, Smits, 1999.
+/// Ground below horizon is lambertian, lit by the sky and the sun.
+#[derive(Debug, Clone, Copy)]
+pub struct SkyModel {
+    /// Unit direction towards the sun
+    pub sun_direction: Vector3<f32>,
+    /// Haziness of the atmosphere, from 2 for clear sky to 10 for hazy one
+    pub turbidity: f32,
+    pub ground_albedo: Vector3<f32>,
+}
+
+impl SkyModel {
+    #[inline]
+    pub fn new(sun_direction: Vector3<f32>, turbidity: f32, ground_albedo: Vector3<f32>) -> Self {
+        SkyModel { sun_direction: sun_direction.normalize(), turbidity, ground_albedo }
+    }
+
+    /// Sun direction from elevation above horizon and azimuth from x towards z, both in radians
+    #[inline]
+    pub fn sun_direction_from_angles(elevation: f32, azimuth: f32) -> Vector3<f32> {
+        Vector3::new(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin())
+    }
+
+    /// Zenith angle of the sun, the model is defined only for sun above horizon
+    #[inline]
+    fn sun_theta(&self) -> f32 {
+        self.sun_direction.y.clamp(0.0, 1.0).acos().min(PI * 0.5 - 1e-3)
+    }
+
+    /// Radiance of the sky in upper hemisphere, without the sun disk
+    pub fn sky_radiance(&self, direction: &Vector3<f32>) -> Vector3<f32> {
+        let t = self.turbidity;
+        let theta_s = self.sun_theta();
+        let sun = Self::sun_direction_from_angles(PI * 0.5 - theta_s, self.sun_direction.z.atan2(self.sun_direction.x));
+        let cos_theta = direction.y.max(0.0);
+        let gamma = direction.dot(&sun).clamp(-1.0, 1.0).acos();
+
+        let perez_luminance = Perez([0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703]);
+        let perez_x = Perez([-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452]);
+        let perez_y = Perez([-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529]);
+
+        // Values at zenith
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let theta = Vector3::new(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s);
+        let zenith_x = t * t * Vector3::new(0.00166, -0.00375, 0.00209).dot(&theta)
+            + t * (Vector3::new(-0.02903, 0.06377, -0.03202).dot(&theta) + 0.00394)
+            + Vector3::new(0.11693, -0.21196, 0.06052).dot(&theta) + 0.25886;
+        let zenith_y = t * t * Vector3::new(0.00275, -0.00610, 0.00317).dot(&theta)
+            + t * (Vector3::new(-0.04214, 0.08970, -0.04153).dot(&theta) + 0.00516)
+            + Vector3::new(0.15346, -0.26756, 0.06670).dot(&theta) + 0.26688;
+
+        let relative = |perez: &Perez| perez.evaluate(cos_theta, gamma) / perez.evaluate(1.0, theta_s);
+        let luminance = zenith_luminance * relative(&perez_luminance) * SKY_RADIANCE_SCALE;
+        let x = zenith_x * relative(&perez_x);
+        let y = zenith_y * relative(&perez_y);
+
+        // xyY to XYZ to linear sRGB
+        let xyz = Vector3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        Vector3::new(
+            3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+            -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+            0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+        ).map(|x| x.max(0.0))
+    }
+
+    /// Radiance of the sun disk after Rayleigh and aerosol extinction along its path through atmosphere
+    pub fn sun_radiance(&self) -> Vector3<f32> {
+        if self.sun_direction.y <= 0.0 {
+            return Vector3::zeros();
+        }
+        let theta_s = self.sun_theta();
+        // Relative air mass, Kasten and Young
+        let air_mass = 1.0 / (theta_s.cos() + 0.50572 * (96.07995 - theta_s.to_degrees()).powf(-1.6364));
+        // Optical depths at red, green and blue wavelengths in micrometers
+        let wavelengths: Vector3<f32> = Vector3::new(0.68, 0.55, 0.44);
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let optical_depth = wavelengths.map(|x| 0.008735 * x.powf(-4.08) + beta * x.powf(-1.3));
+        optical_depth.map(|x| (-air_mass * x).exp()) * SUN_LUMINANCE * SKY_RADIANCE_SCALE
+    }
+
+    /// Sun disk to be sampled separately from the sky texture
+    #[inline]
+    pub fn sun_disk(&self) -> SunDisk {
+        SunDisk::new(self.sun_direction, self.sun_radiance(), SUN_ANGULAR_RADIUS)
+    }
+
+    /// Environment with the sky baked into texture and analytic sun disk
+    pub fn environment(&self) -> EnvironmentLight {
+        let (width, height) = (SKY_TEXTURE_WIDTH, SKY_TEXTURE_HEIGHT);
+        let directions: Vec<Vector3<f32>> = (0..height).flat_map(|row| (0..width).map(move |column| {
+            EnvironmentLight::texture_direction(
+                ((column as f32 + 0.5) / (width - 1) as f32).min(1.0),
+                ((row as f32 + 0.5) / (height - 1) as f32).min(1.0),
+            )
+        })).collect();
+        let mut buffer: Vec<Vector3<f32>> = directions.iter().map(|x| self.sky_radiance(x)).collect();
+
+        // Irradiance of horizontal ground from the sky texels and the sun
+        let cell_solid_angle = 2.0 * PI * PI / ((width - 1) * (height - 1)) as f32;
+        let sky_irradiance: Vector3<f32> = directions.iter().zip(&buffer)
+            .filter(|(direction, _)| direction.y > 0.0)
+            .map(|(direction, radiance)| radiance * (direction.y * (1.0 - direction.y * direction.y).sqrt() * cell_solid_angle))
+            .sum();
+        let sun = self.sun_disk();
+        let irradiance = sky_irradiance + sun.radiance * (sun.solid_angle() * self.sun_direction.y.max(0.0));
+        let ground_radiance = self.ground_albedo.component_mul(&irradiance) / PI;
+        for (direction, radiance) in directions.iter().zip(buffer.iter_mut()) {
+            if direction.y <= 0.0 {
+                *radiance = ground_radiance;
+            }
+        }
+
+        EnvironmentLight::new(Texture::from_buffer(buffer, width, height, TextureSamplingMode::Repeat)).with_sun(sun)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use crate::math::extensions::luminance;
+    use super::SkyModel;
+
+    fn sky() -> SkyModel {
+        SkyModel::new(SkyModel::sun_direction_from_angles(30f32.to_radians(), 0.0), 3.0, Vector3::repeat(0.2))
+    }
+
+    #[test]
+    fn sky_radiance() {
+        let sky = sky();
+        let zenith = sky.sky_radiance(&Vector3::y());
+        // Clear sky is blue away from the sun and brighter around it
+        assert!(zenith.z > zenith.x);
+        let away = sky.sky_radiance(&Vector3::new(-1.0, 1.0, 0.0).normalize());
+        let around = sky.sky_radiance(&Vector3::new(1.0, 0.6, 0.0).normalize());
+        assert!(luminance(&around) > luminance(&away));
+        // Sun is reddened near horizon
+        let sun = sky.sun_radiance();
+        assert!(sun.x > sun.z);
+        let low_sun = SkyModel::new(SkyModel::sun_direction_from_angles(5f32.to_radians(), 0.0), 3.0, Vector3::zeros()).sun_radiance();
+        assert!(low_sun.z / low_sun.x < sun.z / sun.x);
+        assert_eq!(SkyModel::new(-Vector3::y(), 3.0, Vector3::zeros()).sun_radiance(), Vector3::zeros());
+    }
+
+    #[test]
+    fn sky_environment() {
+        let sky = sky();
+        let environment = sky.environment();
+        let sun = sky.sun_disk();
+        assert_eq!(environment.radiance(&sun.direction), environment.texture().sample(0.5, 0.5 + 30.0 / 180.0) + sun.radiance);
+        // Ground is lit and sun is sampled proportionally to its power
+        let ground = environment.radiance(&-Vector3::y());
+        assert!(ground.min() > 0.0);
+        let mut seed = 23;
+        let samples = 10_000;
+        let mut sun_samples = 0;
+        for _ in 0..samples {
+            let sample = environment.sample(&mut seed).unwrap();
+            assert!((environment.pdf(&sample.direction) - sample.pdf).abs() <= sample.pdf * 1e-2);
+            sun_samples += sun.contains(&sample.direction) as u32;
+        }
+        let fraction = sun_samples as f32 / samples as f32;
+        assert!((fraction - environment.sun_probability()).abs() < 0.02, "{fraction}");
+        assert!(fraction > 0.3);
+        // Estimate of radiance integral includes the sun and the sky
+        let mut seed = 29;
+        let estimate: Vector3<f32> = (0..1000)
+            .map(|_| environment.sample(&mut seed).map(|x| x.radiance / x.pdf).unwrap_or_default())
+            .sum::<Vector3<f32>>() / 1000.0;
+        let sun_power = sun.radiance * sun.solid_angle();
+        assert!(estimate.x > sun_power.x && estimate.x < sun_power.x * 2.0);
+    }
+}
@@ -0,0 +1,270 @@
+use std::{fs, path::Path};
+use nalgebra::Vector3;
+
+/// Candela distribution of a luminaire from IES LM-63 photometric file.
+/// Only type C photometry is supported, which covers nearly all architectural luminaires.
+///
+/// Luminaire space has nadir, vertical angle 0, along -y.
+/// Horizontal angle 0 is along +x and 90 is along +z.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IesProfile {
+    /// Vertical angles in degrees, ascending
+    vertical_angles: Vec<f32>,
+    /// Horizontal angles in degrees, ascending
+    horizontal_angles: Vec<f32>,
+    /// Candela values for every horizontal angle, `vertical_angles.len()` values each
+    candela: Vec<f32>,
+    max_candela: f32,
+}
+
+impl IesProfile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read(path).map_err(|x| format!("Failed to load IES file \"{}\". Reason: {}", path.display(), x))?;
+        // Files are often in Latin-1, only numbers after TILT matter
+        Self::parse(&String::from_utf8_lossy(&text))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        // Header and keywords end with TILT line
+        let mut lines = text.lines();
+        let tilt = lines.by_ref()
+            .map(|x| x.trim())
+            .find(|x| x.to_uppercase().starts_with("TILT"))
+            .ok_or("IES file has no TILT line")?;
+        let mut values = lines.flat_map(|x| x.split(|c: char| c.is_whitespace() || c == ',')).filter(|x| !x.is_empty());
+        let mut next = |what: &str| -> Result<f32, String> {
+            values.next()
+                .ok_or(format!("IES file ends before {what}"))?
+                .parse::<f32>()
+                .map_err(|x| format!("Failed to read {what} of IES file. Reason: {x}"))
+        };
+
+        // Tilt of lamps inside luminaire doesn't change the distribution of upright luminaire
+        if tilt.to_uppercase().trim_start_matches("TILT").trim_start_matches([' ', '=']) == "INCLUDE" {
+            next("lamp to luminaire geometry")?;
+            let count = next("tilt angles count")? as usize;
+            for _ in 0..count * 2 {
+                next("tilt data")?;
+            }
+        }
+
+        let _lamps_count = next("lamps count")?;
+        let _lumens_per_lamp = next("lumens per lamp")?;
+        let multiplier = next("candela multiplier")?;
+        let vertical_count = next("vertical angles count")? as usize;
+        let horizontal_count = next("horizontal angles count")? as usize;
+        let photometric_type = next("photometric type")? as u32;
+        let _units = next("units type")?;
+        for what in ["width", "length", "height"] {
+            next(what)?;
+        }
+        let ballast_factor = next("ballast factor")?;
+        let _future_use = next("ballast lamp factor")?;
+        let _input_watts = next("input watts")?;
+
+        if photometric_type != 1 {
+            return Err(format!("IES photometric type {photometric_type} is not supported, only type C is"));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err("IES file has no angles".to_string());
+        }
+        let vertical_angles = (0..vertical_count).map(|_| next("vertical angle")).collect::<Result<Vec<f32>, String>>()?;
+        let horizontal_angles = (0..horizontal_count).map(|_| next("horizontal angle")).collect::<Result<Vec<f32>, String>>()?;
+        let candela = (0..vertical_count * horizontal_count)
+            .map(|_| next("candela value").map(|x| x * multiplier * ballast_factor))
+            .collect::<Result<Vec<f32>, String>>()?;
+        if !vertical_angles.windows(2).all(|x| x[0] < x[1]) || !horizontal_angles.windows(2).all(|x| x[0] < x[1]) {
+            return Err("IES angles must be ascending".to_string());
+        }
+        // Type C profiles start at 0 or are bilateral about 90-270 plane
+        let first_horizontal = horizontal_angles[0];
+        if !(first_horizontal == 0.0 || first_horizontal == 90.0 && *horizontal_angles.last().unwrap() <= 270.0) {
+            return Err(format!("IES horizontal angles starting at {first_horizontal} are not supported"));
+        }
+
+        let max_candela = candela.iter().fold(0.0f32, |x, y| x.max(*y));
+        Ok(IesProfile { vertical_angles, horizontal_angles, candela, max_candela })
+    }
+
+    #[inline]
+    pub fn max_candela(&self) -> f32 {
+        self.max_candela
+    }
+
+    /// Luminous intensity at angles in degrees, interpolated between measured angles
+    pub fn candela(&self, vertical: f32, horizontal: f32) -> f32 {
+        let vertical_range = (self.vertical_angles[0], *self.vertical_angles.last().unwrap());
+        if vertical < vertical_range.0 || vertical > vertical_range.1 {
+            return 0.0;
+        }
+        // Fold horizontal angle into measured range by symmetry of the profile
+        let mut horizontal = horizontal.rem_euclid(360.0);
+        let last_horizontal = *self.horizontal_angles.last().unwrap();
+        if self.horizontal_angles[0] == 90.0 {
+            // Bilateral symmetry about 90-270 plane
+            if !(90.0..=270.0).contains(&horizontal) {
+                horizontal = (180.0 - horizontal).rem_euclid(360.0);
+            }
+        } else if last_horizontal <= 0.0 {
+            horizontal = 0.0;
+        } else if last_horizontal <= 90.0 {
+            // Quadrant symmetry
+            horizontal = if horizontal > 180.0 { 360.0 - horizontal } else { horizontal };
+            horizontal = if horizontal > 90.0 { 180.0 - horizontal } else { horizontal };
+        } else if last_horizontal <= 180.0 && horizontal > 180.0 {
+            // Bilateral symmetry
+            horizontal = 360.0 - horizontal;
+        }
+
+        let (h0, h1, ht) = Self::interval(&self.horizontal_angles, horizontal);
+        let (v0, v1, vt) = Self::interval(&self.vertical_angles, vertical);
+        let value = |h: usize, v: usize| self.candela[h * self.vertical_angles.len() + v];
+        let lower = value(h0, v0) * (1.0 - vt) + value(h0, v1) * vt;
+        let upper = value(h1, v0) * (1.0 - vt) + value(h1, v1) * vt;
+        lower * (1.0 - ht) + upper * ht
+    }
+
+    /// Intensity along unit `direction` in luminaire space relative to the brightest direction
+    #[inline]
+    pub fn relative_intensity(&self, direction: &Vector3<f32>) -> f32 {
+        if self.max_candela <= 0.0 {
+            return 0.0;
+        }
+        let vertical = (-direction.y).clamp(-1.0, 1.0).acos().to_degrees();
+        let horizontal = direction.z.atan2(direction.x).to_degrees();
+        self.candela(vertical, horizontal) / self.max_candela
+    }
+
+    /// Indices of angles around `angle` and interpolation weight between them
+    #[inline]
+    fn interval(angles: &[f32], angle: f32) -> (usize, usize, f32) {
+        if angles.len() == 1 {
+            return (0, 0, 0.0);
+        }
+        let upper = angles.partition_point(|x| *x < angle).clamp(1, angles.len() - 1);
+        let lower = upper - 1;
+        (lower, upper, ((angle - angles[lower]) / (angles[upper] - angles[lower])).clamp(0.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use super::IesProfile;
+
+    /// Rotationally symmetric downlight in LM-63-1995 format
+    const DOWNLIGHT: &str = "IESNA:LM-63-1995
+[TEST] 12345
+[MANUFAC] Example
+[LUMINAIRE] Recessed downlight
+TILT=NONE
+1 1000 2.0 5 1 1 2 0.2 0.2 0
+1.0 1.0 20
+0 22.5 45 67.5 90
+0
+500 400 250, 100 0
+";
+
+    /// Luminaire with quadrant symmetry in LM-63-2002 format, with tilt data included
+    const WALLWASHER: &str = "IESNA:LM-63-2002
+[TEST] 67890
+[ISSUEDATE] 01-01-2020
+[MORE] Wall washer with
+[MORE] asymmetric distribution
+TILT=INCLUDE
+1
+3
+0 45 90
+1.0 0.9 0.8
+1 -1 1 3 3 1 1 0.5 0.1 0.1
+0.5 1 15
+0 45 90
+0 45 90
+100 200 300
+100 150 200
+100 100 100
+";
+
+    /// Wall light bilateral about 90-270 plane, laid out like manufacturer files
+    /// with CRLF line endings, user keywords and values wrapped over lines
+    const WALL_LIGHT: &str = "IESNA:LM-63-2002\r
+[TEST] 2021-0457\r
+[TESTLAB] Photometric Laboratory\r
+[ISSUEDATE] 12-MAR-2021\r
+[MANUFAC] Example Lighting\r
+[LUMCAT] WL-100\r
+[LUMINAIRE] Wall light, frosted glass\r
+[LAMP] LED module 3000K\r
+[_ABSOLUTELUMENS] 1200\r
+TILT=NONE\r
+1 -1 1 3 3 1 2 0.120 0.060 0.080\r
+1.0 1.0 14.5\r
+0.0 45.0\r
+90.0\r
+90.0 180.0\r
+270.0\r
+100.0 80.0 20.0\r
+100.0 60.0 10.0 100.0\r
+40.0 0.0\r
+";
+
+    #[test]
+    fn rotationally_symmetric_profile() {
+        let profile = IesProfile::parse(DOWNLIGHT).unwrap();
+        assert_eq!(profile.max_candela(), 1000.0);
+        assert_eq!(profile.candela(0.0, 0.0), 1000.0);
+        assert_eq!(profile.candela(22.5, 123.0), 800.0);
+        assert_eq!(profile.candela(56.25, 300.0), 350.0);
+        // Nothing above horizontal plane
+        assert_eq!(profile.candela(120.0, 0.0), 0.0);
+        assert_eq!(profile.relative_intensity(&-Vector3::y()), 1.0);
+        assert_eq!(profile.relative_intensity(&Vector3::y()), 0.0);
+        let direction = Vector3::new(0.0, -45f32.to_radians().cos(), 45f32.to_radians().sin());
+        assert!((profile.relative_intensity(&direction) - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn quadrant_symmetric_profile() {
+        let profile = IesProfile::parse(WALLWASHER).unwrap();
+        // Ballast factor scales values
+        assert_eq!(profile.max_candela(), 150.0);
+        assert_eq!(profile.candela(90.0, 0.0), 150.0);
+        assert_eq!(profile.candela(90.0, 90.0), 50.0);
+        assert_eq!(profile.candela(45.0, 45.0), 75.0);
+        // Other quadrants mirror the first one
+        for horizontal in [0.0, 45.0, 90.0] {
+            for vertical in [0.0, 30.0, 90.0] {
+                let value = profile.candela(vertical, horizontal);
+                assert_eq!(profile.candela(vertical, 180.0 - horizontal), value);
+                assert_eq!(profile.candela(vertical, 180.0 + horizontal), value);
+                assert_eq!(profile.candela(vertical, 360.0 - horizontal), value);
+            }
+        }
+        assert!((profile.candela(30.0, 22.5) - 75.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn bilateral_profile_from_90_to_270() {
+        let profile = IesProfile::parse(WALL_LIGHT).unwrap();
+        assert_eq!(profile.max_candela(), 100.0);
+        assert_eq!(profile.candela(45.0, 90.0), 80.0);
+        assert_eq!(profile.candela(45.0, 135.0), 70.0);
+        // Half behind 90-270 plane mirrors the measured one
+        assert_eq!(profile.candela(45.0, 0.0), profile.candela(45.0, 180.0));
+        assert_eq!(profile.candela(45.0, 45.0), 70.0);
+        assert_eq!(profile.candela(45.0, 315.0), profile.candela(45.0, 225.0));
+        assert_eq!(profile.candela(45.0, -45.0), 50.0);
+        assert_eq!(profile.candela(90.0, 270.0), 0.0);
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        assert!(IesProfile::parse("IESNA:LM-63-1995\n1 1000 1").is_err());
+        assert!(IesProfile::parse("TILT=NONE\n1 1000 1 2 1 1 2 0 0 0\n1 1 10\n0 90\n0\n100").is_err());
+        assert!(IesProfile::parse("TILT=NONE\n1 1000 1 2 1 2 2 0 0 0\n1 1 10\n0 90\n0\n100 50").is_err());
+        assert!(IesProfile::parse("TILT=NONE\n1 1000 1 2 1 1 2 0 0 0\n1 1 10\n90 0\n0\n100 50").is_err());
+        // Horizontal angles must start at 0 or 90
+        assert!(IesProfile::parse("TILT=NONE\n1 1000 1 1 2 1 2 0 0 0\n1 1 10\n0\n45 90\n100 50").is_err());
+        assert!(IesProfile::parse("TILT=NONE\n1 1000 1 1 2 1 2 0 0 0\n1 1 10\n0\n90 360\n100 50").is_err());
+    }
+}
@@ -1,2 +1,4 @@
 pub mod scene_loader;
+pub mod ies_loader;
+pub mod volume_loader;
 mod model_loader;
\ No newline at end of file
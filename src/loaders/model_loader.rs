@@ -3,14 +3,16 @@ use obj::ObjResult;
 use obj::raw::{self, parse_mtl, parse_obj, RawObj};
 use std::path::Path;
 use std::{fs::File, collections::HashMap, sync::Arc};
-use std::io::BufReader;
-use crate::material::Material;
-use crate::entity::triangle::Triangle;
+use std::io::{BufReader, Read};
+use crate::material::{Material, EmissionSides, Subsurface};
+use crate::entity::mesh::Mesh;
+use crate::loaders::ies_loader::IesProfile;
+use crate::media::{Medium, homogeneous::HomogeneousMedium};
 use crate::textures::extensions::file_to_texture;
 use crate::textures::texture::TextureSamplingMode;
 
 #[inline]
-pub fn load_model(path: &str) -> Vec<Triangle> {
+pub fn load_model(path: &str) -> Vec<Mesh> {
     let file = File::open(path).unwrap_or_else(|_|
         panic!("Failed to load obj file \"{}\" specified in scene. Reason: Not found", &path)
     );
@@ -45,145 +47,60 @@ pub fn load_model(path: &str) -> Vec<Triangle> {
 }
 
 #[inline]
-fn load_meshes(model: &RawObj, materials: &HashMap<String, Arc<Material>>) -> Vec<Triangle> {
-    let mut triangles = vec![];
-    let mut ind = 0;
-    model.meshes.iter().for_each(|(mesh_name, mesh)| {
+fn load_meshes(model: &RawObj, materials: &HashMap<String, Arc<Material>>) -> Vec<Mesh> {
+    model.meshes.iter().map(|(mesh_name, mesh)| {
+        // Obj has separate indices for positions, uvs and normals,
+        // so every unique combination of them becomes one shared vertex
+        let mut vertices: HashMap<(usize, Option<usize>, Option<usize>), u32> = HashMap::new();
+        let mut positions: Vec<Vector3<f32>> = vec![];
+        let mut normals: Vec<Vector3<f32>> = vec![];
+        let mut uvs: Vec<Vector2<f32>> = vec![];
+        let mut indices: Vec<[u32; 3]> = vec![];
+
         mesh.polygons.iter().for_each(|pol| {
             for i in pol.start..pol.end {
-                match &model.polygons[i] {
-                    raw::object::Polygon::P(p) => {
-                        if p.len() == 3 {
-                            let v1 = model.positions[p[0]];
-                            let v2 = model.positions[p[1]];
-                            let v3 = model.positions[p[2]];
-                            let v1: Vector3<f32> = Vector3::new(v1.0, v1.1, -v1.2);
-                            let v2: Vector3<f32> = Vector3::new(v2.0, v2.1, -v2.2);
-                            let v3: Vector3<f32> = Vector3::new(v3.0, v3.1, -v3.2);
-                            
-                            // Calculate the normal vector of the triangle (cross product of two edges)
-                            let edge1: Vector3<f32> = v2 - v1;
-                            let edge2: Vector3<f32> = v3 - v1;
-                            let n: Vector3<f32> = edge1.cross(&edge2).normalize();
-                            // For some reason Z coordinate is negative, so just reverse it
-                            let triangle = Triangle::new(
-                                v1, v2, v3,
-                                n, n, n,
-                                Vector2::zeros(),
-                                Vector2::zeros(),
-                                Vector2::zeros(),
-                                materials[mesh_name].clone(),
-                                ind
-                            );
-                            ind += 1;
-                            triangles.push(triangle);
-                        } else {
-                            panic!("Mesh must be triangulated, native triangulation is not yet implemented");
-                        }
-                    },
-                    raw::object::Polygon::PT(p) => {
-                        if p.len() == 3 {
-                            let v1 = model.positions[p[0].0];
-                            let v2 = model.positions[p[1].0];
-                            let v3 = model.positions[p[2].0];
-                            let v1: Vector3<f32> = Vector3::new(v1.0, v1.1, -v1.2);
-                            let v2: Vector3<f32> = Vector3::new(v2.0, v2.1, -v2.2);
-                            let v3: Vector3<f32> = Vector3::new(v3.0, v3.1, -v3.2);
-
-                            // Calculate the normal vector of the triangle (cross product of two edges)
-                            let edge1: Vector3<f32> = v2 - v1;
-                            let edge2: Vector3<f32> = v3 - v1;
-                            let n: Vector3<f32> = edge1.cross(&edge2).normalize();
-
-                            let u1 = model.tex_coords[p[0].1];
-                            let u2 = model.tex_coords[p[1].1];
-                            let u3 = model.tex_coords[p[2].1];
-
-                            // For some reason Z coordinate is negative, so just reverse it
-                            let triangle = Triangle::new(
-                                v1, v2, v3,
-                                n, n, n,
-                                Vector2::new(u1.0, u1.1),
-                                Vector2::new(u2.0, u2.1),
-                                Vector2::new(u3.0, u3.1),
-                                materials[mesh_name].clone(),
-                                ind
-                            );
-                            ind += 1;
-                            triangles.push(triangle);
-                        } else {
-                            panic!("Mesh must be triangulated, native triangulation is not yet implemented");
-                        }
-                    },
-                    raw::object::Polygon::PN(p) => {
-                        if p.len() == 3 {
-                            let v1 = model.positions[p[0].0];
-                            let v2 = model.positions[p[1].0];
-                            let v3 = model.positions[p[2].0];
-                            let v1: Vector3<f32> = Vector3::new(v1.0, v1.1, -v1.2);
-                            let v2: Vector3<f32> = Vector3::new(v2.0, v2.1, -v2.2);
-                            let v3: Vector3<f32> = Vector3::new(v3.0, v3.1, -v3.2);
-
-                            let n1 = model.normals[p[0].1];
-                            let n2 = model.normals[p[1].1];
-                            let n3 = model.normals[p[2].1];
-                            // For some reason Z coordinate is negative, so just reverse it
-                            let triangle = Triangle::new(
-                                v1, v2, v3,
-                                Vector3::new(n1.0, n1.1, -n1.2),
-                                Vector3::new(n2.0, n2.1, -n2.2),
-                                Vector3::new(n3.0, n3.1, -n3.2),
-                                Vector2::zeros(),
-                                Vector2::zeros(),
-                                Vector2::zeros(),
-                                materials[mesh_name].clone(),
-                                ind
-                            );
-                            ind += 1;
-                            triangles.push(triangle);
-                        } else {
-                            panic!("Mesh must be triangulated, native triangulation is not yet implemented");
-                        }
-                    },
-                    raw::object::Polygon::PTN(p) => {
-                        if p.len() == 3 {
-                            let v1 = model.positions[p[0].0];
-                            let v2 = model.positions[p[1].0];
-                            let v3 = model.positions[p[2].0];
-                            let v1: Vector3<f32> = Vector3::new(v1.0, v1.1, -v1.2);
-                            let v2: Vector3<f32> = Vector3::new(v2.0, v2.1, -v2.2);
-                            let v3: Vector3<f32> = Vector3::new(v3.0, v3.1, -v3.2);
-                            
-                            let n1 = model.normals[p[0].2];
-                            let n2 = model.normals[p[1].2];
-                            let n3 = model.normals[p[2].2];
-                            
-                            let u1 = model.tex_coords[p[0].1];
-                            let u2 = model.tex_coords[p[1].1];
-                            let u3 = model.tex_coords[p[2].1];
-                            // For some reason Z coordinate is negative, so just reverse it
-                            let triangle = Triangle::new(
-                                v1, v2, v3,
-                                Vector3::new(n1.0, n1.1, -n1.2),
-                                Vector3::new(n2.0, n2.1, -n2.2),
-                                Vector3::new(n3.0, n3.1, -n3.2),
-                                Vector2::new(u1.0, u1.1),
-                                Vector2::new(u2.0, u2.1),
-                                Vector2::new(u3.0, u3.1),
-                                materials[mesh_name].clone(),
-                                ind
-                            );
-                            ind += 1;
-                            triangles.push(triangle);
-                        } else {
-                            panic!("Mesh must be triangulated, native triangulation is not yet implemented");
-                        }
-                    },
+                let polygon: Vec<(usize, Option<usize>, Option<usize>)> = match &model.polygons[i] {
+                    raw::object::Polygon::P(p) => p.iter().map(|x| (*x, None, None)).collect(),
+                    raw::object::Polygon::PT(p) => p.iter().map(|x| (x.0, Some(x.1), None)).collect(),
+                    raw::object::Polygon::PN(p) => p.iter().map(|x| (x.0, None, Some(x.1))).collect(),
+                    raw::object::Polygon::PTN(p) => p.iter().map(|x| (x.0, Some(x.1), Some(x.2))).collect(),
+                };
+                if polygon.len() != 3 {
+                    panic!("Mesh must be triangulated, native triangulation is not yet implemented");
+                }
+
+                let mut triangle = [0u32; 3];
+                for (vertex_index, key) in polygon.into_iter().enumerate() {
+                    triangle[vertex_index] = *vertices.entry(key).or_insert_with(|| {
+                        // For some reason Z coordinate is negative, so just reverse it
+                        let p = model.positions[key.0];
+                        positions.push(Vector3::new(p.0, p.1, -p.2));
+                        // Missing normals are stored as zeros, triangle plane normal is used for them
+                        normals.push(key.2.map_or(Vector3::zeros(), |n| {
+                            let n = model.normals[n];
+                            Vector3::new(n.0, n.1, -n.2)
+                        }));
+                        uvs.push(key.1.map_or(Vector2::zeros(), |u| {
+                            let u = model.tex_coords[u];
+                            Vector2::new(u.0, u.1)
+                        }));
+                        (positions.len() - 1) as u32
+                    });
                 }
+                // Mirrored z flips winding, it is reversed back so front faces stay counter clockwise
+                indices.push([triangle[0], triangle[2], triangle[1]]);
             }
         });
-    });
-    triangles
+
+        // Don't store attributes that obj doesn't have
+        if normals.iter().all(|x| *x == Vector3::zeros()) {
+            normals.clear();
+        }
+        if vertices.keys().all(|x| x.1.is_none()) {
+            uvs.clear();
+        }
+        Mesh::new(positions, normals, uvs, indices, materials[mesh_name].clone())
+    }).collect()
 }
 
 #[inline]
@@ -193,11 +110,13 @@ fn load_materials(libs: &[String], path: &str) -> ObjResult<HashMap<String, Arc<
     
     for mtl in libs {
         let mtl_path = parent_path.join(mtl);
-        let file = File::open(&mtl_path).unwrap_or_else(|_|
+        let mut file = File::open(&mtl_path).unwrap_or_else(|_|
             panic!("Failed to load mtl file \"{:?}\" specified in \"{}\". Reason: Not found", &mtl_path, &path)
         );
-        let input = BufReader::new(file);
-        let mat: raw::RawMtl = parse_mtl(input)?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        let (text, statements) = extract_custom_statements(&text);
+        let mat: raw::RawMtl = parse_mtl(text.as_bytes())?;
 
         materials.extend(mat.materials.iter().map(|(name, raw_material)| {
             let albedo: Vector3<f32> = match raw_material.diffuse.clone().unwrap_or(raw::material::MtlColor::Rgb(0.8, 0.8, 0.8)) {
@@ -205,7 +124,10 @@ fn load_materials(libs: &[String], path: &str) -> ObjResult<HashMap<String, Arc<
                 raw::material::MtlColor::Xyz(_, _, _) => Vector3::new(0.8, 0.8, 0.8),
                 raw::material::MtlColor::Spectral(_, _) => Vector3::new(0.8, 0.8, 0.8),
             };
-            let emission: Vector3<f32> = match raw_material.emissive.clone().unwrap_or(raw::material::MtlColor::Rgb(0.0, 0.0, 0.0)) {
+            // Emission map without color emits texture as is
+            let default_emission = if raw_material.emissive_map.is_some() { 1.0 } else { 0.0 };
+            let emission: Vector3<f32> = match raw_material.emissive.clone()
+                .unwrap_or(raw::material::MtlColor::Rgb(default_emission, default_emission, default_emission)) {
                 raw::material::MtlColor::Rgb(r, g, b) => Vector3::new(r, g, b),
                 raw::material::MtlColor::Xyz(_, _, _) => Vector3::new(0.0, 0.0, 0.0),
                 raw::material::MtlColor::Spectral(_, _) => Vector3::new(0.0, 0.0, 0.0),
@@ -217,15 +139,147 @@ fn load_materials(libs: &[String], path: &str) -> ObjResult<HashMap<String, Arc<
                 None
             };
 
+            let emission_map = raw_material.emissive_map.as_ref()
+                .and_then(|x| file_to_texture(&parent_path.join(&x.file), TextureSamplingMode::Repeat));
+
+            let statements = statements.get(name).cloned().unwrap_or_default();
+            let emission_profile = statements.emission_profile.as_ref().map(|x| {
+                let profile = IesProfile::load(&parent_path.join(x)).unwrap_or_else(|x| panic!("{x}"));
+                Arc::new(profile)
+            });
+
             let material = Arc::new(Material::new(
                 albedo,
                 emission,
                 1.0,
                 0.0,
                 albedo_map
-            ));
+            ).with_emission_tex(emission_map)
+                .with_emission_sides(statements.emission_sides)
+                .with_emission_profile(emission_profile)
+                .with_interior_medium(statements.interior_medium())
+                .with_subsurface(statements.subsurface()));
             (name.clone(), material)
         }));
     }
     Ok(materials)
 }
+
+/// Material statements which obj parser doesn't know
+#[derive(Debug, Clone, Default)]
+struct CustomStatements {
+    /// `emission_sides one|two`
+    emission_sides: EmissionSides,
+    /// `emission_profile <ies file>`, relative to the mtl file
+    emission_profile: Option<String>,
+    /// `medium_absorption r g b`
+    medium_absorption: Option<Vector3<f32>>,
+    /// `medium_scattering r g b`
+    medium_scattering: Option<Vector3<f32>>,
+    /// `medium_anisotropy g`
+    medium_anisotropy: f32,
+    /// `subsurface_mfp r g b`, mean free path per channel
+    subsurface_mfp: Option<Vector3<f32>>,
+    /// `subsurface_anisotropy g`
+    subsurface_anisotropy: f32,
+}
+
+impl CustomStatements {
+    /// Interior medium if material has any of its coefficients
+    fn interior_medium(&self) -> Option<Medium> {
+        if self.medium_absorption.is_none() && self.medium_scattering.is_none() {
+            return None;
+        }
+        Some(Medium::Homogeneous(HomogeneousMedium::new(self.medium_absorption.unwrap_or_default(),
+            self.medium_scattering.unwrap_or_default(), self.medium_anisotropy)))
+    }
+
+    #[inline]
+    fn subsurface(&self) -> Option<Subsurface> {
+        self.subsurface_mfp.map(|x| Subsurface::new(x, self.subsurface_anisotropy))
+    }
+}
+
+/// Removes custom statements from mtl text and returns them by material name
+fn extract_custom_statements(text: &str) -> (String, HashMap<String, CustomStatements>) {
+    let mut statements: HashMap<String, CustomStatements> = HashMap::new();
+    let mut material: Option<&str> = None;
+    let filtered = text.lines().filter(|line| {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.first() {
+            Some(&"newmtl") => {
+                material = words.get(1).copied();
+                return true;
+            },
+            Some(&("emission_sides" | "emission_profile" | "medium_absorption" | "medium_scattering" | "medium_anisotropy"
+                | "subsurface_mfp" | "subsurface_anisotropy")) => (),
+            _ => return true,
+        }
+        let Some(entry) = material.map(|x| statements.entry(x.to_string()).or_default()) else {
+            return false;
+        };
+        let floats = |count: usize| -> Vec<f32> {
+            (1..=count).map(|i| words.get(i)
+                .and_then(|x| x.parse::<f32>().ok())
+                .unwrap_or_else(|| panic!("Failed to read {}, expected {count} numbers.", words[0]))
+            ).collect()
+        };
+        match words[0] {
+            "emission_sides" => entry.emission_sides = match words.get(1) {
+                Some(&"one") => EmissionSides::Front,
+                Some(&"two") => EmissionSides::Both,
+                _ => panic!("Failed to read emission sides, expected \"one\" or \"two\"."),
+            },
+            "emission_profile" => entry.emission_profile = Some(words.get(1)
+                .expect("Failed to read emission profile, expected IES file.")
+                .to_string()),
+            "medium_absorption" => entry.medium_absorption = Some(Vector3::from_vec(floats(3))),
+            "medium_scattering" => entry.medium_scattering = Some(Vector3::from_vec(floats(3))),
+            "medium_anisotropy" => entry.medium_anisotropy = floats(1)[0],
+            "subsurface_mfp" => entry.subsurface_mfp = Some(Vector3::from_vec(floats(3))),
+            _ => entry.subsurface_anisotropy = floats(1)[0],
+        }
+        false
+    }).collect::<Vec<&str>>().join("\n");
+    (filtered, statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use crate::material::{EmissionSides, Subsurface};
+    use crate::media::Medium;
+    use super::extract_custom_statements;
+
+    #[test]
+    fn emission_sides_statements() {
+        let text = "newmtl Lamp\nKe 1 1 1\nemission_sides one\nemission_profile lamp.ies\nnewmtl Screen\nemission_sides two\nnewmtl Wall\nKd 0.8 0.8 0.8";
+        let (filtered, statements) = extract_custom_statements(text);
+        assert_eq!(filtered, "newmtl Lamp\nKe 1 1 1\nnewmtl Screen\nnewmtl Wall\nKd 0.8 0.8 0.8");
+        assert_eq!(statements["Lamp"].emission_sides, EmissionSides::Front);
+        assert_eq!(statements["Lamp"].emission_profile.as_deref(), Some("lamp.ies"));
+        assert_eq!(statements["Screen"].emission_sides, EmissionSides::Both);
+        assert!(statements["Screen"].emission_profile.is_none());
+        assert!(!statements.contains_key("Wall"));
+        assert!(statements["Lamp"].interior_medium().is_none());
+
+        let text = "newmtl Smoke\nmedium_scattering 0.5 0.5 0.5\nmedium_anisotropy 0.3\nnewmtl Ink\nmedium_absorption 1 2 3";
+        let (filtered, statements) = extract_custom_statements(text);
+        assert_eq!(filtered, "newmtl Smoke\nnewmtl Ink");
+        let Some(Medium::Homogeneous(smoke)) = statements["Smoke"].interior_medium() else {
+            panic!("Smoke must have homogeneous medium");
+        };
+        assert_eq!(smoke.extinction(), Vector3::repeat(0.5));
+        assert_eq!(smoke.phase.g, 0.3);
+        let Some(Medium::Homogeneous(ink)) = statements["Ink"].interior_medium() else {
+            panic!("Ink must have homogeneous medium");
+        };
+        assert_eq!(ink.absorption, Vector3::new(1.0, 2.0, 3.0));
+        assert!(statements["Ink"].subsurface().is_none());
+
+        let text = "newmtl Skin\nKd 0.8 0.5 0.4\nsubsurface_mfp 0.5 0.2 0.1\nsubsurface_anisotropy 0.8";
+        let (filtered, statements) = extract_custom_statements(text);
+        assert_eq!(filtered, "newmtl Skin\nKd 0.8 0.5 0.4");
+        assert_eq!(statements["Skin"].subsurface(), Some(Subsurface::new(Vector3::new(0.5, 0.2, 0.1), 0.8)));
+    }
+}
@@ -1,10 +1,113 @@
-use std::{fs::File, io::{BufReader, prelude::*}};
-use crate::{entity::triangle::Triangle, camera::Camera};
-use super::model_loader;
+use std::{fs::File, io::{BufReader, prelude::*}, path::Path, sync::Arc};
+use crate::material::Material;
+use crate::{entity::mesh::Mesh, camera::Camera, lights::{environment::EnvironmentLight, sky::SkyModel, punctual::PunctualLight}};
+use crate::media::{Medium, grid::GridMedium, homogeneous::{Fog, HomogeneousMedium}};
+use crate::textures::{texture::TextureSamplingMode, extensions_f32::file_to_texture};
+use super::{model_loader, ies_loader::IesProfile, volume_loader::{load_voxels, box_mesh}};
 use model_loader::load_model;
 use nalgebra::Vector3;
 
-pub fn load_scene(path: &str) -> (Vec<Triangle>, Camera) {
+/// Radiance of the environment if scene has no `Environment` section
+const DEFAULT_ENVIRONMENT_COLOR: Vector3<f32> = Vector3::new(0.3, 0.3, 0.3);
+
+/// Environment options, the light is created after the whole section is read
+#[derive(Default)]
+struct EnvironmentDescription {
+    texture: Option<String>,
+    color: Option<Vector3<f32>>,
+    gradient: Option<(Vector3<f32>, Vector3<f32>)>,
+    sky: Option<SkyModel>,
+    yaw: f32,
+    intensity: Option<f32>,
+    visibility: Option<(bool, bool)>,
+}
+
+impl EnvironmentDescription {
+    fn into_light(self) -> EnvironmentLight {
+        let texture = self.texture.and_then(|x| file_to_texture(Path::new(&x), TextureSamplingMode::Repeat));
+        let mut environment = match (texture, self.sky, self.gradient, self.color) {
+            (Some(texture), _, _, _) => EnvironmentLight::new(texture),
+            (None, Some(sky), _, _) => sky.environment(),
+            (None, None, Some((bottom, top)), _) => EnvironmentLight::gradient(bottom, top),
+            (None, None, None, color) => EnvironmentLight::constant(color.unwrap_or(DEFAULT_ENVIRONMENT_COLOR)),
+        };
+        environment.set_yaw(self.yaw.to_radians());
+        environment.intensity = self.intensity.unwrap_or(1.0);
+        (environment.visible_to_camera, environment.visible_to_lighting) = self.visibility.unwrap_or((true, true));
+        environment
+    }
+}
+
+/// Fog options, fog is created only if the section has any coefficient
+#[derive(Default)]
+struct FogDescription {
+    absorption: Option<Vector3<f32>>,
+    scattering: Option<Vector3<f32>>,
+    anisotropy: f32,
+    distance: Option<f32>,
+}
+
+impl FogDescription {
+    fn into_fog(self) -> Option<Fog> {
+        if self.absorption.is_none() && self.scattering.is_none() {
+            return None;
+        }
+        Some(Fog {
+            medium: HomogeneousMedium::new(self.absorption.unwrap_or_default(), self.scattering.unwrap_or_default(), self.anisotropy),
+            distance: self.distance.unwrap_or(f32::INFINITY),
+        })
+    }
+}
+
+/// Voxel grid volume, it's placed in the scene as box mesh with the grid as interior medium
+#[derive(Default)]
+struct VolumeDescription {
+    file: Option<String>,
+    resolution: Option<[usize; 3]>,
+    bounds: Option<(Vector3<f32>, Vector3<f32>)>,
+    absorption: Vector3<f32>,
+    scattering: Vector3<f32>,
+    anisotropy: f32,
+}
+
+impl VolumeDescription {
+    fn into_mesh(self) -> Mesh {
+        let file = self.file.expect("Failed to read file of the volume.");
+        let voxels = load_voxels(Path::new(&file), self.resolution).unwrap_or_else(|x| panic!("{x}"));
+        let (min, max) = self.bounds.or(voxels.bounds).expect("Failed to read bounds of the volume.");
+        let grid = GridMedium::new(voxels.resolution, voxels.density, min, max, self.absorption, self.scattering, self.anisotropy);
+        let material = Material::default().with_interior_medium(Some(Medium::Grid(Arc::new(grid))));
+        box_mesh(&min, &max, Arc::new(material))
+    }
+}
+
+/// Parses `count` floats after the line key
+fn parse_floats(s: &[&str], count: usize, what: &str) -> Vec<f32> {
+    (1..=count).map(|i| s.get(i)
+        .and_then(|n| n.parse::<f32>().ok())
+        .unwrap_or_else(|| panic!("Failed to read {what}."))
+    ).collect()
+}
+
+/// Parses light line, `count` floats are required and the rest are optional with zero default
+fn parse_light(s: &[&str], count: usize, optional: usize) -> Vec<f32> {
+    let mut values = parse_floats(s, count, &format!("{} light", s[0]));
+    values.extend((count + 1..=count + optional).map(|i| s.get(i)
+        .map_or(0.0, |n| n.parse::<f32>().unwrap_or_else(|_| panic!("Failed to read {} light.", s[0])))
+    ));
+    values
+}
+
+/// Everything read from scene file
+pub struct LoadedScene {
+    pub meshes: Vec<Mesh>,
+    pub camera: Camera,
+    pub environment: EnvironmentLight,
+    pub lights: Vec<PunctualLight>,
+    pub fog: Option<Fog>,
+}
+
+pub fn load_scene(path: &str) -> LoadedScene {
     let file = File::open(path).unwrap_or_else(|_|
         panic!("Failed to load scene file \"{}\". Reason: Not found", &path)
     );
@@ -20,12 +123,31 @@ pub fn load_scene(path: &str) -> (Vec<Triangle>, Camera) {
     
     let mut is_reading_model = false;
     let mut is_reading_camera = false;
+    let mut is_reading_environment = false;
+    let mut environment = EnvironmentDescription::default();
+    let mut is_reading_lights = false;
+    let mut lights: Vec<PunctualLight> = vec![];
+    let mut is_reading_fog = false;
+    let mut fog = FogDescription::default();
+    let mut is_reading_volume = false;
+    let mut volumes: Vec<VolumeDescription> = vec![];
     reader.lines().map_while(Result::ok).filter(|x| !(x.starts_with('#') || x.is_empty())).for_each(|ref x| {
         match x.to_lowercase().as_str() {
             "model[" => is_reading_model = true,
             "]model" =>  is_reading_model = false,
             "camera[" =>  is_reading_camera = true,
             "]camera" =>  is_reading_camera = false,
+            "environment[" => is_reading_environment = true,
+            "]environment" => is_reading_environment = false,
+            "light[" => is_reading_lights = true,
+            "]light" => is_reading_lights = false,
+            "fog[" => is_reading_fog = true,
+            "]fog" => is_reading_fog = false,
+            "volume[" => {
+                is_reading_volume = true;
+                volumes.push(VolumeDescription::default());
+            },
+            "]volume" => is_reading_volume = false,
             _ => {
                 if is_reading_model && x.ends_with(".obj") {
                     models.push(x.clone());
@@ -79,14 +201,103 @@ pub fn load_scene(path: &str) -> (Vec<Triangle>, Camera) {
                         _ => ()
                     }
                 }
+                if is_reading_environment {
+                    let s: Vec<&str> = x.split_whitespace().collect();
+                    match s.first() {
+                        Some(&"t") => environment.texture = s.get(1).map(|x| x.to_string()),
+                        Some(&"c") => {
+                            let c = parse_floats(&s, 3, "color of the environment");
+                            environment.color = Some(Vector3::new(c[0], c[1], c[2]));
+                        },
+                        Some(&"g") => {
+                            let c = parse_floats(&s, 6, "gradient of the environment");
+                            environment.gradient = Some((Vector3::new(c[0], c[1], c[2]), Vector3::new(c[3], c[4], c[5])));
+                        },
+                        Some(&"s") => {
+                            let v = parse_floats(&s, 4, "sky of the environment");
+                            let sun_direction = SkyModel::sun_direction_from_angles(v[0].to_radians(), v[1].to_radians());
+                            environment.sky = Some(SkyModel::new(sun_direction, v[2], Vector3::repeat(v[3])));
+                        },
+                        Some(&"y") => environment.yaw = parse_floats(&s, 1, "yaw of the environment")[0],
+                        Some(&"i") => environment.intensity = Some(parse_floats(&s, 1, "intensity of the environment")[0]),
+                        Some(&"v") => {
+                            let v = parse_floats(&s, 2, "visibility of the environment");
+                            environment.visibility = Some((v[0] != 0.0, v[1] != 0.0));
+                        },
+                        _ => ()
+                    }
+                }
+                if is_reading_fog {
+                    let s: Vec<&str> = x.split_whitespace().collect();
+                    match s.first() {
+                        Some(&"a") => {
+                            let c = parse_floats(&s, 3, "absorption of the fog");
+                            fog.absorption = Some(Vector3::new(c[0], c[1], c[2]));
+                        },
+                        Some(&"s") => {
+                            let c = parse_floats(&s, 3, "scattering of the fog");
+                            fog.scattering = Some(Vector3::new(c[0], c[1], c[2]));
+                        },
+                        Some(&"g") => fog.anisotropy = parse_floats(&s, 1, "anisotropy of the fog")[0],
+                        Some(&"d") => fog.distance = Some(parse_floats(&s, 1, "distance of the fog")[0]),
+                        _ => ()
+                    }
+                }
+                if let Some(volume) = volumes.last_mut().filter(|_| is_reading_volume) {
+                    let s: Vec<&str> = x.split_whitespace().collect();
+                    let vector = |v: &[f32]| Vector3::new(v[0], v[1], v[2]);
+                    match s.first() {
+                        Some(&"f") => volume.file = s.get(1).map(|x| x.to_string()),
+                        Some(&"r") => {
+                            let v = parse_floats(&s, 3, "resolution of the volume");
+                            volume.resolution = Some([v[0] as usize, v[1] as usize, v[2] as usize]);
+                        },
+                        Some(&"b") => {
+                            let v = parse_floats(&s, 6, "bounds of the volume");
+                            volume.bounds = Some((vector(&v[0..3]), vector(&v[3..6])));
+                        },
+                        Some(&"a") => volume.absorption = vector(&parse_floats(&s, 3, "absorption of the volume")),
+                        Some(&"s") => volume.scattering = vector(&parse_floats(&s, 3, "scattering of the volume")),
+                        Some(&"g") => volume.anisotropy = parse_floats(&s, 1, "anisotropy of the volume")[0],
+                        _ => ()
+                    }
+                }
+                if is_reading_lights {
+                    let mut s: Vec<&str> = x.split_whitespace().collect();
+                    // Optional candela distribution at the end of the line
+                    let profile = s.iter().position(|x| x.eq_ignore_ascii_case("ies")).map(|i| {
+                        let path = s.get(i + 1).unwrap_or_else(|| panic!("Failed to read IES file of {} light.", s[0]));
+                        let profile = IesProfile::load(Path::new(path)).unwrap_or_else(|x| panic!("{x}"));
+                        s.truncate(i);
+                        Arc::new(profile)
+                    });
+                    let vector = |v: &[f32]| Vector3::new(v[0], v[1], v[2]);
+                    match s.first().map(|x| x.to_lowercase()).as_deref() {
+                        Some("point") => {
+                            let v = parse_light(&s, 6, 1);
+                            lights.push(PunctualLight::point(vector(&v[0..3]), vector(&v[3..6]), v[6]).with_profile(profile));
+                        },
+                        Some("spot") => {
+                            let v = parse_light(&s, 11, 1);
+                            lights.push(PunctualLight::spot(vector(&v[0..3]), vector(&v[3..6]), vector(&v[6..9]),
+                                v[9].to_radians(), v[10].to_radians(), v[11]).with_profile(profile));
+                        },
+                        Some("directional") => {
+                            let v = parse_light(&s, 6, 1);
+                            lights.push(PunctualLight::directional(vector(&v[0..3]), vector(&v[3..6]), v[6].to_radians()));
+                        },
+                        _ => ()
+                    }
+                }
             }
         }
     });
 
     println!("{:?}", models);
-    let mut triangles: Vec<Triangle> = Vec::new();
+    let mut meshes: Vec<Mesh> = Vec::new();
     for m in models.iter() {
-        triangles.extend(load_model(m));
+        meshes.extend(load_model(m));
     }
-    (triangles, camera)
+    meshes.extend(volumes.into_iter().map(VolumeDescription::into_mesh));
+    LoadedScene { meshes, camera, environment: environment.into_light(), lights, fog: fog.into_fog() }
 }
\ No newline at end of file
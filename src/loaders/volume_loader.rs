@@ -0,0 +1,131 @@
+use std::{fs, path::Path, sync::Arc};
+use nalgebra::Vector3;
+use crate::{entity::mesh::Mesh, material::Material};
+
+/// Magic bytes of volume file with header
+const VOLUME_MAGIC: &[u8; 8] = b"RTVOLUME";
+
+/// Voxel densities read from file, x changes the fastest
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelData {
+    pub resolution: [usize; 3],
+    pub density: Vec<f32>,
+    /// Minimum and maximum corners stored in the file
+    pub bounds: Option<(Vector3<f32>, Vector3<f32>)>,
+}
+
+pub fn load_voxels(path: &Path, resolution: Option<[usize; 3]>) -> Result<VoxelData, String> {
+    let bytes = fs::read(path).map_err(|x| format!("Failed to load volume file \"{}\". Reason: {}", path.display(), x))?;
+    parse_voxels(&bytes, resolution)
+}
+
+/// Reads file with header or raw densities of `resolution`.
+///
+/// Header is `RTVOLUME` followed by resolution as three u32 and bounds as six f32, then f32 densities follow.
+/// Raw files have either f32 densities or u8 densities, which are mapped to 0..1.
+/// All numbers are little endian.
+pub fn parse_voxels(bytes: &[u8], resolution: Option<[usize; 3]>) -> Result<VoxelData, String> {
+    let read_u32 = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let read_f32 = |offset: usize| f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+    if bytes.starts_with(VOLUME_MAGIC) {
+        const HEADER_SIZE: usize = 8 + 3 * 4 + 6 * 4;
+        if bytes.len() < HEADER_SIZE {
+            return Err("Volume file ends inside of header".to_string());
+        }
+        let resolution = [0, 1, 2].map(|i| read_u32(8 + i * 4) as usize);
+        let min = Vector3::from_fn(|i, _| read_f32(20 + i * 4));
+        let max = Vector3::from_fn(|i, _| read_f32(32 + i * 4));
+        let count = density_count(&resolution)?;
+        if Some(bytes.len()) != count.checked_mul(4).and_then(|x| x.checked_add(HEADER_SIZE)) {
+            return Err(format!("Volume file must have {count} densities for resolution {resolution:?}"));
+        }
+        let density = (0..count).map(|i| read_f32(HEADER_SIZE + i * 4)).collect();
+        return Ok(VoxelData { resolution, density, bounds: Some((min, max)) });
+    }
+
+    let resolution = resolution.ok_or("Raw volume file needs resolution")?;
+    let count = density_count(&resolution)?;
+    let density = if Some(bytes.len()) == count.checked_mul(4) {
+        (0..count).map(|i| read_f32(i * 4)).collect()
+    } else if bytes.len() == count {
+        bytes.iter().map(|x| *x as f32 / 255.0).collect()
+    } else {
+        return Err(format!("Raw volume file size doesn't match resolution {resolution:?}"));
+    };
+    Ok(VoxelData { resolution, density, bounds: None })
+}
+
+/// Number of voxels, every axis must have at least one
+fn density_count(resolution: &[usize; 3]) -> Result<usize, String> {
+    if resolution.contains(&0) {
+        return Err(format!("Volume resolution {resolution:?} must not be zero"));
+    }
+    resolution.iter().try_fold(1usize, |count, x| count.checked_mul(*x))
+        .ok_or_else(|| format!("Volume resolution {resolution:?} is too big"))
+}
+
+/// Box mesh between `min` and `max` with faces pointing outside, used as boundary of volume
+pub fn box_mesh(min: &Vector3<f32>, max: &Vector3<f32>, material: Arc<Material>) -> Mesh {
+    let corner = |i: u32| Vector3::new(
+        if i & 1 == 0 { min.x } else { max.x },
+        if i & 2 == 0 { min.y } else { max.y },
+        if i & 4 == 0 { min.z } else { max.z },
+    );
+    let positions = (0..8).map(corner).collect();
+    // Quads are counter clockwise seen from outside
+    let quads = [[0, 4, 6, 2], [1, 3, 7, 5], [0, 1, 5, 4], [2, 6, 7, 3], [0, 2, 3, 1], [4, 5, 7, 6]];
+    let indices = quads.iter().flat_map(|[a, b, c, d]| [[*a, *b, *c], [*a, *c, *d]]).collect();
+    Mesh::new(positions, vec![], vec![], indices, material)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use crate::{entity::geometry::Geometry, material::Material};
+    use super::{parse_voxels, box_mesh, VOLUME_MAGIC};
+
+    #[test]
+    fn voxel_files() {
+        let mut bytes = VOLUME_MAGIC.to_vec();
+        [2u32, 1, 1].iter().for_each(|x| bytes.extend(x.to_le_bytes()));
+        [-1.0f32, 0.0, 0.0, 1.0, 1.0, 2.0, 0.25, 0.75].iter().for_each(|x| bytes.extend(x.to_le_bytes()));
+        let voxels = parse_voxels(&bytes, None).unwrap();
+        assert_eq!(voxels.resolution, [2, 1, 1]);
+        assert_eq!(voxels.density, vec![0.25, 0.75]);
+        assert_eq!(voxels.bounds, Some((Vector3::new(-1.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 2.0))));
+        assert!(parse_voxels(&bytes[..bytes.len() - 1], None).is_err());
+
+        // Raw files of bytes and floats
+        let voxels = parse_voxels(&[0, 255, 51, 0], Some([2, 2, 1])).unwrap();
+        assert_eq!(voxels.density, vec![0.0, 1.0, 0.2, 0.0]);
+        assert_eq!(voxels.bounds, None);
+        let floats: Vec<u8> = [0.5f32, 2.0].iter().flat_map(|x| x.to_le_bytes()).collect();
+        assert_eq!(parse_voxels(&floats, Some([1, 1, 2])).unwrap().density, vec![0.5, 2.0]);
+        assert!(parse_voxels(&floats, None).is_err());
+        assert!(parse_voxels(&floats, Some([3, 1, 1])).is_err());
+
+        // Zero and overflowing resolutions are errors
+        assert!(parse_voxels(&[], Some([0, 1, 1])).is_err());
+        assert!(parse_voxels(&floats, Some([usize::MAX, 2, 1])).is_err());
+        assert!(parse_voxels(&floats, Some([usize::MAX / 2, 1, 1])).is_err());
+        let mut header = VOLUME_MAGIC.to_vec();
+        [0u32, 1, 1].iter().for_each(|x| header.extend(x.to_le_bytes()));
+        [0.0f32; 6].iter().for_each(|x| header.extend(x.to_le_bytes()));
+        assert!(parse_voxels(&header, None).is_err());
+    }
+
+    #[test]
+    fn box_faces_outside() {
+        let min = Vector3::new(-1.0, 0.0, -2.0);
+        let max = Vector3::new(1.0, 3.0, 2.0);
+        let geometry = Geometry::new(vec![box_mesh(&min, &max, Material::default().into())]);
+        let center = (min + max) * 0.5;
+        for triangle in &geometry.triangles {
+            let triangle = geometry.triangle(triangle);
+            let [a, b, c] = triangle.vertices();
+            let outside = (a + b + c) / 3.0 - center;
+            assert!(triangle.plane_normal().dot(&outside) > 0.0);
+        }
+    }
+}
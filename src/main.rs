@@ -2,8 +2,6 @@ use std::{env, time::{Instant, Duration}, sync::{Arc, Mutex, Condvar, atomic::At
 use std::thread;
 use std::sync::atomic::Ordering;
 use rtracer::math::extensions::u32_from_u8_rgb;
-use rtracer::textures::texture::TextureSamplingMode;
-use rtracer::textures::extensions_f32::file_to_texture;
 use nalgebra::{Vector3, Vector2};
 use rtracer::camera::Camera;
 use minifb::{Key, Window, WindowOptions};
@@ -34,14 +32,14 @@ fn time_since_startup(start_time: Instant) -> f32 {
 #[allow(clippy::too_many_arguments)]
 fn print_times(accumulation_time: Duration, total_frame_elapsed: Duration,
         main_thread_wait_elapsed: Duration, render_thread_wait_elapsed: Duration,
-        rays_count: u64,
+        rays_count: u64, shadow_rays_count: u64,
         logic_elapsed: Duration, render_elapsed: Duration,
         window_draw_elapsed: Duration, sample_count: u32,
         clear_console: bool) {
     // Remove previous lines
     if clear_console {
         // Let's not talk about it
-        for _ in 0..11 {
+        for _ in 0..12 {
             print!("\x1B[1A\x1B[K");
         }
     }
@@ -49,6 +47,7 @@ fn print_times(accumulation_time: Duration, total_frame_elapsed: Duration,
     println!("Render time: {accumulation_time:.2?}\nSample count: {sample_count:?}");
     println!("Current frame timings:\nTotal: {total_frame_elapsed:.2?}");
     println!("MegaRays/Second: {:.3}", rays_count as f32 * 1e-6);
+    println!("Shadow MegaRays/Second: {:.3}", shadow_rays_count as f32 * 1e-6);
     println!("Render: {render_elapsed:.2?}\nWait for main thread: {render_thread_wait_elapsed:.2?}");
     println!("Wait for render thread: {main_thread_wait_elapsed:.2?}\nWindow: {window_draw_elapsed:.2?}\nLogic: {logic_elapsed:.2?}\n");
 }
@@ -85,6 +84,7 @@ Argument syntax:
     [-SampleCount]
     [-ImageWidth] [-ImageHeight]
     [-ImageWidth] [-ImageHeight] [-SampleCount]
+    Any of the above followed by [wide] to trace with 4-wide BVH
 
     If no arguments passed SampleCount is set to 0, ImageWidth and ImageHeight is 800
     If SampleCount is 0, render is infinite.
@@ -101,7 +101,12 @@ fn main() {
     let mut max_samples = 0u32;
 
     // Get command line arguments
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // Optional last argument enables wide BVH
+    let wide_bvh = args.len() > 1 && args[args.len() - 1].to_lowercase() == "wide";
+    if wide_bvh {
+        args.pop();
+    }
     
     // Print help text
     if args.len() == 2 && args[1].to_lowercase() == "help" {
@@ -149,15 +154,19 @@ fn main() {
     let gamma_lut = GammaLut::new(32, 2.2);
 
     // Create scene
-    let (loaded_geometry, mut camera) = load_scene("scene.rts");
-    let mut scene_data = SceneData::new(loaded_geometry);
-    println!("Triangle count: {}", scene_data.objects.len());
+    let scene = load_scene("scene.rts");
+    let mut camera = scene.camera;
+    let environment = scene.environment;
+    let mut scene_data = SceneData::new(scene.meshes);
+    scene_data.lights = scene.lights;
+    scene_data.fog = scene.fog;
+    println!("Triangle count: {}\nVertex count: {}", scene_data.primitives.geometry.triangles.len(), scene_data.primitives.geometry.vertex_count());
     // Calculate bvh for loaded scene
-    scene_data.calculate_bvh();
+    scene_data.calculate_bvh_cached(Path::new("bvh_cache"));
+    if wide_bvh {
+        scene_data.calculate_wide_bvh();
+    }
 
-    // Load skybox image
-    let skybox_texture = file_to_texture(Path::new("sunset_in_the_chalk_quarry_4k.exr"), TextureSamplingMode::Repeat);
-    
     // Setup camera
     camera.screen_width = imgx as u16;
     camera.screen_height = imgy as u16;
@@ -176,7 +185,7 @@ fn main() {
     // Limit window fps to 120
     //window.limit_update_rate(Some(Duration::from_secs_f32(1.0 / 120.0)));
 
-    let render = Render::new(imgx, imgy, skybox_texture);
+    let render = Render::new(imgx, imgy, environment);
     let accumulation_time = Instant::now();
     let accumulated_time = Duration::ZERO;
     let render_elapsed = Duration::ZERO;
@@ -211,7 +220,7 @@ fn main() {
     let condvar = Arc::new(Condvar::new());
 
     println!();
-    print_times(accumulation_time.elapsed(), total_frame_elapsed, main_thread_wait_elapsed, render_wait_elapsed, 0, logic_elapsed, render_elapsed, window_draw_elapsed, 0, false);
+    print_times(accumulation_time.elapsed(), total_frame_elapsed, main_thread_wait_elapsed, render_wait_elapsed, 0, 0, logic_elapsed, render_elapsed, window_draw_elapsed, 0, false);
     
     // Clone references to the mutex and condition variable for the thread to use
     let thread_render_data = Arc::clone(&render_data);
@@ -289,10 +298,12 @@ fn main() {
                 print_times(data.accumulated_time, total_frame_elapsed,
                     main_thread_wait_elapsed, data.render_wait_elapsed,
                     data.scene_data.rays_count.load(Ordering::Relaxed),
+                    data.scene_data.shadow_rays_count.load(Ordering::Relaxed),
                     logic_elapsed, data.render_elapsed, window_draw_elapsed,
                     data.render.get_accumulated_frames_count(), true);
 
                 data.scene_data.rays_count.store(0, Ordering::Relaxed);
+                data.scene_data.shadow_rays_count.store(0, Ordering::Relaxed);
                 total_frame_elapsed = Duration::ZERO;
                 data.render_wait_elapsed = Duration::ZERO;
                 main_thread_wait_elapsed = Duration::ZERO;
@@ -1,20 +1,188 @@
-use nalgebra::Vector3;
+use std::sync::Arc;
+use nalgebra::{Vector3, Vector2};
 
+use crate::loaders::ies_loader::IesProfile;
+use crate::media::{Medium, homogeneous::HomogeneousMedium};
 use crate::textures::texture::Texture;
+use crate::math::extensions::f32_vector3_from_u32;
+
+/// Sides of emissive triangles which emit light
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmissionSides {
+    #[default]
+    Both,
+    /// Only the side with counter clockwise winding, plane normal of triangle points to it
+    Front,
+}
+
+/// Random walk subsurface scattering, replaces the diffuse lobe of material
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Subsurface {
+    /// Average distance light travels inside between scattering events, per channel
+    pub mean_free_path: Vector3<f32>,
+    /// Henyey-Greenstein anisotropy of scattering inside
+    pub anisotropy: f32,
+}
+
+impl Subsurface {
+    #[inline]
+    pub fn new(mean_free_path: Vector3<f32>, anisotropy: f32) -> Self {
+        Subsurface { mean_free_path, anisotropy }
+    }
+
+    /// Medium under the surface with single scattering albedo chosen so that light leaving
+    /// the surface after multiple scattering has `albedo`.
+    /// Inverse of multiple scattering albedo approximation by van de Hulst.
+    pub fn medium(&self, albedo: &Vector3<f32>) -> HomogeneousMedium {
+        let extinction = self.mean_free_path.map(|x| 1.0 / x.max(1e-4));
+        let single_scattering_albedo = albedo.map(|a| {
+            let a = a.clamp(0.0, 0.999);
+            let x = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+            (1.0 - x * x).clamp(0.0, 1.0)
+        });
+        let scattering = extinction.component_mul(&single_scattering_albedo);
+        HomogeneousMedium::new(extinction - scattering, scattering, self.anisotropy)
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct Material {
     pub albedo: Vector3<f32>,
+    /// Emitted radiance, multiplies `emission_tex` if material has it
     pub emission: Vector3<f32>,
     pub roughness: f32,
     pub metallic: f32,
-    pub albedo_tex: Option<Texture<u32>>
+    pub albedo_tex: Option<Texture<u32>>,
+    pub emission_tex: Option<Texture<u32>>,
+    pub emission_sides: EmissionSides,
+    /// Candela distribution modulating emission by direction, nadir is along -y in world space
+    pub emission_profile: Option<Arc<IesProfile>>,
+    /// Medium inside of closed mesh, surface of such mesh is only a boundary which rays pass through
+    pub interior_medium: Option<Medium>,
+    pub subsurface: Option<Subsurface>,
 }
 
 impl Material {
     #[inline]
     pub fn new(albedo: Vector3<f32>, emission: Vector3<f32>, roughness: f32, metallic: f32,
         albedo_tex: Option<Texture<u32>>) -> Self {
-        Material { albedo, emission, roughness, metallic, albedo_tex }
+        Material { albedo, emission, roughness, metallic, albedo_tex, ..Default::default() }
+    }
+
+    #[inline]
+    pub fn with_emission_tex(mut self, emission_tex: Option<Texture<u32>>) -> Self {
+        self.emission_tex = emission_tex;
+        self
+    }
+
+    #[inline]
+    pub fn with_emission_sides(mut self, emission_sides: EmissionSides) -> Self {
+        self.emission_sides = emission_sides;
+        self
+    }
+
+    #[inline]
+    pub fn with_emission_profile(mut self, emission_profile: Option<Arc<IesProfile>>) -> Self {
+        self.emission_profile = emission_profile;
+        self
+    }
+
+    #[inline]
+    pub fn with_interior_medium(mut self, interior_medium: Option<Medium>) -> Self {
+        self.interior_medium = interior_medium;
+        self
+    }
+
+    #[inline]
+    pub fn with_subsurface(mut self, subsurface: Option<Subsurface>) -> Self {
+        self.subsurface = subsurface;
+        self
+    }
+
+    /// Returns true if material can emit anything
+    #[inline]
+    pub fn is_emissive(&self) -> bool {
+        self.emission != Vector3::zeros()
+    }
+
+    /// Radiance emitted at texture coordinates `uv` from the front or the back side
+    /// along unit `direction` leaving the surface
+    #[inline]
+    pub fn emitted(&self, uv: &Vector2<f32>, front_face: bool, direction: &Vector3<f32>) -> Vector3<f32> {
+        if !front_face && self.emission_sides == EmissionSides::Front {
+            return Vector3::zeros();
+        }
+        let emission = match &self.emission_tex {
+            Some(emission_tex) => f32_vector3_from_u32(emission_tex.sample(uv.x, uv.y)).component_mul(&self.emission),
+            None => self.emission,
+        };
+        match &self.emission_profile {
+            Some(profile) => emission * profile.relative_intensity(direction),
+            None => emission,
+        }
+    }
+
+    /// Emission averaged over the texture, used to estimate power of emitters
+    pub fn average_emission(&self) -> Vector3<f32> {
+        match &self.emission_tex {
+            Some(emission_tex) if !emission_tex.get_buffer_read().is_empty() => {
+                let buffer = emission_tex.get_buffer_read();
+                let sum: Vector3<f32> = buffer.iter().map(|x| f32_vector3_from_u32(*x)).sum();
+                (sum / buffer.len() as f32).component_mul(&self.emission)
+            },
+            _ => self.emission,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use nalgebra::{Vector3, Vector2};
+    use crate::loaders::ies_loader::IesProfile;
+    use crate::textures::texture::{Texture, TextureSamplingMode};
+    use super::{Material, EmissionSides, Subsurface};
+
+    #[test]
+    fn emission() {
+        // Left half is red, right half is black
+        let texture = Texture::from_buffer(vec![0xFF0000, 0xFF0000, 0, 0], 4, 1, TextureSamplingMode::Clamp);
+        let material = Material::new(Vector3::zeros(), Vector3::repeat(2.0), 1.0, 0.0, None)
+            .with_emission_tex(Some(texture));
+        let down = -Vector3::y();
+        assert_eq!(material.emitted(&Vector2::new(0.1, 0.5), true, &down), Vector3::new(2.0, 0.0, 0.0));
+        assert_eq!(material.emitted(&Vector2::new(0.9, 0.5), true, &down), Vector3::zeros());
+        assert_eq!(material.emitted(&Vector2::new(0.1, 0.5), false, &down), Vector3::new(2.0, 0.0, 0.0));
+        assert_eq!(material.average_emission(), Vector3::new(1.0, 0.0, 0.0));
+
+        let one_sided = Material::new(Vector3::zeros(), Vector3::repeat(2.0), 1.0, 0.0, None)
+            .with_emission_sides(EmissionSides::Front);
+        assert_eq!(one_sided.emitted(&Vector2::zeros(), true, &down), Vector3::repeat(2.0));
+        assert_eq!(one_sided.emitted(&Vector2::zeros(), false, &down), Vector3::zeros());
+        assert!(!Material::default().is_emissive());
+
+        // Profile which emits only downwards
+        let profile = IesProfile::parse("TILT=NONE\n1 1000 1 2 1 1 2 0 0 0\n1 1 10\n0 90\n0\n100 0").unwrap();
+        let directional = Material::new(Vector3::zeros(), Vector3::repeat(2.0), 1.0, 0.0, None)
+            .with_emission_profile(Some(Arc::new(profile)));
+        assert_eq!(directional.emitted(&Vector2::zeros(), true, &down), Vector3::repeat(2.0));
+        let side = Vector3::new(1.0, -1.0, 0.0).normalize();
+        assert!((directional.emitted(&Vector2::zeros(), true, &side).x - 1.0).abs() < 1e-5);
+        assert_eq!(directional.emitted(&Vector2::zeros(), true, &Vector3::x()), Vector3::zeros());
+    }
+
+    #[test]
+    fn subsurface_medium() {
+        let subsurface = Subsurface::new(Vector3::new(1.0, 0.5, 0.25), 0.0);
+        let medium = subsurface.medium(&Vector3::new(0.0, 0.5, 1.0));
+        assert_eq!(medium.extinction(), Vector3::new(1.0, 2.0, 4.0));
+        // Black surface absorbs everything, white one scatters almost everything
+        assert!(medium.scattering.x < 1e-4);
+        assert!(medium.scattering.z / medium.extinction().z > 0.99);
+        // Single scattering albedo is higher than the multiple scattering one
+        let single = medium.scattering.y / medium.extinction().y;
+        assert!(single > 0.5 && single < 0.99, "{single}");
+        let brighter = subsurface.medium(&Vector3::repeat(0.7));
+        assert!(brighter.scattering.y / brighter.extinction().y > single);
     }
 }
@@ -0,0 +1,182 @@
+/// Piecewise constant distribution over `[0, 1)` made of equal sized cells
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    /// Not normalized weights of cells
+    function: Vec<f32>,
+    /// Cumulative distribution at cell borders, `cdf[0] = 0` and `cdf[n] = 1`
+    cdf: Vec<f32>,
+    /// Mean of `function` over the domain
+    integral: f32,
+}
+
+impl Distribution1D {
+    /// Uniform distribution is used if all weights are zero
+    pub fn new(function: Vec<f32>) -> Self {
+        assert!(!function.is_empty(), "Distribution needs at least one cell");
+        let count = function.len();
+        let mut cdf = Vec::with_capacity(count + 1);
+        cdf.push(0.0);
+        let mut sum = 0.0f64;
+        for weight in &function {
+            sum += *weight as f64 / count as f64;
+            cdf.push(sum as f32);
+        }
+        let integral = sum as f32;
+        if integral > 0.0 {
+            cdf.iter_mut().for_each(|x| *x /= integral);
+            // Rounding must not leave space for zero weight cells at the end
+            if let Some(last) = function.iter().rposition(|x| *x > 0.0) {
+                cdf[last + 1..].fill(1.0);
+            }
+        } else {
+            cdf.iter_mut().enumerate().for_each(|(i, x)| *x = i as f32 / count as f32);
+        }
+        Distribution1D { function, cdf, integral }
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.function.len()
+    }
+
+    #[inline]
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    /// Probability density of cell `index`, relative to `[0, 1)` domain
+    #[inline]
+    pub fn pdf(&self, index: usize) -> f32 {
+        if self.integral > 0.0 {
+            self.function[index] / self.integral
+        } else {
+            1.0
+        }
+    }
+
+    /// Maps uniform `u` from `[0, 1)` to (position in `[0, 1)`, pdf, cell index)
+    #[inline]
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        // Last border not greater than `u`, cells with zero weight are never picked
+        let index = (self.cdf.partition_point(|x| *x <= u) - 1).min(self.count() - 1);
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = if width > 0.0 { ((u - self.cdf[index]) / width).clamp(0.0, 1.0) } else { 0.5 };
+        let position = ((index as f32 + offset) / self.count() as f32).min(1.0 - f32::EPSILON);
+        (position, self.pdf(index), index)
+    }
+}
+
+/// Piecewise constant distribution over `[0, 1)²`, sampled by rows with marginal distribution
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    /// Distribution of `x` in every row
+    conditionals: Vec<Distribution1D>,
+    /// Distribution of rows
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `function` is `width * height` weights stored by rows
+    pub fn new(function: &[f32], width: usize, height: usize) -> Self {
+        assert_eq!(function.len(), width * height, "Distribution size doesn't match weights count");
+        let conditionals: Vec<Distribution1D> = function.chunks(width).map(|x| Distribution1D::new(x.to_vec())).collect();
+        let marginal = Distribution1D::new(conditionals.iter().map(|x| x.integral()).collect());
+        Distribution2D { conditionals, marginal }
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.conditionals[0].count()
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.marginal.count()
+    }
+
+    /// Mean of weights over the domain
+    #[inline]
+    pub fn integral(&self) -> f32 {
+        self.marginal.integral()
+    }
+
+    /// Maps uniform `u` and `v` to (`x`, `y`, pdf)
+    #[inline]
+    pub fn sample_continuous(&self, u: f32, v: f32) -> (f32, f32, f32) {
+        let (y, y_pdf, row) = self.marginal.sample_continuous(v);
+        let (x, x_pdf, _) = self.conditionals[row].sample_continuous(u);
+        (x, y, x_pdf * y_pdf)
+    }
+
+    /// Probability density of point in `[0, 1)²`
+    #[inline]
+    pub fn pdf(&self, x: f32, y: f32) -> f32 {
+        let column = ((x * self.width() as f32) as usize).min(self.width() - 1);
+        let row = ((y * self.height() as f32) as usize).min(self.height() - 1);
+        self.marginal.pdf(row) * self.conditionals[row].pdf(column)
+    }
+}
+
+/// Multiple importance sampling weight of strategy with `pdf` against strategy with `other_pdf`.
+/// "Optimally Combining Sampling Techniques for Monte Carlo Rendering", Veach, Guibas, 1995.
+#[inline(always)]
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (pdf, other_pdf) = (pdf * pdf, other_pdf * other_pdf);
+    if pdf.is_infinite() {
+        return 1.0;
+    }
+    if pdf + other_pdf > 0.0 { pdf / (pdf + other_pdf) } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::math::pcg;
+    use super::{Distribution1D, Distribution2D, power_heuristic};
+
+    #[test]
+    fn distribution_1d() {
+        let distribution = Distribution1D::new(vec![1.0, 0.0, 3.0, 0.0]);
+        assert_eq!(distribution.integral(), 1.0);
+        assert_eq!(distribution.pdf(0), 1.0);
+        assert_eq!(distribution.pdf(2), 3.0);
+        assert_eq!(distribution.sample_continuous(0.0), (0.0, 1.0, 0));
+        assert_eq!(distribution.sample_continuous(0.125), (0.125, 1.0, 0));
+        // Cells with zero weight are skipped
+        assert_eq!(distribution.sample_continuous(0.25), (0.5, 3.0, 2));
+        assert_eq!(distribution.sample_continuous(0.625), (0.625, 3.0, 2));
+        assert!(distribution.sample_continuous(0.9999).0 < 0.75);
+    }
+
+    #[test]
+    fn zero_distribution_is_uniform() {
+        let distribution = Distribution1D::new(vec![0.0; 4]);
+        assert_eq!(distribution.sample_continuous(0.6), (0.6, 1.0, 2));
+        assert_eq!(distribution.pdf(3), 1.0);
+    }
+
+    #[test]
+    fn distribution_2d() {
+        let weights = [0.0, 1.0, 2.0, 5.0, 0.0, 0.0];
+        let distribution = Distribution2D::new(&weights, 3, 2);
+        let mut seed = 3;
+        let mut counts = [0; 6];
+        let samples = 100_000;
+        for _ in 0..samples {
+            let (x, y, pdf) = distribution.sample_continuous(pcg::random_f32(&mut seed), pcg::random_f32(&mut seed));
+            assert!((distribution.pdf(x, y) - pdf).abs() < 1e-4);
+            counts[(y * 2.0) as usize * 3 + (x * 3.0) as usize] += 1;
+        }
+        // Frequency of cells is proportional to weights
+        for (count, weight) in counts.iter().zip(weights) {
+            assert!((*count as f32 / samples as f32 - weight / 8.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn power_heuristic_weights() {
+        assert_eq!(power_heuristic(1.0, 1.0), 0.5);
+        assert_eq!(power_heuristic(3.0, 1.0) + power_heuristic(1.0, 3.0), 1.0);
+        assert_eq!(power_heuristic(f32::INFINITY, 1.0), 1.0);
+        assert_eq!(power_heuristic(0.0, 0.0), 0.0);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod ray;
 pub mod extensions;
-pub mod pcg;
\ No newline at end of file
+pub mod pcg;
+pub mod distribution;
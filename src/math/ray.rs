@@ -6,18 +6,37 @@ pub struct Ray {
     pub origin: Vector3<f32>,
     direction: Vector3<f32>,
     frac_direction: Vector3<f32>,
+    /// Hits are valid only for `t_min < t < t_max`
+    pub t_min: f32,
+    pub t_max: f32,
 }
 
 #[allow(dead_code)]
 impl Ray {
     #[inline]
     pub fn new(origin: Vector3<f32>, direction: Vector3<f32>) -> Self {
+        Self::with_interval(origin, direction, 0.0, f32::INFINITY)
+    }
+
+    /// Ray which hits only objects with `t_min < t < t_max`
+    #[inline]
+    pub fn with_interval(origin: Vector3<f32>, direction: Vector3<f32>, t_min: f32, t_max: f32) -> Self {
         Ray {
             origin,
             direction,
-            frac_direction: Vector3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z)
+            frac_direction: Vector3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z),
+            t_min,
+            t_max
         }
     }
+
+    /// Ray from `from` to `to`, which doesn't hit anything beyond `to`
+    #[inline]
+    pub fn segment(from: Vector3<f32>, to: Vector3<f32>) -> Self {
+        let direction = to - from;
+        let length = direction.norm();
+        Self::with_interval(from, direction / length, 0.0, length)
+    }
     
     #[inline]
     pub fn set_direction(&mut self, direction: &Vector3<f32>) {
@@ -34,4 +53,51 @@ impl Ray {
     pub fn get_frac_direction(&self) -> &Vector3<f32> {
         &self.frac_direction
     }
-}
\ No newline at end of file
+}
+
+/// Moves ray origin from the surface along geometric `normal` to avoid self intersection.
+/// Offset is a few ulps and scales with the magnitude of `point`.
+/// "A Fast and Robust Method for Avoiding Self-Intersection", Ray Tracing Gems, chapter 6.
+#[inline]
+pub fn offset_ray_origin(point: &Vector3<f32>, normal: &Vector3<f32>) -> Vector3<f32> {
+    const ORIGIN: f32 = 1.0 / 32.0;
+    const FLOAT_SCALE: f32 = 1.0 / 65536.0;
+    const INT_SCALE: f32 = 256.0;
+    Vector3::from_fn(|i, _| {
+        let p = point[i];
+        let n = normal[i];
+        if p.abs() < ORIGIN {
+            return p + FLOAT_SCALE * n;
+        }
+        let offset = (INT_SCALE * n) as i32;
+        let offset = if p < 0.0 { -offset } else { offset };
+        f32::from_bits((p.to_bits() as i32).wrapping_add(offset) as u32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use super::{Ray, offset_ray_origin};
+
+    #[test]
+    fn offset_origin_leaves_surface() {
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        for point in [Vector3::new(0.0, 0.0, 0.0), Vector3::new(5.0, 1000.0, -3.0), Vector3::new(1.0, -2000.0, 1.0)] {
+            let offset = offset_ray_origin(&point, &normal);
+            assert!(offset.y > point.y);
+            assert_eq!(offset.x, point.x);
+            assert_eq!(offset.z, point.z);
+            // Only a few ulps away
+            assert!(offset.y - point.y <= point.y.abs().max(1.0) * 1e-4);
+        }
+    }
+
+    #[test]
+    fn segment_interval() {
+        let ray = Ray::segment(Vector3::new(1.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 4.0));
+        assert_eq!(*ray.get_direction(), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(ray.t_min, 0.0);
+        assert_eq!(ray.t_max, 4.0);
+    }
+}
@@ -0,0 +1,311 @@
+use std::ops::ControlFlow;
+use nalgebra::Vector3;
+use crate::math::pcg;
+use super::{MediumEvent, Collision, collide, phase::HenyeyGreenstein};
+
+/// Size of majorant grid cell in voxels along every axis
+const MAJORANT_CELL_SIZE: usize = 8;
+/// Ratio tracking plays russian roulette below this transmittance
+const ROULETTE_TRANSMITTANCE: f32 = 0.1;
+
+/// Heterogeneous medium with densities in voxel grid stretched over axis aligned box.
+/// Coefficients are per unit of density, density is interpolated between voxel centers.
+#[derive(Debug, Clone)]
+pub struct GridMedium {
+    resolution: [usize; 3],
+    /// Densities with x changing the fastest
+    density: Vec<f32>,
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+    majorant_resolution: [usize; 3],
+    /// The largest density which can be interpolated inside each majorant cell
+    majorants: Vec<f32>,
+    pub absorption: Vector3<f32>,
+    pub scattering: Vector3<f32>,
+    pub phase: HenyeyGreenstein,
+}
+
+impl GridMedium {
+    pub fn new(resolution: [usize; 3], density: Vec<f32>, min: Vector3<f32>, max: Vector3<f32>,
+        absorption: Vector3<f32>, scattering: Vector3<f32>, g: f32) -> Self {
+        assert_eq!(density.len(), resolution.iter().product::<usize>(), "Voxel count must match grid resolution");
+        assert!(resolution.iter().all(|x| *x > 0), "Grid resolution must not be zero");
+        let density: Vec<f32> = density.into_iter().map(|x| x.max(0.0)).collect();
+        let majorant_resolution = resolution.map(|x| x.div_ceil(MAJORANT_CELL_SIZE));
+
+        // Interpolation inside of majorant cell uses one more voxel on each side
+        let mut majorants = Vec::with_capacity(majorant_resolution.iter().product());
+        for z in 0..majorant_resolution[2] {
+            for y in 0..majorant_resolution[1] {
+                for x in 0..majorant_resolution[0] {
+                    let range = |cell: usize, axis: usize| {
+                        (cell * MAJORANT_CELL_SIZE).saturating_sub(1)..((cell + 1) * MAJORANT_CELL_SIZE + 1).min(resolution[axis])
+                    };
+                    let mut majorant = 0.0f32;
+                    for vz in range(z, 2) {
+                        for vy in range(y, 1) {
+                            for vx in range(x, 0) {
+                                majorant = majorant.max(density[(vz * resolution[1] + vy) * resolution[0] + vx]);
+                            }
+                        }
+                    }
+                    majorants.push(majorant);
+                }
+            }
+        }
+
+        GridMedium {
+            resolution,
+            density,
+            min,
+            max,
+            majorant_resolution,
+            majorants,
+            absorption: absorption.map(|x| x.max(0.0)),
+            scattering: scattering.map(|x| x.max(0.0)),
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    #[inline]
+    pub fn bounds(&self) -> (Vector3<f32>, Vector3<f32>) {
+        (self.min, self.max)
+    }
+
+    #[inline]
+    pub fn resolution(&self) -> [usize; 3] {
+        self.resolution
+    }
+
+    /// Density at `point` interpolated trilinearly, zero outside of the bounds
+    pub fn density(&self, point: &Vector3<f32>) -> f32 {
+        if (0..3).any(|i| point[i] < self.min[i] || point[i] > self.max[i]) {
+            return 0.0;
+        }
+        // Voxel centers are at half coordinates
+        let grid = (point - self.min).component_div(&(self.max - self.min));
+        let mut lower = [0; 3];
+        let mut upper = [0; 3];
+        let mut t = [0.0; 3];
+        for axis in 0..3 {
+            let coordinate = grid[axis] * self.resolution[axis] as f32 - 0.5;
+            let last = self.resolution[axis] - 1;
+            let floor = coordinate.floor();
+            lower[axis] = (floor.max(0.0) as usize).min(last);
+            upper[axis] = ((floor + 1.0).max(0.0) as usize).min(last);
+            t[axis] = coordinate - floor;
+        }
+        let voxel = |x: usize, y: usize, z: usize| self.density[(z * self.resolution[1] + y) * self.resolution[0] + x];
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let row = |y: usize, z: usize| lerp(voxel(lower[0], y, z), voxel(upper[0], y, z), t[0]);
+        let plane = |z: usize| lerp(row(lower[1], z), row(upper[1], z), t[1]);
+        lerp(plane(lower[2]), plane(upper[2]), t[2])
+    }
+
+    /// Visits parts of the ray inside the bounds before `t_max`, one per majorant cell in order along the ray.
+    /// `f` gets the start and the end of the part and the largest density there.
+    fn majorant_segments<B>(&self, origin: &Vector3<f32>, direction: &Vector3<f32>, t_max: f32,
+        mut f: impl FnMut(f32, f32, f32) -> ControlFlow<B>) -> ControlFlow<B> {
+        // Clip ray by the bounds
+        let mut t_enter = 0.0f32;
+        let mut t_exit = t_max;
+        for axis in 0..3 {
+            let inverse = 1.0 / direction[axis];
+            let t0 = (self.min[axis] - origin[axis]) * inverse;
+            let t1 = (self.max[axis] - origin[axis]) * inverse;
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        if t_enter >= t_exit {
+            return ControlFlow::Continue(());
+        }
+
+        // Traverse majorant cells with 3D DDA, "A Fast Voxel Traversal Algorithm", Amanatides and Woo, 1987
+        let scale = Vector3::from_fn(|i, _| self.majorant_resolution[i] as f32).component_div(&(self.max - self.min));
+        let start = (origin + direction * t_enter - self.min).component_mul(&scale);
+        let grid_direction = direction.component_mul(&scale);
+        let mut cell = [0i64; 3];
+        let mut step = [0i64; 3];
+        let mut next_t = [f32::INFINITY; 3];
+        let mut delta_t = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            cell[axis] = (start[axis].floor() as i64).clamp(0, self.majorant_resolution[axis] as i64 - 1);
+            if grid_direction[axis] > 0.0 {
+                step[axis] = 1;
+                delta_t[axis] = 1.0 / grid_direction[axis];
+                next_t[axis] = t_enter + ((cell[axis] + 1) as f32 - start[axis]) * delta_t[axis];
+            } else if grid_direction[axis] < 0.0 {
+                step[axis] = -1;
+                delta_t[axis] = -1.0 / grid_direction[axis];
+                next_t[axis] = t_enter + (start[axis] - cell[axis] as f32) * delta_t[axis];
+            }
+        }
+
+        let mut t = t_enter;
+        loop {
+            let axis = if next_t[0] < next_t[1] {
+                if next_t[0] < next_t[2] { 0 } else { 2 }
+            } else if next_t[1] < next_t[2] { 1 } else { 2 };
+            let t_end = next_t[axis].min(t_exit);
+            let index = (cell[2] as usize * self.majorant_resolution[1] + cell[1] as usize) * self.majorant_resolution[0] + cell[0] as usize;
+            f(t, t_end, self.majorants[index])?;
+            if t_end >= t_exit {
+                return ControlFlow::Continue(());
+            }
+            t = t_end;
+            cell[axis] += step[axis];
+            if cell[axis] < 0 || cell[axis] >= self.majorant_resolution[axis] as i64 {
+                return ControlFlow::Continue(());
+            }
+            next_t[axis] += delta_t[axis];
+        }
+    }
+
+    /// Samples the first interaction of ray from `origin` along unit `direction` before `t_max`.
+    /// Delta tracking with majorant of each cell, null collisions keep channels unbiased.
+    pub fn sample(&self, origin: &Vector3<f32>, direction: &Vector3<f32>, t_max: f32, seed: &mut u32) -> MediumEvent {
+        let max_extinction = (self.absorption + self.scattering).max();
+        let mut weight = Vector3::repeat(1.0);
+        let event = self.majorant_segments(origin, direction, t_max, |start, end, majorant_density| {
+            let majorant = majorant_density * max_extinction;
+            if majorant <= 0.0 {
+                return ControlFlow::Continue(());
+            }
+            // Exponential distribution is memoryless, so tracking restarts at each cell
+            let mut t = start;
+            loop {
+                t -= (1.0 - pcg::random_f32(seed)).ln() / majorant;
+                if t >= end {
+                    return ControlFlow::Continue(());
+                }
+                let density = self.density(&(origin + direction * t));
+                match collide(&mut weight, &(self.absorption * density), &(self.scattering * density), majorant, seed) {
+                    Collision::Absorb => return ControlFlow::Break(MediumEvent::Absorb),
+                    Collision::Scatter => return ControlFlow::Break(MediumEvent::Scatter { distance: t, weight }),
+                    Collision::Null => (),
+                }
+            }
+        });
+        match event {
+            ControlFlow::Break(event) => event,
+            ControlFlow::Continue(()) => MediumEvent::Pass { weight },
+        }
+    }
+
+    /// Unbiased estimate of light fraction passing from `origin` along unit `direction` to `distance`.
+    /// Ratio tracking with majorant of each cell, "Residual Ratio Tracking", Novák et al., 2014.
+    pub fn transmittance(&self, origin: &Vector3<f32>, direction: &Vector3<f32>, distance: f32, seed: &mut u32) -> Vector3<f32> {
+        let extinction = self.absorption + self.scattering;
+        let max_extinction = extinction.max();
+        let mut transmittance = Vector3::repeat(1.0);
+        let blocked = self.majorant_segments(origin, direction, distance, |start, end, majorant_density| {
+            let majorant = majorant_density * max_extinction;
+            if majorant <= 0.0 {
+                return ControlFlow::Continue(());
+            }
+            let mut t = start;
+            loop {
+                t -= (1.0 - pcg::random_f32(seed)).ln() / majorant;
+                if t >= end {
+                    return ControlFlow::Continue(());
+                }
+                let density = self.density(&(origin + direction * t));
+                transmittance = transmittance.component_mul(&extinction.map(|x| 1.0 - x * density / majorant));
+                // Long rays in dense media stop early without bias
+                if transmittance.max() < ROULETTE_TRANSMITTANCE {
+                    if pcg::random_f32(seed) < 0.5 {
+                        return ControlFlow::Break(());
+                    }
+                    transmittance *= 2.0;
+                }
+            }
+        });
+        if blocked.is_break() { Vector3::zeros() } else { transmittance }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use crate::media::{MediumEvent, homogeneous::HomogeneousMedium};
+    use super::GridMedium;
+
+    /// Grid over box from -1 to 1 with density growing along x
+    fn ramp_grid(resolution: usize) -> GridMedium {
+        let density = (0..resolution.pow(3)).map(|i| (i % resolution) as f32 / (resolution - 1) as f32).collect();
+        GridMedium::new([resolution; 3], density, Vector3::repeat(-1.0), Vector3::repeat(1.0),
+            Vector3::new(0.5, 0.2, 0.0), Vector3::new(1.0, 1.0, 2.0), 0.0)
+    }
+
+    #[test]
+    fn density_interpolation() {
+        let grid = ramp_grid(20);
+        assert_eq!(grid.density(&Vector3::new(-1.0, 0.0, 0.0)), 0.0);
+        assert_eq!(grid.density(&Vector3::new(1.0, 0.3, -0.7)), 1.0);
+        assert!((grid.density(&Vector3::new(0.0, 0.5, 0.5)) - 0.5).abs() < 1e-5);
+        assert_eq!(grid.density(&Vector3::new(1.5, 0.0, 0.0)), 0.0);
+        // Majorants bound every interpolated density
+        let mut seed = 3;
+        for _ in 0..1000 {
+            let point = Vector3::from_fn(|_, _| crate::math::pcg::random_f32(&mut seed) * 2.0 - 1.0);
+            let mut majorant = 0.0f32;
+            let _ = grid.majorant_segments(&point, &Vector3::x(), 1e-4, |_, _, x| {
+                majorant = x;
+                std::ops::ControlFlow::<()>::Continue(())
+            });
+            assert!(grid.density(&point) <= majorant + 1e-6);
+        }
+    }
+
+    #[test]
+    fn constant_grid_matches_homogeneous() {
+        let mut seed = 5;
+        let absorption = Vector3::new(0.2, 0.5, 0.1);
+        let scattering = Vector3::new(0.6, 0.3, 0.2);
+        let grid = GridMedium::new([10, 12, 9], vec![2.0; 1080], Vector3::repeat(-1.0), Vector3::repeat(1.0), absorption, scattering, 0.0);
+        let homogeneous = HomogeneousMedium::new(absorption * 2.0, scattering * 2.0, 0.0);
+        let origin = Vector3::new(-2.0, -0.3, 0.1);
+        let direction = Vector3::new(1.0, 0.2, -0.1).normalize();
+        // Ray enters the box at about 1 and leaves at about 3
+        let distance = 2.5;
+        let expected = homogeneous.transmittance(distance - 1.0 / direction.x);
+
+        let samples = 100000;
+        let mut ratio_tracking = Vector3::zeros();
+        let mut passed = Vector3::zeros();
+        for _ in 0..samples {
+            ratio_tracking += grid.transmittance(&origin, &direction, distance, &mut seed);
+            match grid.sample(&origin, &direction, distance, &mut seed) {
+                MediumEvent::Pass { weight } => passed += weight,
+                MediumEvent::Scatter { distance: t, .. } => assert!(t > 1.0 / direction.x - 1e-4 && t < distance),
+                MediumEvent::Absorb => (),
+            }
+        }
+        for i in 0..3 {
+            assert!((ratio_tracking[i] / samples as f32 - expected[i]).abs() < 1e-2, "{ratio_tracking} {expected}");
+            assert!((passed[i] / samples as f32 - expected[i]).abs() < 1e-2, "{passed} {expected}");
+        }
+    }
+
+    #[test]
+    fn heterogeneous_transmittance() {
+        let mut seed = 9;
+        let grid = ramp_grid(16);
+        // Along x optical depth is integral of the ramp, density is constant in voxels at the ends
+        let origin = Vector3::new(-1.5, 0.1, 0.2);
+        let direction = Vector3::x();
+        let steps = 10000;
+        let optical_depth: f32 = (0..steps).map(|i| {
+            grid.density(&(origin + direction * (0.5 + 2.0 * (i as f32 + 0.5) / steps as f32))) * 2.0 / steps as f32
+        }).sum();
+        let expected = (grid.absorption + grid.scattering).map(|x| (-x * optical_depth).exp());
+        let samples = 50000;
+        let estimate: Vector3<f32> = (0..samples).map(|_| grid.transmittance(&origin, &direction, 10.0, &mut seed)).sum::<Vector3<f32>>() / samples as f32;
+        for i in 0..3 {
+            assert!((estimate[i] - expected[i]).abs() < 1e-2, "{estimate} {expected}");
+        }
+        // Rays which miss the box pass unchanged
+        assert_eq!(grid.transmittance(&origin, &Vector3::y(), 10.0, &mut seed), Vector3::repeat(1.0));
+        assert_eq!(grid.sample(&origin, &Vector3::y(), 10.0, &mut seed), MediumEvent::Pass { weight: Vector3::repeat(1.0) });
+    }
+}
@@ -0,0 +1,106 @@
+use nalgebra::Vector3;
+use crate::math::pcg;
+use super::{MediumEvent, Collision, collide, phase::HenyeyGreenstein};
+
+/// Medium with the same coefficients everywhere, coefficients are per unit of distance
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HomogeneousMedium {
+    pub absorption: Vector3<f32>,
+    pub scattering: Vector3<f32>,
+    pub phase: HenyeyGreenstein,
+}
+
+impl HomogeneousMedium {
+    #[inline]
+    pub fn new(absorption: Vector3<f32>, scattering: Vector3<f32>, g: f32) -> Self {
+        HomogeneousMedium {
+            absorption: absorption.map(|x| x.max(0.0)),
+            scattering: scattering.map(|x| x.max(0.0)),
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    #[inline]
+    pub fn extinction(&self) -> Vector3<f32> {
+        self.absorption + self.scattering
+    }
+
+    /// Fraction of light passing `distance` through the medium
+    #[inline]
+    pub fn transmittance(&self, distance: f32) -> Vector3<f32> {
+        if distance.is_infinite() {
+            return self.extinction().map(|x| if x > 0.0 { 0.0 } else { 1.0 });
+        }
+        self.extinction().map(|x| (-x * distance).exp())
+    }
+
+    /// Samples the first interaction of ray in the medium before `t_max`.
+    /// Delta tracking against the largest extinction of all channels with spectral weights.
+    pub fn sample(&self, t_max: f32, seed: &mut u32) -> MediumEvent {
+        let majorant = self.extinction().max();
+        let mut weight = Vector3::repeat(1.0);
+        if majorant <= 0.0 {
+            return MediumEvent::Pass { weight };
+        }
+        let mut t = 0.0;
+        loop {
+            // Distance to the next collision with exponential distribution
+            t -= (1.0 - pcg::random_f32(seed)).ln() / majorant;
+            if t >= t_max {
+                return MediumEvent::Pass { weight };
+            }
+            match collide(&mut weight, &self.absorption, &self.scattering, majorant, seed) {
+                Collision::Absorb => return MediumEvent::Absorb,
+                Collision::Scatter => return MediumEvent::Scatter { distance: t, weight },
+                Collision::Null => (),
+            }
+        }
+    }
+}
+
+/// Homogeneous medium filling the whole scene outside of meshes with interior media
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fog {
+    pub medium: HomogeneousMedium,
+    /// Distance rays travel through fog before reaching the environment
+    pub distance: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use crate::media::MediumEvent;
+    use super::HomogeneousMedium;
+
+    #[test]
+    fn delta_tracking() {
+        let mut seed = 11;
+        let samples = 200000;
+        // Chromatic medium, passed weight and scattered weight are unbiased per channel
+        let medium = HomogeneousMedium::new(Vector3::new(0.1, 0.3, 0.0), Vector3::new(0.5, 0.1, 0.2), 0.0);
+        let distance = 2.0;
+        let mut passed = Vector3::zeros();
+        let mut scattered = Vector3::zeros();
+        for _ in 0..samples {
+            match medium.sample(distance, &mut seed) {
+                MediumEvent::Pass { weight } => passed += weight,
+                MediumEvent::Scatter { distance: t, weight } => {
+                    assert!(t < distance);
+                    scattered += weight;
+                },
+                MediumEvent::Absorb => (),
+            }
+        }
+        let transmittance = medium.transmittance(distance);
+        let expected_scattered = medium.scattering.component_div(&medium.extinction()).component_mul(&transmittance.map(|x| 1.0 - x));
+        for i in 0..3 {
+            assert!((passed[i] / samples as f32 - transmittance[i]).abs() < 1e-2, "{i} {passed}");
+            assert!((scattered[i] / samples as f32 - expected_scattered[i]).abs() < 1e-2, "{i} {scattered}");
+        }
+
+        // Rays always pass through empty medium and never pass infinite distance in dense one
+        assert_eq!(HomogeneousMedium::default().sample(10.0, &mut seed), MediumEvent::Pass { weight: Vector3::repeat(1.0) });
+        assert_ne!(medium.sample(f32::INFINITY, &mut seed), MediumEvent::Pass { weight: Vector3::repeat(1.0) });
+        assert_eq!(medium.transmittance(f32::INFINITY), Vector3::zeros());
+    }
+}
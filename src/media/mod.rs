@@ -0,0 +1,90 @@
+pub mod phase;
+pub mod homogeneous;
+pub mod grid;
+
+use std::sync::Arc;
+use nalgebra::Vector3;
+use crate::math::pcg;
+use homogeneous::HomogeneousMedium;
+use grid::GridMedium;
+use phase::HenyeyGreenstein;
+
+/// Result of tracking ray through medium up to the next surface
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediumEvent {
+    /// Ray is scattered at `distance`, `weight` multiplies path throughput
+    Scatter { distance: f32, weight: Vector3<f32> },
+    /// Ray is absorbed and the path ends
+    Absorb,
+    /// Ray reached the surface, `weight` multiplies path throughput
+    Pass { weight: Vector3<f32> },
+}
+
+/// Participating medium inside of a closed mesh or around meshes
+#[derive(Debug, Clone)]
+pub enum Medium {
+    Homogeneous(HomogeneousMedium),
+    Grid(Arc<GridMedium>),
+}
+
+impl Medium {
+    #[inline]
+    pub fn phase(&self) -> HenyeyGreenstein {
+        match self {
+            Medium::Homogeneous(medium) => medium.phase,
+            Medium::Grid(medium) => medium.phase,
+        }
+    }
+
+    /// Samples the first interaction of ray from `origin` along unit `direction` before `t_max`
+    #[inline]
+    pub fn sample(&self, origin: &Vector3<f32>, direction: &Vector3<f32>, t_max: f32, seed: &mut u32) -> MediumEvent {
+        match self {
+            Medium::Homogeneous(medium) => medium.sample(t_max, seed),
+            Medium::Grid(medium) => medium.sample(origin, direction, t_max, seed),
+        }
+    }
+
+    /// Fraction of light passing from `origin` along unit `direction` to `distance`, estimated for grids
+    #[inline]
+    pub fn transmittance(&self, origin: &Vector3<f32>, direction: &Vector3<f32>, distance: f32, seed: &mut u32) -> Vector3<f32> {
+        match self {
+            Medium::Homogeneous(medium) => medium.transmittance(distance),
+            Medium::Grid(medium) => medium.transmittance(origin, direction, distance, seed),
+        }
+    }
+}
+
+/// Type of collision with medium in delta tracking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Collision {
+    Absorb,
+    Scatter,
+    /// Fictitious particle which makes the medium homogeneous with `majorant` extinction
+    Null,
+}
+
+/// Picks type of collision by coefficients weighted with path throughput and updates `weight`,
+/// so channels with lower extinction than `majorant` stay unbiased.
+/// "Spectral and Decomposition Tracking", Kutz et al., 2017.
+#[inline]
+fn collide(weight: &mut Vector3<f32>, absorption: &Vector3<f32>, scattering: &Vector3<f32>, majorant: f32, seed: &mut u32) -> Collision {
+    let null = (absorption + scattering).map(|x| (majorant - x).max(0.0));
+    let absorption_probability = weight.dot(absorption);
+    let scattering_probability = weight.dot(scattering);
+    let null_probability = weight.dot(&null);
+    let total = absorption_probability + scattering_probability + null_probability;
+    if total <= 0.0 {
+        return Collision::Absorb;
+    }
+    let u = pcg::random_f32(seed) * total;
+    if u < absorption_probability {
+        Collision::Absorb
+    } else if u < absorption_probability + scattering_probability {
+        *weight = weight.component_mul(scattering) * (total / (scattering_probability * majorant));
+        Collision::Scatter
+    } else {
+        *weight = weight.component_mul(&null) * (total / (null_probability * majorant));
+        Collision::Null
+    }
+}
@@ -0,0 +1,71 @@
+use std::f32::consts::PI;
+use nalgebra::Vector3;
+use crate::math::{extensions::orthonormal_basis, pcg};
+
+/// Henyey-Greenstein phase function, `g` is the average cosine of scattering angle.
+/// Positive `g` scatters forward, negative scatters back and zero is isotropic.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HenyeyGreenstein {
+    pub g: f32,
+}
+
+impl HenyeyGreenstein {
+    #[inline]
+    pub fn new(g: f32) -> Self {
+        HenyeyGreenstein { g: g.clamp(-0.99, 0.99) }
+    }
+
+    /// Scattered fraction per steradian, `cos` is between directions of travel before and after scattering.
+    /// The function is normalized, so it's also the pdf of [`HenyeyGreenstein::sample`].
+    #[inline]
+    pub fn evaluate(&self, cos: f32) -> f32 {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cos;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+
+    /// Direction of travel after scattering of ray going along unit `direction`
+    #[inline]
+    pub fn sample(&self, direction: &Vector3<f32>, seed: &mut u32) -> Vector3<f32> {
+        let g = self.g;
+        let u = pcg::random_f32(seed);
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        }.clamp(-1.0, 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * pcg::random_f32(seed);
+        let (tangent, bitangent) = orthonormal_basis(direction);
+        (tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + direction * cos_theta).normalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use nalgebra::Vector3;
+    use super::HenyeyGreenstein;
+
+    #[test]
+    fn henyey_greenstein() {
+        for g in [-0.7, 0.0, 0.3, 0.9] {
+            let phase = HenyeyGreenstein::new(g);
+            // Integral over the sphere is one
+            const STEPS: usize = 20000;
+            let integral: f32 = (0..STEPS).map(|i| {
+                let cos = -1.0 + 2.0 * (i as f32 + 0.5) / STEPS as f32;
+                phase.evaluate(cos) * 2.0 * PI * 2.0 / STEPS as f32
+            }).sum();
+            assert!((integral - 1.0).abs() < 1e-2, "g {g} integral {integral}");
+
+            // Mean cosine of sampled directions is g
+            let mut seed = 7;
+            let direction = Vector3::new(1.0, 2.0, -1.0).normalize();
+            let samples = 20000;
+            let mean: f32 = (0..samples).map(|_| phase.sample(&direction, &mut seed).dot(&direction)).sum::<f32>() / samples as f32;
+            assert!((mean - g).abs() < 0.02, "g {g} mean {mean}");
+        }
+    }
+}
@@ -1,27 +1,38 @@
 use crate::math::pcg::{self, random_direction, random_vector3};
-use crate::math::ray::Ray;
+use crate::math::ray::{Ray, offset_ray_origin};
 use crate::scene::SceneData;
+use crate::bvh::{RayPacket, PACKET_TILE_SIZE};
+use crate::entity::{hit::Hit, primitive::Primitive, surface_interaction::SurfaceInteraction};
 use crate::camera::Camera;
 use crate::math::extensions::*;
-use crate::textures::texture::Texture;
+use crate::lights::environment::EnvironmentLight;
+use crate::math::distribution::power_heuristic;
+use crate::media::{Medium, MediumEvent, homogeneous::HomogeneousMedium};
 use nalgebra::{Vector2, Vector3};
 use rayon::prelude::*;
 
+/// Shadow rays stop a bit before sampled emitter, so they don't hit it
+const SHADOW_RAY_EPSILON: f32 = 1e-3;
+/// Medium boundaries passed by a path or a shadow ray, they don't count as bounces
+const MAX_BOUNDARY_CROSSINGS: u32 = 16;
+/// Scattering events of subsurface random walk before it's terminated
+const MAX_WALK_STEPS: u32 = 256;
+
 pub struct Render {
     pub texture_buffer: Vec<Vector3<f32>>,
     pub bvh_debug: bool,
-    pub texture: Option<Texture<Vector3<f32>>>,
+    pub environment: EnvironmentLight,
     accumulated_frames: u32,
     seed: u32
 }
 
 impl Render {
     #[inline]
-    pub fn new(width: u32, height: u32, sky_texture: Option<Texture<Vector3<f32>>>) -> Self {
+    pub fn new(width: u32, height: u32, environment: EnvironmentLight) -> Self {
         Render {
             texture_buffer: vec![Vector3::zeros(); (width * height) as usize],
             bvh_debug: false,
-            texture: sky_texture,
+            environment,
             accumulated_frames: 0,
             seed: 153544,
         }
@@ -31,138 +42,388 @@ impl Render {
         // Weight of current frame
         let weight = 1.0 / (self.accumulated_frames + 1) as f32;
         self.seed = pcg::hash(self.seed);
-        
-        if self.bvh_debug {
-            // Iterate over the pixels of the image
-            self.texture_buffer.par_iter_mut().enumerate().for_each(|(i, pixel)| {
-                let x = i % camera.screen_width as usize;
-                let y = camera.screen_height as usize - i / camera.screen_width as usize;
-                let mut seed = self.seed.wrapping_mul(x as u32).wrapping_mul(y as u32);
-                
-                // Get camera ray
-                let mut ray: Ray = camera.ray_from_screen_point(&Vector2::new(x as f32, y as f32), &mut seed);
-                let mut color: Vector3<f32> = Vector3::new(0.005, 0.005, 0.005);
-
-                const TRANSMISSION_BOUNCES: u32 = 16;
-                for _ in 0..TRANSMISSION_BOUNCES {
-                    let scene_hit = scene.cast_debug_ray(&ray);
-                    if let Some(hit) = scene_hit {
-                        let hit_point = ray.origin + ray.get_direction() * hit.t;
-
-                        let mut bvh_color: Vector3<f32> = random_vector3(&mut ((hit.object.first_object + hit.object.object_count) as u32));
-                        let distance_to_edge = (hit.object.distance_to_edge(&hit_point) * 15.0).clamp(0.0, 1.0);
-                        let edge_color = Vector3::new(0.9, 0.9, 0.9);
-                        bvh_color = lerp_vector3(&edge_color, &bvh_color, distance_to_edge.clamp(0.0, 1.0));
-
-                        ray.origin = hit.point + ray.get_direction() * 0.001;
-                        color = lerp_vector3(&color, &bvh_color, 0.25);
-                    } else {
-                        break;
+        let frame_seed = self.seed;
+        let environment = &self.environment;
+        let width = camera.screen_width as usize;
+        let height = camera.screen_height as usize;
+
+        // Iterate over bands of tile height, primary rays of every tile are traced as one packet
+        self.texture_buffer.par_chunks_mut(width * PACKET_TILE_SIZE).enumerate().for_each(|(band, pixels)| {
+            let band_start = band * width * PACKET_TILE_SIZE;
+            for tile_x in (0..width).step_by(PACKET_TILE_SIZE) {
+                let tile: Vec<usize> = (0..pixels.len() / width)
+                    .flat_map(|row| (tile_x..(tile_x + PACKET_TILE_SIZE).min(width)).map(move |x| row * width + x))
+                    .collect();
+                let screen_points = tile.iter().map(|i| {
+                    let i = band_start + i;
+                    (i % width, height - i / width)
+                });
+
+                if self.bvh_debug {
+                    let mut rays: Vec<Ray> = screen_points.map(|(x, y)| {
+                        let mut seed = frame_seed.wrapping_mul(x as u32).wrapping_mul(y as u32);
+                        camera.ray_from_screen_point(&Vector2::new(x as f32, y as f32), &mut seed)
+                    }).collect();
+                    let colors = Self::trace_debug_tile(scene, &mut rays);
+                    for (i, color) in tile.iter().zip(colors) {
+                        pixels[*i] = lerp_vector3(&pixels[*i], &color, weight);
                     }
+                } else {
+                    let mut seeds = Vec::with_capacity(tile.len());
+                    let rays: Vec<Ray> = screen_points.map(|(x, y)| {
+                        let mut seed = frame_seed.wrapping_add(y as u32).wrapping_mul(x as u32).wrapping_add(x as u32).wrapping_mul(y as u32);
+                        let ray = camera.ray_from_screen_point(&Vector2::new(x as f32, y as f32), &mut seed);
+                        seeds.push(seed);
+                        ray
+                    }).collect();
+                    let packet = RayPacket::new(rays);
+                    let hits = scene.cast_packet(&packet);
+                    for (((i, ray), hit), seed) in tile.iter().zip(packet.rays()).zip(hits).zip(seeds) {
+                        let light = Self::trace_path(scene, environment, ray.clone(), hit, seed);
+                        // Blend generated pixel with old one
+                        pixels[*i] = lerp_vector3(&pixels[*i], &light, weight);
+                    }
+                }
+            }
+        });
+        self.accumulated_frames += 1;
+    }
+
+    /// Colors of bvh boxes hit by camera rays, rays are traced through several boxes.
+    /// Rays which hit something are traced further together as the next packet.
+    fn trace_debug_tile(scene: &SceneData, rays: &mut [Ray]) -> Vec<Vector3<f32>> {
+        let mut colors = vec![Vector3::new(0.005, 0.005, 0.005); rays.len()];
+        let mut active: Vec<usize> = (0..rays.len()).collect();
+
+        const TRANSMISSION_BOUNCES: u32 = 16;
+        for _ in 0..TRANSMISSION_BOUNCES {
+            if active.is_empty() {
+                break;
+            }
+            let packet = RayPacket::new(active.iter().map(|i| rays[*i].clone()).collect());
+            let hits = scene.cast_debug_packet(&packet);
+            let mut next_active = Vec::with_capacity(active.len());
+            for (i, hit) in active.iter().zip(hits) {
+                let Some(hit) = hit else {
+                    continue;
+                };
+                let ray = &mut rays[*i];
+                let hit_point = ray.origin + ray.get_direction() * hit.t;
+
+                let mut bvh_color: Vector3<f32> = random_vector3(&mut ((hit.object.first_object + hit.object.object_count) as u32));
+                let distance_to_edge = (hit.object.distance_to_edge(&hit_point) * 15.0).clamp(0.0, 1.0);
+                let edge_color = Vector3::new(0.9, 0.9, 0.9);
+                bvh_color = lerp_vector3(&edge_color, &bvh_color, distance_to_edge.clamp(0.0, 1.0));
+
+                // Continue behind the box
+                ray.t_min = hit.t;
+                colors[*i] = lerp_vector3(&colors[*i], &bvh_color, 0.25);
+                next_active.push(*i);
+            }
+            active = next_active;
+        }
+        colors
+    }
+
+    /// Light coming along camera `ray`, `first_hit` is its precalculated closest hit
+    fn trace_path<'a>(scene: &'a SceneData, environment: &EnvironmentLight, mut ray: Ray,
+        first_hit: Option<Hit<'a, Primitive>>, mut seed: u32) -> Vector3<f32> {
+        let mut color: Vector3<f32> = Vector3::new(1.0, 1.0, 1.0);
+        let mut light: Vector3<f32> = Vector3::zeros();
+        // Solid angle pdf of the last diffuse bounce, `None` for camera and glossy rays which can't hit lights explicitly
+        let mut bsdf_pdf: Option<f32> = None;
+        // Shading point and normal of the last bounce, needed for pdf of emitters sampled from there
+        let mut last_position: Vector3<f32> = Vector3::zeros();
+        let mut last_normal: Vector3<f32> = Vector3::zeros();
+        // Medium the ray travels in, camera is expected to be outside of meshes with interior media
+        let fog = scene.fog.map(|x| Medium::Homogeneous(x.medium));
+        let fog = fog.as_ref();
+        let fog_distance = scene.fog.map_or(f32::INFINITY, |x| x.distance);
+        let mut medium: Option<&Medium> = fog;
+        // Closest hit of the next ray if it's already known
+        let mut next_hit = Some(first_hit);
+        let mut boundary_crossings = 0;
+
+        const MAX_BOUNCES: u32 = 3;
+        let mut bounce = 0;
+        while bounce < MAX_BOUNCES {
+            // Stop when color is black
+            if color.x.max(color.y.max(color.z)) < f32::EPSILON {
+                break;
+            }
+            // Calculate intersection, the first one is traced with the tile packet
+            let t_ray = ray.clone();
+            let ray_direction = *ray.get_direction();
+            let hit_option = match next_hit.take() {
+                Some(hit) => hit,
+                None => scene.cast_ray(&t_ray),
+            };
+
+            // Ray can scatter in medium before it reaches the surface or the environment
+            if let Some(current_medium) = medium {
+                let t_max = hit_option.as_ref().map_or(fog_distance, |x| x.t);
+                match current_medium.sample(&ray.origin, &ray_direction, t_max, &mut seed) {
+                    MediumEvent::Absorb => break,
+                    MediumEvent::Pass { weight } => color = color.component_mul(&weight),
+                    MediumEvent::Scatter { distance, weight } => {
+                        color = color.component_mul(&weight);
+                        let position = ray.origin + ray_direction * distance;
+                        let phase = current_medium.phase();
+                        // Phase function is sampled perfectly, so it's both the scattered fraction and the pdf
+                        if bounce + 1 < MAX_BOUNCES {
+                            light += Self::sample_lights(scene, environment, &position, &position, &Vector3::zeros(), medium, &mut seed, |direction| {
+                                let pdf = phase.evaluate(ray_direction.dot(direction));
+                                Some((Vector3::repeat(pdf), pdf))
+                            }).component_mul(&color);
+                        }
+                        let direction = phase.sample(&ray_direction, &mut seed);
+                        bsdf_pdf = Some(phase.evaluate(ray_direction.dot(&direction)));
+                        last_position = position;
+                        last_normal = Vector3::zeros();
+                        ray = Ray::new(position, direction);
+                        bounce += 1;
+                        continue;
+                    },
                 }
+            }
+
+            // Calculate fragment
+            if let Some(hit) = hit_option {
+                let surface = SurfaceInteraction::new(&scene.primitives, &hit, &ray_direction);
+                let material = surface.material;
+                let normal = surface.shading_normal;
 
-                let blended_color = lerp_vector3(pixel, &color, weight);
-                *pixel = blended_color;
-            });
-        } else {
-            // Iterate over the pixels of the image
-            self.texture_buffer.par_iter_mut().enumerate().for_each(|(i, pixel)| {
-                let x = i % camera.screen_width as usize;
-                let y = camera.screen_height as usize - i / camera.screen_width as usize;
-                let mut seed = self.seed.wrapping_add(y as u32).wrapping_mul(x as u32).wrapping_add(x as u32).wrapping_mul(y as u32);
-
-                //let screen_pos = Vector2::<f32>::new(x as f32 / camera.screen_width as f32, y as f32 / camera.screen_height as f32);
-                // Get camera ray
-                let mut ray: crate::math::ray::Ray = camera.ray_from_screen_point(&Vector2::new(x as f32, y as f32), &mut seed);
-                let mut color: Vector3<f32> = Vector3::new(1.0, 1.0, 1.0);
-                let mut light: Vector3<f32> = Vector3::zeros();
-
-                const MAX_BOUNCES: u32 = 3;
-                for _ in 0..MAX_BOUNCES {
-                    // Stop when color is black
-                    if color.x.max(color.y.max(color.z)) < f32::EPSILON {
+                // Boundary of interior medium changes medium of the ray, but doesn't count as bounce
+                if let Some(interior_medium) = &material.interior_medium {
+                    medium = if surface.front_face { Some(interior_medium) } else { fog };
+                    ray.origin = offset_ray_origin(&surface.position, &-surface.geometric_normal);
+                    boundary_crossings += 1;
+                    if boundary_crossings > MAX_BOUNDARY_CROSSINGS {
                         break;
                     }
-                    // Calculate intersection
-                    let t_ray = ray.clone();
-                    let ray_direction = *ray.get_direction();
-                    let hit_option = scene.cast_ray(&t_ray);
-                    // Calculate fragment
-                    if let Some(hit) = hit_option {
-                        let material = &hit.object.material;
-
-                        let bar_coords: Vector2<f32> = hit.object.bar_coords(&hit.point);
-                        let uv_coors: Vector2<f32> = hit.object.uv_coords(&bar_coords);
-                        let normal: Vector3<f32> = hit.object.normal(&bar_coords, &ray_direction);
-
-                        let albedo_color: Vector3<f32> = if let Some(albedo_tex) = &material.albedo_tex {
-                            f32_vector3_from_u32(albedo_tex.sample(uv_coors.x, uv_coors.y)).component_mul(&material.albedo)
-                        } else {
-                            material.albedo
-                        };
+                    continue;
+                }
 
-                        ray.origin = hit.point + normal * 0.001;
-                        let reflection: Vector3<f32> = reflect(ray.get_direction(), &normal);
-                        let diffuse: Vector3<f32> = (normal + random_direction(&mut seed)).normalize();
-
-                        // Calculate light contribution by explicit sampling
-                        // FIXME: Produces incorrect result
-                        /*let random_index = pcg::random_u32(&mut seed) as usize % scene.light_objects.len();
-                        let random_light_object = &scene.objects[scene.light_objects[random_index]];
-                        let light_object_midpoint: Vector3<f32> = random_light_object.random_point(&mut seed);
-                        let random_ray = Ray::new(ray.origin, (light_object_midpoint - ray.origin).normalize());
-                        let additional_ray = scene.cast_ray(&random_ray);
-
-                        let additional_emission = if let Some(ar) = additional_ray {
-                            if ar.object.index == hit.object.index || ar.object.index != random_light_object.index {
-                                Vector3::zeros()
-                            } else {
-                                let ar_bar_coord: Vector2<f32> = ar.object.bar_coords(&ar.point);
-                                let ar_normal: Vector3<f32> = ar.object.normal(&ar_bar_coord, random_ray.get_direction());
-                                
-                                ar.object.material.emission * // light emission
-                                random_ray.get_direction().dot(&normal).max(0.0) * // lambert
-                                random_ray.get_direction().dot(&-ar_normal).max(0.0) * // light visibility
-                                (1.0 / (ar.t + 1.0).powi(2)) // Inverse square law
-                            }
-                        } else {
-                            Vector3::zeros()
-                        };*/
+                let albedo_color: Vector3<f32> = if let Some(albedo_tex) = &material.albedo_tex {
+                    f32_vector3_from_u32(albedo_tex.sample(surface.uv.x, surface.uv.y)).component_mul(&material.albedo)
+                } else {
+                    material.albedo
+                };
 
-                        ray.set_direction(&lerp_vector3(&reflection, &diffuse, material.roughness).normalize());
+                // Emitters which could be sampled explicitly from the last diffuse bounce are weighted
+                if material.is_emissive() {
+                    // Only triangles of meshes are sampled as lights
+                    let emitter = match hit.object {
+                        Primitive::Triangle(triangle) => scene.light_sampler.light_index(triangle),
+                        _ => None,
+                    };
+                    let weight = match (bsdf_pdf, emitter) {
+                        (Some(pdf), Some(emitter)) => {
+                            // Ray could pass medium boundaries since the last bounce
+                            let distance = (surface.position - last_position).norm();
+                            let light_pdf = scene.light_sampler.pdf(&scene.primitives.geometry, &last_position, &last_normal, emitter, &ray_direction, distance);
+                            power_heuristic(pdf, light_pdf)
+                        },
+                        _ => 1.0,
+                    };
+                    light += material.emitted(&surface.uv, surface.front_face, &-ray_direction).component_mul(&color) * weight;
+                }
 
-                        //explicit sampling
-                        /*if bounce_index == 0 {
-                            light += material.emission.component_mul(&color);
-                        }*/
-                        light += material.emission.component_mul(&color); // comment when using explicit sampling
-                        color = color.component_mul(&albedo_color);
-                        //light += additional_emission.component_mul(&color); // explicit sampling
-                    } else {
-                        if let Some(sky_tex) = &self.texture {
-                            let uvs = Self::uv_on_sphere(ray.get_direction());
-                            let sky_color = sky_tex.sample(-uvs.0, uvs.1);
-                            light += sky_color.component_mul(&color);
-                        } else {
-                            light += Vector3::new(0.3, 0.3, 0.3).component_mul(&color);
+                // Offset along geometric normal on the side ray came from
+                ray.origin = offset_ray_origin(&surface.position, &surface.geometric_normal);
+                // Material is a mix of cosine weighted diffuse lobe, picked with probability of roughness,
+                // and glossy lobe between reflection and diffuse direction. Lights are sampled for the diffuse part
+                let diffuse_probability = material.roughness.clamp(0.0, 1.0);
+
+                // Subsurface materials don't have diffuse and glossy lobes when ray enters the surface
+                let subsurface = material.subsurface.filter(|_| surface.front_face);
+
+                // Explicit light sampling, weighted against sampling the diffuse lobe
+                if bounce + 1 < MAX_BOUNCES && diffuse_probability > 0.0 && subsurface.is_none() {
+                    let geometric_normal = surface.geometric_normal;
+                    light += Self::sample_lights(scene, environment, &ray.origin, &surface.position, &normal, medium, &mut seed, |direction| {
+                        let cos = direction.dot(&normal);
+                        (cos > 0.0 && direction.dot(&geometric_normal) > 0.0).then(|| {
+                            let lobe_pdf = diffuse_probability * cos / std::f32::consts::PI;
+                            (albedo_color * lobe_pdf, lobe_pdf)
+                        })
+                    }).component_mul(&color);
+                }
+                last_position = surface.position;
+                last_normal = normal;
+
+                if let Some(subsurface) = subsurface {
+                    // Random walk inside is picked with probability of roughness, otherwise light is reflected as by a mirror.
+                    // Lights are sampled where the walk leaves, mirror reflection can't be sampled explicitly
+                    if pcg::random_f32(&mut seed) < diffuse_probability {
+                        // Light leaves the surface somewhere else after random walk inside, with diffuse distribution
+                        let Some((exit, weight)) = Self::random_walk(scene, &surface, &subsurface.medium(&albedo_color), &mut seed) else {
+                            break;
+                        };
+                        color = color.component_mul(&weight);
+                        let normal = -exit.shading_normal;
+                        let geometric_normal = -exit.geometric_normal;
+                        ray.origin = offset_ray_origin(&exit.position, &geometric_normal);
+                        if bounce + 1 < MAX_BOUNCES {
+                            light += Self::sample_lights(scene, environment, &ray.origin, &exit.position, &normal, medium, &mut seed, |direction| {
+                                let cos = direction.dot(&normal);
+                                (cos > 0.0 && direction.dot(&geometric_normal) > 0.0).then(|| {
+                                    let lobe_pdf = cos / std::f32::consts::PI;
+                                    (Vector3::repeat(lobe_pdf), lobe_pdf)
+                                })
+                            }).component_mul(&color);
                         }
-                        break;
+                        last_position = exit.position;
+                        last_normal = normal;
+                        let direction = (normal + random_direction(&mut seed)).normalize();
+                        ray.set_direction(&direction);
+                        bsdf_pdf = Some(direction.dot(&normal).max(0.0) / std::f32::consts::PI);
+                        bounce += 1;
+                        continue;
+                    }
+                    ray.set_direction(&reflect(ray.get_direction(), &normal));
+                    bsdf_pdf = None;
+                } else {
+                    let diffuse: Vector3<f32> = (normal + random_direction(&mut seed)).normalize();
+                    if pcg::random_f32(&mut seed) < diffuse_probability {
+                        ray.set_direction(&diffuse);
+                        bsdf_pdf = Some(diffuse_probability * diffuse.dot(&normal).max(0.0) / std::f32::consts::PI);
+                    } else {
+                        let reflection: Vector3<f32> = reflect(ray.get_direction(), &normal);
+                        ray.set_direction(&lerp_vector3(&reflection, &diffuse, material.roughness).normalize());
+                        bsdf_pdf = None;
                     }
                 }
 
-                // Blend generated pixel with old one
-                let blended_color = lerp_vector3(pixel, &light, weight);
-                *pixel = blended_color;
-            });
+                color = color.component_mul(&albedo_color);
+            } else {
+                let visible = if bounce == 0 { environment.visible_to_camera } else { environment.visible_to_lighting };
+                if !visible {
+                    break;
+                }
+                let direction = ray.get_direction();
+                let weight = bsdf_pdf.map_or(1.0, |pdf| power_heuristic(pdf, environment.pdf(direction)));
+                light += environment.radiance(direction).component_mul(&color) * weight;
+                break;
+            }
+            bounce += 1;
         }
-        self.accumulated_frames += 1;
+        light
     }
 
-    #[inline(always)]
-    fn uv_on_sphere(dir: &Vector3<f32>) -> (f32, f32) {
-        let u = 0.5 + f32::atan2(dir.z, dir.x) / (2.0 * std::f32::consts::PI);
-        let v = 0.5 + dir.y.asin() / (std::f32::consts::PI);
-        (u.max(0.0), v.max(0.0))
+    /// Random walk inside of subsurface scattering mesh entered at `surface`.
+    /// Returns the surface where light leaves, its normals face inside, and weight of the walk,
+    /// or `None` if light is absorbed or lost.
+    fn random_walk<'a>(scene: &'a SceneData, surface: &SurfaceInteraction, medium: &HomogeneousMedium,
+        seed: &mut u32) -> Option<(SurfaceInteraction<'a>, Vector3<f32>)> {
+        // Light enters with diffuse transmission, around geometric normal if shading normal gives direction outside
+        let entering = |x: &Vector3<f32>| x.dot(&surface.geometric_normal) < 0.0;
+        let mut direction: Vector3<f32> = (random_direction(seed) - surface.shading_normal).try_normalize(0.0).filter(entering)
+            .or_else(|| (random_direction(seed) - surface.geometric_normal).try_normalize(0.0).filter(entering))
+            .unwrap_or(-surface.geometric_normal);
+        let mut origin = offset_ray_origin(&surface.position, &-surface.geometric_normal);
+        let mut weight = Vector3::repeat(1.0);
+        for _ in 0..MAX_WALK_STEPS {
+            let ray = Ray::new(origin, direction);
+            // Walk is lost if mesh isn't closed
+            let hit = scene.cast_ray(&ray)?;
+            // Other meshes inside are ignored, walk continues behind them
+            let exits = hit.object.same_surface(&surface.primitive);
+            match medium.sample(hit.t, seed) {
+                MediumEvent::Absorb => return None,
+                MediumEvent::Scatter { distance, weight: scatter_weight } => {
+                    weight = weight.component_mul(&scatter_weight);
+                    origin += direction * distance;
+                    direction = medium.phase.sample(&direction, seed);
+                },
+                MediumEvent::Pass { weight: pass_weight } => {
+                    weight = weight.component_mul(&pass_weight);
+                    let exit = SurfaceInteraction::new(&scene.primitives, &hit, &direction);
+                    if exits {
+                        return Some((exit, weight));
+                    }
+                    origin = offset_ray_origin(&exit.position, &-exit.geometric_normal);
+                },
+            }
+        }
+        None
+    }
+
+    /// Light from the environment, emitters and punctual lights scattered at `position`.
+    /// `lobe` returns scattered fraction and pdf of sampling direction towards the light
+    /// or `None` if light from there isn't scattered. Shadow rays start at offset `origin`.
+    #[allow(clippy::too_many_arguments)]
+    fn sample_lights(scene: &SceneData, environment: &EnvironmentLight, origin: &Vector3<f32>, position: &Vector3<f32>,
+        normal: &Vector3<f32>, medium: Option<&Medium>, seed: &mut u32,
+        lobe: impl Fn(&Vector3<f32>) -> Option<(Vector3<f32>, f32)>) -> Vector3<f32> {
+        let mut light = Vector3::zeros();
+        // Environment, weighted against sampling the lobe
+        if environment.visible_to_lighting {
+            if let Some(sample) = environment.sample(seed) {
+                if let Some((scattered, lobe_pdf)) = lobe(&sample.direction) {
+                    let transmittance = Self::transmittance(scene, origin, &sample.direction, f32::INFINITY, medium, seed);
+                    let weight = power_heuristic(sample.pdf, lobe_pdf);
+                    light += sample.radiance.component_mul(&scattered).component_mul(&transmittance) * (weight / sample.pdf);
+                }
+            }
+        }
+
+        // Emissive triangles, weighted against sampling the lobe
+        if let Some(sample) = scene.light_sampler.sample(&scene.primitives.geometry, position, normal, seed) {
+            if let Some((scattered, lobe_pdf)) = lobe(&sample.direction) {
+                let transmittance = Self::transmittance(scene, origin, &sample.direction, sample.distance * (1.0 - SHADOW_RAY_EPSILON), medium, seed);
+                let weight = power_heuristic(sample.pdf, lobe_pdf);
+                light += sample.emission.component_mul(&scattered).component_mul(&transmittance) * (weight / sample.pdf);
+            }
+        }
+
+        // Punctual lights can't be hit by rays, so they are only sampled explicitly
+        for scene_light in &scene.lights {
+            let Some(sample) = scene_light.sample(position, seed) else {
+                continue;
+            };
+            if let Some((scattered, _)) = lobe(&sample.direction) {
+                let transmittance = Self::transmittance(scene, origin, &sample.direction, sample.distance, medium, seed);
+                light += sample.irradiance.component_mul(&scattered).component_mul(&transmittance);
+            }
+        }
+        light
+    }
+
+    /// Fraction of light passing from `origin` along `direction` to `distance` starting in `medium`.
+    /// Without media it's an occlusion query, otherwise shadow ray passes medium boundaries.
+    fn transmittance(scene: &SceneData, origin: &Vector3<f32>, direction: &Vector3<f32>, distance: f32,
+        medium: Option<&Medium>, seed: &mut u32) -> Vector3<f32> {
+        if !scene.has_media() {
+            let occluded = scene.occluded(&Ray::new(*origin, *direction), distance);
+            return if occluded { Vector3::zeros() } else { Vector3::repeat(1.0) };
+        }
+        let fog = scene.fog.map(|x| Medium::Homogeneous(x.medium));
+        let fog_distance = scene.fog.map_or(f32::INFINITY, |x| x.distance);
+        let mut medium = medium;
+        let mut transmittance = Vector3::repeat(1.0);
+        let mut origin = *origin;
+        let mut distance = distance;
+        for _ in 0..=MAX_BOUNDARY_CROSSINGS {
+            let ray = Ray::with_interval(origin, *direction, 0.0, distance);
+            let Some(hit) = scene.cast_shadow_ray(&ray) else {
+                if let Some(medium) = medium {
+                    transmittance.component_mul_assign(&medium.transmittance(&origin, direction, distance.min(fog_distance), seed));
+                }
+                return transmittance;
+            };
+            let surface = SurfaceInteraction::new(&scene.primitives, &hit, direction);
+            let Some(interior_medium) = &surface.material.interior_medium else {
+                return Vector3::zeros();
+            };
+            if let Some(medium) = medium {
+                transmittance.component_mul_assign(&medium.transmittance(&origin, direction, hit.t, seed));
+            }
+            medium = if surface.front_face { Some(interior_medium) } else { fog.as_ref() };
+            origin = offset_ray_origin(&surface.position, &-surface.geometric_normal);
+            distance -= hit.t;
+        }
+        Vector3::zeros()
     }
 
     #[inline]
@@ -176,3 +437,114 @@ impl Render {
         self.accumulated_frames
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+    use std::sync::Arc;
+    use nalgebra::{Affine3, Matrix4};
+    use crate::{scene::SceneData, material::Material, bvh::Bvh};
+    use crate::entity::{mesh::Mesh, sphere::Sphere, primitive::{Primitive, Primitives, Instance}, geometry::Geometry, surface_interaction::SurfaceInteraction};
+    use crate::lights::{environment::EnvironmentLight, punctual::PunctualLight};
+    use crate::loaders::volume_loader::box_mesh;
+    use crate::media::homogeneous::HomogeneousMedium;
+    use crate::math::ray::Ray;
+    use super::Render;
+
+    #[test]
+    fn point_light_on_glossy_surface() {
+        let environment = EnvironmentLight::constant(Vector3::zeros());
+        for roughness in [0.25, 0.99, 1.0] {
+            // Floor under point light, only the diffuse part of the material is lit directly
+            let material = Material::new(Vector3::repeat(1.0), Vector3::zeros(), roughness, 0.0, None);
+            let positions = vec![Vector3::new(-5.0, 0.0, -5.0), Vector3::new(5.0, 0.0, -5.0), Vector3::new(5.0, 0.0, 5.0), Vector3::new(-5.0, 0.0, 5.0)];
+            let mut scene = SceneData::new(vec![Mesh::new(positions, vec![], vec![], vec![[0, 2, 1], [0, 3, 2]], material.into())]);
+            scene.lights = vec![PunctualLight::point(Vector3::new(0.0, 1.0, 0.0), Vector3::repeat(1.0), 0.0)];
+            scene.calculate_bvh();
+            let ray = Ray::new(Vector3::new(0.0, 2.0, -2.0), Vector3::new(0.0, -1.0, 1.0).normalize());
+            let hit_ray = ray.clone();
+            let samples = 64;
+            let light = (0..samples).map(|seed| {
+                Render::trace_path(&scene, &environment, ray.clone(), scene.cast_ray(&hit_ray), seed + 1)
+            }).sum::<Vector3<f32>>() / samples as f32;
+            let expected = roughness / std::f32::consts::PI;
+            assert!((light.x - expected).abs() < 1e-3, "roughness {roughness} light {light}");
+        }
+    }
+
+    #[test]
+    fn point_light_on_sphere_and_instance() {
+        let environment = EnvironmentLight::constant(Vector3::zeros());
+        let material: Arc<Material> = Material::new(Vector3::repeat(1.0), Vector3::zeros(), 1.0, 0.0, None).into();
+        // Unit sphere directly and as twice smaller instance of sphere with radius 2
+        let mut inner = Primitives::new(Geometry::default());
+        inner.add_sphere(Sphere::new(Vector3::zeros(), 2.0, material.clone()));
+        let mut inner_bvh = Bvh::default();
+        let bounds = inner.calculate_bounds();
+        inner_bvh.calculate_bvh(bounds.clone(), bounds.iter().map(|x| x.centroid).collect());
+        let instance = Instance::new(Arc::new(inner), Arc::new(inner_bvh), Affine3::from_matrix_unchecked(Matrix4::new_scaling(0.5)));
+        let mut sphere_scene = SceneData::new(vec![]);
+        sphere_scene.add_sphere(Sphere::new(Vector3::zeros(), 1.0, material));
+        let mut instance_scene = SceneData::new(vec![]);
+        instance_scene.add_instance(instance);
+
+        for mut scene in [sphere_scene, instance_scene] {
+            scene.lights = vec![PunctualLight::point(Vector3::new(0.0, 3.0, 0.0), Vector3::repeat(1.0), 0.0)];
+            scene.calculate_bvh();
+            // Hits the top of the sphere, light is 2 units above it
+            let ray = Ray::new(Vector3::new(0.0, 2.0, -1.0), Vector3::new(0.0, -1.0, 1.0).normalize());
+            let hit = scene.cast_ray(&ray).unwrap();
+            let surface = SurfaceInteraction::new(&scene.primitives, &hit, ray.get_direction());
+            assert!((surface.position - Vector3::y()).norm() < 1e-4);
+            assert!((surface.shading_normal - Vector3::y()).norm() < 1e-4);
+            assert!(surface.front_face);
+            let samples = 16;
+            let light = (0..samples).map(|seed| {
+                Render::trace_path(&scene, &environment, ray.clone(), scene.cast_ray(&ray), seed + 1)
+            }).sum::<Vector3<f32>>() / samples as f32;
+            let expected = 0.25 / std::f32::consts::PI;
+            assert!((light.x - expected).abs() < 1e-3, "light {light}");
+        }
+    }
+
+    #[test]
+    fn random_walk_in_closed_box() {
+        // Box with a smaller box inside, which the walk has to pass through
+        let material = Material::default().into();
+        let mut scene = SceneData::new(vec![
+            box_mesh(&Vector3::repeat(-1.0), &Vector3::repeat(1.0), material),
+            box_mesh(&Vector3::repeat(-0.2), &Vector3::repeat(0.2), Material::default().into()),
+        ]);
+        scene.calculate_bvh();
+        let direction = Vector3::new(0.1, 0.2, 1.0).normalize();
+        let ray = Ray::new(Vector3::new(0.0, 0.0, -3.0), direction);
+        let hit = scene.cast_ray(&ray).unwrap();
+        let surface = SurfaceInteraction::new(&scene.primitives, &hit, &direction);
+        assert!(matches!(surface.primitive, Primitive::Triangle(x) if x.mesh == 0));
+
+        let medium = HomogeneousMedium::new(Vector3::repeat(0.1), Vector3::repeat(2.0), 0.3);
+        let mut seed = 5;
+        let mut exits = 0;
+        for _ in 0..2000 {
+            let Some((exit, weight)) = Render::random_walk(&scene, &surface, &medium, &mut seed) else {
+                continue;
+            };
+            exits += 1;
+            assert!(matches!(exit.primitive, Primitive::Triangle(x) if x.mesh == 0));
+            assert!(weight.iter().all(|x| (0.0..=1.0 + 1e-5).contains(x)), "{weight}");
+            // Exit is on the box and normals face inside
+            assert!((exit.position.abs().max() - 1.0).abs() < 1e-4);
+            assert!(exit.geometric_normal.dot(&exit.position) < 0.0);
+        }
+        assert!(exits > 1000, "{exits}");
+
+        // Shading normal far from geometric one, light still enters and leaves without absorption
+        let mut tilted = surface;
+        tilted.shading_normal = (tilted.geometric_normal + Vector3::new(3.0, 0.0, 0.0)).normalize();
+        let medium = HomogeneousMedium::new(Vector3::zeros(), Vector3::repeat(2.0), 0.0);
+        for _ in 0..500 {
+            let (exit, _) = Render::random_walk(&scene, &tilted, &medium, &mut seed).unwrap();
+            assert!(matches!(exit.primitive, Primitive::Triangle(x) if x.mesh == 0));
+        }
+    }
+}
@@ -1,54 +1,191 @@
-use std::{time::Instant, sync::{Arc, atomic::{AtomicU64, Ordering}}};
-use crate::{math::ray::Ray, entity::{hit::Hit, triangle::Triangle, Bounds}, bvh::{BvhNode, Bvh}};
+use std::{time::Instant, sync::{Arc, atomic::{AtomicU64, Ordering}}, path::Path, fs, io::ErrorKind, hash::Hasher};
+use crate::{math::ray::Ray, entity::{hit::{Hit, HittableSet}, mesh::Mesh, geometry::Geometry, sphere::Sphere, primitive::{Primitive, Primitives, Instance}, Bounds}};
+use crate::bvh::{BvhNode, Bvh, BvhBuilder, Fnv1a, Bvh4, RayPacket};
+use crate::lights::{punctual::PunctualLight, light_tree::LightSampler};
+use crate::media::homogeneous::Fog;
 use nalgebra::Vector3;
-use rayon::prelude::*;
 
 pub struct SceneData {
-    pub objects: Vec<Triangle>,
+    /// Meshes, spheres and instances in the bvh, only triangles of meshes are sampled as lights
+    pub primitives: Primitives,
     pub light_objects: Vec<usize>,
+    /// Picks emissive triangles from `light_objects` for explicit sampling
+    pub light_sampler: LightSampler,
+    /// Lights without geometry, evaluated with shadow rays
+    pub lights: Vec<PunctualLight>,
+    /// Medium around meshes, rays travel in vacuum without it
+    pub fog: Option<Fog>,
+    /// Some material has interior medium
+    medium_boundaries: bool,
     bvh_accel: Bvh,
+    /// Collapsed copy of `bvh_accel` used for traversal if built
+    bvh_wide: Option<Bvh4>,
     pub rays_count: Arc<AtomicU64>,
+    /// Occlusion queries are counted separately from `rays_count`
+    pub shadow_rays_count: Arc<AtomicU64>,
     pub debug_objects: Vec<BvhNode>,
     bvh_debug: Bvh
 }
 
 impl SceneData {
     #[inline]
-    pub fn new(objects: Vec<Triangle>) -> Self {
-        // Take indexes of all triangles with emission
-        let light_objects: Vec<usize> = objects.iter().enumerate().filter_map(|x| {
-            if x.1.material.emission != Vector3::zeros() {
-                Some(x.0)
-            } else {
-                None
-            }
-        }).collect();
+    pub fn new(meshes: Vec<Mesh>) -> Self {
+        let primitives = Primitives::new(Geometry::new(meshes));
+        let light_objects = Self::calculate_light_objects(&primitives.geometry);
+        let light_sampler = LightSampler::new(&primitives.geometry, &light_objects);
+        let medium_boundaries = Self::calculate_medium_boundaries(&primitives);
         SceneData {
-            objects,
+            primitives,
             light_objects,
+            light_sampler,
+            lights: vec![],
+            fog: None,
+            medium_boundaries,
             bvh_accel: Bvh::default(),
+            bvh_wide: None,
             rays_count: Arc::new(AtomicU64::new(0)),
+            shadow_rays_count: Arc::new(AtomicU64::new(0)),
             debug_objects: vec![],
             bvh_debug: Bvh::default(),
         }
     }
 
     #[inline]
-    pub fn add_object(&mut self, object: Triangle) -> &Triangle {
-        self.objects.push(object);
-        let triangle = self.objects.last().unwrap();
-        triangle
+    pub fn add_mesh(&mut self, mesh: Mesh) -> &Mesh {
+        let mesh_index = self.primitives.add_mesh(mesh);
+        self.light_objects = Self::calculate_light_objects(&self.primitives.geometry);
+        self.update_light_sampler();
+        self.medium_boundaries = Self::calculate_medium_boundaries(&self.primitives);
+        &self.primitives.geometry.meshes[mesh_index]
+    }
+
+    /// Spheres are not sampled as lights, emissive ones are found only by hitting them
+    #[inline]
+    pub fn add_sphere(&mut self, sphere: Sphere) {
+        self.primitives.add_sphere(sphere);
+        self.medium_boundaries = Self::calculate_medium_boundaries(&self.primitives);
+    }
+
+    /// Instanced primitives are not sampled as lights, emissive ones are found only by hitting them
+    #[inline]
+    pub fn add_instance(&mut self, instance: Instance) {
+        self.primitives.add_instance(instance);
+        self.medium_boundaries = Self::calculate_medium_boundaries(&self.primitives);
+    }
+
+    /// Rebuilds light sampler after emissive triangles are changed or moved, keeps selection strategy
+    #[inline]
+    pub fn update_light_sampler(&mut self) {
+        let selection = self.light_sampler.selection;
+        self.light_sampler = LightSampler::new(&self.primitives.geometry, &self.light_objects);
+        self.light_sampler.selection = selection;
+    }
+
+    /// Take indexes of all triangles with emission
+    #[inline]
+    fn calculate_light_objects(geometry: &Geometry) -> Vec<usize> {
+        geometry.triangles.iter().enumerate().filter_map(|x| {
+            if geometry.triangle(x.1).material().is_emissive() {
+                Some(x.0)
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    #[inline]
+    fn calculate_medium_boundaries(primitives: &Primitives) -> bool {
+        primitives.any_material(&|x| x.interior_medium.is_some())
+    }
+
+    /// Returns true if rays can travel through any medium, otherwise shadow rays are simple occlusion queries
+    #[inline]
+    pub fn has_media(&self) -> bool {
+        self.fog.is_some() || self.medium_boundaries
+    }
+
+    /// Builder used on next `calculate_bvh` call
+    #[inline]
+    pub fn set_bvh_builder(&mut self, builder: BvhBuilder) {
+        self.bvh_accel.set_builder(builder);
     }
 
     #[inline]
     pub fn calculate_bvh(&mut self) {
         let timer = Instant::now();
-        let objects_bounds: Vec<Bounds> = Self::calculate_objects_bounds(&self.objects);
+        let objects_bounds: Vec<Bounds> = self.primitives.calculate_bounds();
         println!("Bounds generation time: {} ms", timer.elapsed().as_millis());
-        let objects_centroids: Vec<Vector3<f32>> = self.objects.iter().map(|x| (x.vertex1() + x.vertex2() + x.vertex3()) / 3.0).collect();
+        let objects_centroids: Vec<Vector3<f32>> = objects_bounds.iter().map(|x| x.centroid).collect();
+        let timer = Instant::now();
+        self.bvh_accel.calculate_bvh_clipped(objects_bounds, objects_centroids, &self.primitives);
+        println!("BVH generation time: {} ms.\nBVH count: {}\nBVH references: {}", timer.elapsed().as_millis(),
+            self.bvh_accel.bvh_count(), self.bvh_accel.reference_count());
+    }
+
+    /// Loads bvh from `cache_dir` if it was built for the same geometry, otherwise builds and saves it there.
+    /// Only scenes of meshes are cached, with spheres or instances bvh is always built.
+    pub fn calculate_bvh_cached(&mut self, cache_dir: &Path) {
+        if !self.primitives.triangles_only() {
+            self.calculate_bvh();
+            return;
+        }
+        let timer = Instant::now();
+        let key = self.bvh_cache_key();
+        let path = cache_dir.join(format!("{:016x}.bvh", key));
+        let objects_bounds: Vec<Bounds> = self.primitives.calculate_bounds();
+        match self.bvh_accel.load(&path, key, &objects_bounds) {
+            Ok(true) => {
+                println!("BVH loaded from cache in {} ms.\nBVH count: {}", timer.elapsed().as_millis(), self.bvh_accel.bvh_count());
+                return;
+            },
+            Ok(false) => {},
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => println!("Can't load BVH cache {}: {}", path.display(), e),
+        }
+        self.calculate_bvh();
+        if let Err(e) = fs::create_dir_all(cache_dir).and_then(|_| self.bvh_accel.save(&path, key)) {
+            println!("Can't save BVH cache {}: {}", path.display(), e);
+        }
+    }
+
+    /// Hash of geometry and builder settings the bvh depends on
+    fn bvh_cache_key(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        for mesh in &self.primitives.geometry.meshes {
+            hasher.write_usize(mesh.triangle_count());
+            for position in mesh.positions() {
+                position.iter().for_each(|x| hasher.write_u32(x.to_bits()));
+            }
+            for indices in mesh.indices() {
+                indices.iter().for_each(|x| hasher.write_u32(*x));
+            }
+        }
+        hasher.write(format!("{:?}", self.bvh_accel.builder()).as_bytes());
+        hasher.finish()
+    }
+
+    /// Collapses bvh to 4-wide tree with SIMD box tests, used by `cast_ray` afterwards.
+    /// Must be called after bvh is calculated.
+    #[inline]
+    pub fn calculate_wide_bvh(&mut self) {
+        let timer = Instant::now();
+        let bvh_wide = Bvh4::new(&self.bvh_accel);
+        println!("Wide BVH generation time: {} ms.\nWide BVH count: {}", timer.elapsed().as_millis(), bvh_wide.node_count());
+        self.bvh_wide = Some(bvh_wide);
+    }
+
+    /// Updates bvh after vertices of meshes were moved, see [`Bvh::refit`]
+    #[inline]
+    pub fn refit_bvh(&mut self) -> f32 {
         let timer = Instant::now();
-        self.bvh_accel.calculate_bvh(objects_bounds, objects_centroids);
-        println!("BVH generation time: {} ms.\nBVH count: {}", timer.elapsed().as_millis(), self.bvh_accel.bvh_count());
+        let objects_bounds: Vec<Bounds> = self.primitives.calculate_bounds();
+        let degradation = self.bvh_accel.refit(&objects_bounds);
+        self.update_light_sampler();
+        if self.bvh_wide.is_some() {
+            self.bvh_wide = Some(Bvh4::new(&self.bvh_accel));
+        }
+        println!("BVH refit time: {} ms. Degradation: {:.3}", timer.elapsed().as_millis(), degradation);
+        degradation
     }
 
     #[inline]
@@ -60,21 +197,81 @@ impl SceneData {
     }
 
     #[inline]
-    fn calculate_objects_bounds(objects: &[Triangle]) -> Vec<Bounds> {
-        objects.par_iter().map(
-            |x| x.into()
-        ).collect()
+    pub fn cast_ray<'a>(&'a self, ray: &'a Ray) -> Option<Hit<'a, Primitive>> {
+        self.rays_count.fetch_add(1, Ordering::Relaxed);
+        self.intersect(ray)
     }
 
+    /// Same as `cast_ray`, but counted in `shadow_rays_count`.
+    /// Used by shadow rays which have to find medium boundaries on the way.
     #[inline]
-    pub fn cast_ray<'a>(&'a self, ray: &'a Ray) -> Option<Hit<Triangle>> {
-        self.rays_count.fetch_add(1, Ordering::Relaxed);
-        self.bvh_accel.intersect(ray, &self.objects)
+    pub fn cast_shadow_ray<'a>(&'a self, ray: &'a Ray) -> Option<Hit<'a, Primitive>> {
+        self.shadow_rays_count.fetch_add(1, Ordering::Relaxed);
+        self.intersect(ray)
+    }
+
+    /// Scenes of only meshes are traversed with triangles of geometry, without dispatch on primitive kind
+    #[inline]
+    fn intersect<'a>(&'a self, ray: &'a Ray) -> Option<Hit<'a, Primitive>> {
+        if self.primitives.triangles_only() {
+            self.intersect_objects(ray, &self.primitives.geometry).map(|x| self.primitives.triangle_hit(x))
+        } else {
+            self.intersect_objects(ray, &self.primitives)
+        }
+    }
+
+    #[inline(always)]
+    fn intersect_objects<'a, T, S: HittableSet<T>>(&'a self, ray: &'a Ray, objects: &'a S) -> Option<Hit<'a, T>> {
+        match &self.bvh_wide {
+            Some(bvh_wide) => bvh_wide.intersect(ray, objects),
+            None => self.bvh_accel.intersect(ray, objects),
+        }
+    }
+
+    /// Returns true if anything is hit by `ray` closer than `t_max`
+    #[inline]
+    pub fn occluded(&self, ray: &Ray, t_max: f32) -> bool {
+        self.shadow_rays_count.fetch_add(1, Ordering::Relaxed);
+        if self.primitives.triangles_only() {
+            self.occluded_objects(ray, t_max, &self.primitives.geometry)
+        } else {
+            self.occluded_objects(ray, t_max, &self.primitives)
+        }
+    }
+
+    #[inline(always)]
+    fn occluded_objects<T, S: HittableSet<T>>(&self, ray: &Ray, t_max: f32, objects: &S) -> bool {
+        match &self.bvh_wide {
+            Some(bvh_wide) => bvh_wide.occluded(ray, t_max, objects),
+            None => self.bvh_accel.occluded(ray, t_max, objects),
+        }
+    }
+
+    /// Closest hits of coherent rays, see [`Bvh::intersect_packet`].
+    /// With wide bvh rays are traced one by one through it instead, it's faster than binary packet traversal
+    /// and primary rays hit the same structure as the following bounces.
+    #[inline]
+    pub fn cast_packet<'a>(&'a self, packet: &'a RayPacket) -> Vec<Option<Hit<'a, Primitive>>> {
+        self.rays_count.fetch_add(packet.len() as u64, Ordering::Relaxed);
+        if self.bvh_wide.is_some() {
+            packet.rays().iter().map(|ray| self.intersect(ray)).collect()
+        } else if self.primitives.triangles_only() {
+            self.bvh_accel.intersect_packet(packet, &self.primitives.geometry).into_iter()
+                .map(|x| x.map(|x| self.primitives.triangle_hit(x)))
+                .collect()
+        } else {
+            self.bvh_accel.intersect_packet(packet, &self.primitives)
+        }
+    }
+
+    #[inline]
+    pub fn cast_debug_packet<'a>(&'a self, packet: &RayPacket) -> Vec<Option<Hit<'a, BvhNode>>> {
+        self.bvh_debug.intersect_packet(packet, self.debug_objects.as_slice())
     }
 
     #[inline]
-    pub fn cast_debug_ray<'a>(&'a self, ray: &'a Ray) -> Option<Hit<BvhNode>> {
-        self.bvh_debug.intersect(ray, &self.debug_objects)
+    pub fn cast_debug_ray<'a>(&'a self, ray: &'a Ray) -> Option<Hit<'a, BvhNode>> {
+        self.bvh_debug.intersect(ray, self.debug_objects.as_slice())
     }
 
     #[inline]